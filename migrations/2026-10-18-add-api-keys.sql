@@ -0,0 +1,12 @@
+CREATE TABLE api_keys (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    user_id TEXT NOT NULL REFERENCES users(id),
+    key_hash TEXT UNIQUE NOT NULL,
+    scopes TEXT NOT NULL,
+    created_at integer(8) not null default (strftime('%s','now')),
+    expires_at integer(8),
+    last_used_at integer(8),
+    revoked_at integer(8),
+    revoked_by TEXT REFERENCES users(id)
+);
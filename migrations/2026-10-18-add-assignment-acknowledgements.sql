@@ -0,0 +1,2 @@
+ALTER TABLE assignments ADD COLUMN acknowledged_at integer(8);
+ALTER TABLE assignments ADD COLUMN acknowledged_by TEXT REFERENCES users(id);
@@ -0,0 +1,11 @@
+CREATE TABLE audit_log (
+    id TEXT PRIMARY KEY,
+    at_time integer(8) not null default (strftime('%s','now')),
+    actor_id TEXT REFERENCES users(id),
+    action TEXT NOT NULL,
+    target_type TEXT NOT NULL,
+    target_id TEXT,
+    details TEXT
+);
+
+CREATE INDEX audit_log_target ON audit_log(target_type, target_id);
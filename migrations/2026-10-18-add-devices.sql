@@ -0,0 +1,27 @@
+CREATE TABLE devices (
+    id TEXT PRIMARY KEY,
+    resource_id TEXT NOT NULL REFERENCES resources(id),
+    name TEXT NOT NULL,
+    token_hash TEXT UNIQUE NOT NULL,
+    created_at integer(8) not null default (strftime('%s','now')),
+    created_by TEXT REFERENCES users(id),
+    revoked_at integer(8),
+    revoked_by TEXT REFERENCES users(id),
+    last_seen_at integer(8),
+    external_id TEXT
+);
+
+CREATE UNIQUE INDEX devices_external_id ON devices(external_id) WHERE revoked_at IS NULL;
+
+CREATE TABLE rejected_location_updates (
+    id TEXT PRIMARY KEY,
+    at_time integer(8) not null default (strftime('%s','now')),
+    device_id TEXT REFERENCES devices(id),
+    resource_id TEXT,
+    remote_addr TEXT,
+    reason TEXT NOT NULL,
+    last_at integer(8) not null default (strftime('%s','now')),
+    count integer NOT NULL DEFAULT 1
+);
+
+CREATE INDEX rejected_location_updates_last_at ON rejected_location_updates(last_at);
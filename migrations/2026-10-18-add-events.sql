@@ -0,0 +1,9 @@
+CREATE TABLE events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    at_time integer(8) not null default (strftime('%s','now')),
+    kind TEXT NOT NULL,
+    entity_type TEXT NOT NULL,
+    entity_id TEXT NOT NULL,
+    payload TEXT NOT NULL
+);
+CREATE INDEX events_at_time ON events(at_time);
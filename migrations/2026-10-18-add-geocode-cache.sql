@@ -0,0 +1,9 @@
+CREATE TABLE geocode_cache (
+    lat_key TEXT NOT NULL,
+    lon_key TEXT NOT NULL,
+    response TEXT NOT NULL,
+    created_at integer(8) not null default (strftime('%s','now')),
+    PRIMARY KEY (lat_key, lon_key)
+);
+
+CREATE INDEX geocode_cache_created_at ON geocode_cache(created_at);
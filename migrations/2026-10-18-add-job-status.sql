@@ -0,0 +1,12 @@
+ALTER TABLE jobs ADD COLUMN status TEXT NOT NULL DEFAULT 'pending';
+
+UPDATE jobs SET status = 'closed' WHERE closed_at IS NOT NULL;
+
+CREATE TABLE job_status_history (
+    id TEXT PRIMARY KEY,
+    job_id TEXT NOT NULL REFERENCES jobs(id),
+    from_status TEXT,
+    to_status TEXT NOT NULL,
+    changed_at integer(8) not null default (strftime('%s','now')),
+    changed_by TEXT REFERENCES users(id)
+);
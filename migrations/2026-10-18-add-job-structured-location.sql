@@ -0,0 +1,5 @@
+ALTER TABLE jobs ADD COLUMN address TEXT;
+ALTER TABLE jobs ADD COLUMN cross_street TEXT;
+ALTER TABLE jobs ADD COLUMN location_notes TEXT;
+ALTER TABLE jobs ADD COLUMN geocode_confidence REAL;
+ALTER TABLE jobs ADD COLUMN location_overridden BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,6 @@
+CREATE TABLE login_throttles (
+    key TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at integer(8) not null default (strftime('%s','now')),
+    locked_until integer(8)
+);
@@ -0,0 +1,29 @@
+ALTER TABLE users ADD COLUMN mfa_enabled BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE users ADD COLUMN mfa_secret TEXT;
+ALTER TABLE users ADD COLUMN mfa_last_step integer(8);
+
+CREATE TABLE mfa_recovery_codes (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id),
+    code_hash TEXT NOT NULL,
+    created_at integer(8) not null default (strftime('%s','now')),
+    used_at integer(8)
+);
+
+CREATE INDEX mfa_recovery_codes_user ON mfa_recovery_codes(user_id);
+
+CREATE TABLE mfa_challenges (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id),
+    token_hash TEXT UNIQUE NOT NULL,
+    created_at integer(8) not null default (strftime('%s','now')),
+    expires_at integer(8) not null,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    used_at integer(8)
+);
+
+CREATE TABLE mfa_required_roles (
+    role TEXT PRIMARY KEY,
+    set_at integer(8) not null default (strftime('%s','now')),
+    set_by TEXT REFERENCES users(id)
+);
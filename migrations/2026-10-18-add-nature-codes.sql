@@ -0,0 +1,13 @@
+CREATE TABLE nature_codes (
+    code TEXT PRIMARY KEY,
+    description TEXT NOT NULL,
+    default_priority INTEGER NOT NULL DEFAULT 3,
+    recommended_resource_types TEXT NOT NULL DEFAULT '[]',
+    response_plan TEXT,
+    enabled BOOLEAN NOT NULL DEFAULT true,
+    created_at integer(8) not null default (strftime('%s','now')),
+    updated_at integer(8) not null default (strftime('%s','now'))
+);
+
+ALTER TABLE jobs ADD COLUMN nature_code TEXT REFERENCES nature_codes(code);
+ALTER TABLE jobs ADD COLUMN priority INTEGER;
@@ -0,0 +1,11 @@
+CREATE TABLE password_resets (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id),
+    token_hash TEXT UNIQUE NOT NULL,
+    created_at integer(8) not null default (strftime('%s','now')),
+    created_by TEXT REFERENCES users(id),
+    expires_at integer(8) not null,
+    used_at integer(8)
+);
+
+CREATE INDEX password_resets_user ON password_resets(user_id);
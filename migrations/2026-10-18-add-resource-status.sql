@@ -0,0 +1,17 @@
+ALTER TABLE resources ADD COLUMN status TEXT NOT NULL DEFAULT 'off_duty';
+ALTER TABLE resources ADD COLUMN status_changed_at integer(8);
+
+UPDATE resources SET status = 'available' WHERE in_service;
+
+CREATE TABLE resource_status_history (
+    id TEXT PRIMARY KEY,
+    resource_id TEXT NOT NULL REFERENCES resources(id),
+    status TEXT NOT NULL,
+    note TEXT,
+    changed_at integer(8) not null default (strftime('%s','now')),
+    changed_by TEXT REFERENCES users(id)
+);
+
+ALTER TABLE assignments ADD COLUMN en_route_at integer(8);
+ALTER TABLE assignments ADD COLUMN on_scene_at integer(8);
+ALTER TABLE assignments ADD COLUMN cleared_at integer(8);
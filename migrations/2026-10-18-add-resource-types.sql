@@ -0,0 +1,5 @@
+ALTER TABLE resources ADD COLUMN resource_type TEXT;
+ALTER TABLE resources ADD COLUMN capabilities TEXT NOT NULL DEFAULT '[]';
+
+ALTER TABLE jobs ADD COLUMN latitude REAL;
+ALTER TABLE jobs ADD COLUMN longitude REAL;
@@ -0,0 +1,16 @@
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id),
+    refresh_token_hash TEXT UNIQUE NOT NULL,
+    previous_token_hash TEXT,
+    created_at integer(8) not null default (strftime('%s','now')),
+    last_seen_at integer(8) not null default (strftime('%s','now')),
+    expires_at integer(8) not null,
+    user_agent TEXT,
+    ip_address TEXT,
+    revoked_at integer(8),
+    revoked_by TEXT REFERENCES users(id)
+);
+
+CREATE INDEX sessions_user ON sessions(user_id);
+CREATE INDEX sessions_previous_token ON sessions(previous_token_hash);
@@ -0,0 +1,7 @@
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'read_only';
+
+UPDATE users SET role = CASE
+    WHEN admin THEN 'admin'
+    WHEN dispatcher THEN 'dispatcher'
+    ELSE 'read_only'
+END;
@@ -0,0 +1,20 @@
+-- user_id referenced resources(id), so no user could ever be bound to a unit.
+-- SQLite can't change a foreign key in place, so the table is rebuilt.
+CREATE TABLE resource_user_bindings_new (
+    id TEXT PRIMARY KEY,
+    resource_id TEXT NOT NULL REFERENCES resources(id),
+    user_id TEXT NOT NULL REFERENCES users(id),
+    created_at integer(8) not null default (strftime('%s','now')),
+    removed_at integer(8),
+    created_by TEXT REFERENCES users(id),
+    removed_by TEXT REFERENCES users(id)
+);
+
+INSERT INTO resource_user_bindings_new(id,resource_id,user_id,created_at,removed_at)
+    SELECT id,resource_id,user_id,created_at,removed_at FROM resource_user_bindings
+        WHERE user_id IN (SELECT id FROM users);
+
+DROP TABLE resource_user_bindings;
+ALTER TABLE resource_user_bindings_new RENAME TO resource_user_bindings;
+
+CREATE UNIQUE INDEX resource_user_bindings_active ON resource_user_bindings(resource_id, user_id) WHERE removed_at IS NULL;
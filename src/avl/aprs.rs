@@ -0,0 +1,235 @@
+//! APRS position reports, received by connecting to an APRS-IS server as a
+//! client and filtering for the callsigns of registered devices. A device's
+//! external id is its callsign, including any SSID, like `N0CALL-9`.
+//!
+//! APRS is broadcast in the clear with nothing to authenticate the sender, so
+//! anyone can transmit as a registered callsign. It's only used when
+//! `APRS_ENABLED` is set.
+
+use std::{sync::Arc, time::Duration};
+
+use sqlx::{Pool, Sqlite};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::broadcast,
+};
+
+use super::{device_by_external_id, ingest, Fix};
+use crate::{db, routes::v0::stream::Event};
+
+/// How long to wait before reconnecting after the server drops us
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Parses an uncompressed `DDMM.mmN/DDDMM.mmW` position, where spaces mark
+/// digits the sender has made ambiguous on purpose.
+fn parse_uncompressed(position: &str) -> Option<(f64, f64)> {
+    // Anything past the position is a free-text comment, so only the
+    // position itself has to be ASCII to slice it up by byte offsets
+    let lat = position.get(0..8).filter(|lat| lat.is_ascii())?;
+    let lon = position.get(9..18).filter(|lon| lon.is_ascii())?;
+    let (lat, lon) = (lat.replace(' ', "0"), lon.replace(' ', "0"));
+
+    let latitude = lat[0..2].parse::<f64>().ok()? + lat[2..7].parse::<f64>().ok()? / 60.0;
+    let latitude = match &lat[7..8] {
+        "N" => latitude,
+        "S" => -latitude,
+        _ => return None,
+    };
+    let longitude = lon[0..3].parse::<f64>().ok()? + lon[3..8].parse::<f64>().ok()? / 60.0;
+    let longitude = match &lon[8..9] {
+        "E" => longitude,
+        "W" => -longitude,
+        _ => return None,
+    };
+    Some((latitude, longitude))
+}
+
+fn decode_base91(chars: &str) -> Option<f64> {
+    chars.bytes().try_fold(0.0, |acc, b| match b {
+        33..=124 => Some(acc * 91.0 + (b - 33) as f64),
+        _ => None,
+    })
+}
+
+/// Parses a compressed position: a symbol table character followed by four
+/// base-91 characters each of latitude and longitude.
+fn parse_compressed(position: &str) -> Option<(f64, f64)> {
+    let latitude = 90.0 - decode_base91(position.get(1..5)?)? / 380926.0;
+    let longitude = -180.0 + decode_base91(position.get(5..9)?)? / 190463.0;
+    Some((latitude, longitude))
+}
+
+/// Parses an APRS-IS line like `N0CALL-9>APRS,TCPIP*:!4903.50N/07201.75W-`
+/// into the sender's callsign and position. Mic-E and other position formats
+/// aren't supported.
+pub fn parse_packet(line: &str) -> Option<Fix> {
+    let (header, info) = line.trim_end().split_once(':')?;
+    let (source, _path) = header.split_once('>')?;
+
+    let position = match info.chars().next()? {
+        '!' | '=' => info.get(1..)?,
+        // Position preceded by a 7-character timestamp, like `092345z`
+        '/' | '@' => {
+            let timestamp = info.get(1..8)?.as_bytes();
+            if !timestamp[..6].iter().all(u8::is_ascii_digit)
+                || !matches!(timestamp[6], b'z' | b'h' | b'/')
+            {
+                return None;
+            }
+            info.get(8..)?
+        }
+        _ => return None,
+    };
+    let (latitude, longitude) = match position.chars().next()? {
+        '0'..='9' | ' ' => parse_uncompressed(position)?,
+        _ => parse_compressed(position)?,
+    };
+
+    Some(Fix {
+        external_id: Some(source.to_uppercase()),
+        latitude,
+        longitude,
+        at_time: None,
+    })
+}
+
+/// Stays connected to an APRS-IS server, reconnecting whenever the connection
+/// drops. The filter is rebuilt from the registered devices on each connect.
+pub async fn run(
+    server: String,
+    callsign: String,
+    passcode: String,
+    pool: Arc<Pool<Sqlite>>,
+    event_tx: Arc<broadcast::Sender<Event>>,
+) {
+    loop {
+        if let Err(e) = connect(&server, &callsign, &passcode, &pool, &event_tx).await {
+            tracing::warn!("APRS-IS connection to {} failed: {}", server, e);
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn connect(
+    server: &str,
+    callsign: &str,
+    passcode: &str,
+    pool: &Arc<Pool<Sqlite>>,
+    event_tx: &Arc<broadcast::Sender<Event>>,
+) -> anyhow::Result<()> {
+    let callsigns = db::devices::list_external_ids(pool).await?;
+    if callsigns.is_empty() {
+        tracing::debug!("no devices with external ids, not connecting to APRS-IS");
+        return Ok(());
+    }
+
+    let mut stream = TcpStream::connect(server).await?;
+    let login = format!(
+        "user {} pass {} vers integral {} filter b/{}\r\n",
+        callsign,
+        passcode,
+        option_env!("CARGO_PKG_VERSION").unwrap_or("unknown"),
+        callsigns.join("/")
+    );
+    stream.write_all(login.as_bytes()).await?;
+    tracing::info!("connected to APRS-IS server {}", server);
+
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        // Server comments and keepalives
+        if line.starts_with('#') {
+            continue;
+        }
+        let Some(fix) = parse_packet(&line) else {
+            continue;
+        };
+        let Some(callsign) = &fix.external_id else {
+            continue;
+        };
+        let ingested = match device_by_external_id(pool, callsign, "aprs", None).await {
+            Ok(device) => ingest(pool, event_tx, &device, fix, "aprs", None).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = ingested {
+            tracing::debug!("failed to ingest APRS position: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn parses_uncompressed_positions() {
+        let fix = parse_packet("N0CALL-9>APRS,TCPIP*,qAC,T2TEST:!4903.50N/07201.75W-Test 001234")
+            .unwrap();
+        assert_eq!(fix.external_id.as_deref(), Some("N0CALL-9"));
+        assert_close(fix.latitude, 49.058333);
+        assert_close(fix.longitude, -72.029166);
+        assert_eq!(fix.at_time, None);
+
+        let fix = parse_packet("N0CALL>APRS,WIDE2-1,qAR,N0GATE:@092345z4903.50S/07201.75E>088/036")
+            .unwrap();
+        assert_close(fix.latitude, -49.058333);
+        assert_close(fix.longitude, 72.029166);
+    }
+
+    #[test]
+    fn parses_compressed_positions() {
+        let fix = parse_packet("N0CALL>APRS,TCPIP*:!/5L!!<*e7>7P[").unwrap();
+        assert_close(fix.latitude, 49.5);
+        assert_close(fix.longitude, -72.75);
+    }
+
+    #[test]
+    fn fills_in_ambiguous_digits() {
+        let fix = parse_packet("N0CALL>APRS:=4903.  N/07201.  W-").unwrap();
+        assert_close(fix.latitude, 49.05);
+        assert_close(fix.longitude, -72.016666);
+    }
+
+    #[test]
+    fn uppercases_callsigns() {
+        let fix = parse_packet("n0call-9>APRS:!4903.50N/07201.75W-").unwrap();
+        assert_eq!(fix.external_id.as_deref(), Some("N0CALL-9"));
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        // Server comment
+        assert!(parse_packet("# aprsc 2.1.14-g5e22b37").is_none());
+        // Mic-E isn't supported
+        assert!(parse_packet("N0CALL-9>T7SVWP,WIDE1-1,qAR,N0GATE:`c52l!J>/]\"4)}=").is_none());
+        // Status report, not a position
+        assert!(parse_packet("N0CALL>APRS:>Net control tonight").is_none());
+        assert!(parse_packet("N0CALL>APRS:!4903.50X/07201.75W-").is_none());
+        assert!(parse_packet("N0CALL>APRS:!4903.50N/07201.7").is_none());
+        assert!(parse_packet("N0CALL>APRS:!").is_none());
+        assert!(parse_packet("N0CALL>APRS:@0923").is_none());
+        assert!(parse_packet("N0CALL APRS !4903.50N/07201.75W-").is_none());
+        assert!(parse_packet("").is_none());
+    }
+
+    #[test]
+    fn handles_non_ascii() {
+        assert!(parse_packet("N0CALL-9>APRS,TCPIP*:!4é03.50N/07201.75W-").is_none());
+        assert!(parse_packet("N0CALL-9>APRS,TCPIP*:!4903.50N/0é201.75W-").is_none());
+        assert!(parse_packet("N0CALL-9>APRS,TCPIP*:!/5Lé!<*e7>7P[").is_none());
+        assert!(parse_packet("N0CALL-9>APRS,TCPIP*:@09é345z4903.50N/07201.75W-").is_none());
+        // Comments can be in any language
+        let fix = parse_packet("N0CALL-9>APRS,TCPIP*:!4903.50N/07201.75W-Grüße aus Köln").unwrap();
+        assert_close(fix.latitude, 49.058333);
+    }
+}
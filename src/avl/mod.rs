@@ -0,0 +1,251 @@
+//! Adapters for commodity GPS trackers. Each protocol is parsed into a [`Fix`]
+//! and recorded against the resource its device is bound to. OsmAnd and NMEA
+//! trackers authenticate with their device token. APRS can't carry one, so
+//! APRS positions are matched on the device's external id alone.
+
+use std::sync::Arc;
+
+use sqlx::{Pool, Sqlite};
+use tokio::sync::broadcast;
+
+use crate::{
+    db::{self, devices::Device},
+    geo,
+    routes::v0::stream::{self, Event},
+};
+
+pub mod aprs;
+pub mod nmea;
+pub mod osmand;
+
+/// Fixes further in the future than this are assumed to have a bad clock
+const MAX_CLOCK_SKEW: i64 = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fix {
+    /// The tracker's own identifier, matched against `devices.external_id`
+    pub external_id: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// When the fix was taken, if the tracker said
+    pub at_time: Option<i64>,
+}
+
+#[derive(Debug)]
+pub enum IngestError {
+    InvalidCoordinates,
+    UnknownDevice,
+    /// The fix names a different tracker than the device whose token sent it
+    WrongDevice,
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for IngestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IngestError::InvalidCoordinates => write!(f, "invalid coordinates"),
+            IngestError::UnknownDevice => write!(f, "unknown device"),
+            IngestError::WrongDevice => write!(f, "tracker id does not match the device"),
+            IngestError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+async fn reject(
+    pool: &Pool<Sqlite>,
+    device: Option<&Device>,
+    remote_addr: Option<String>,
+    reason: &str,
+) {
+    tracing::warn!(remote_addr = remote_addr.as_deref(), "{}", reason);
+    let result = db::devices::log_rejection(
+        pool,
+        device.map(|d| d.id.as_str()),
+        device.map(|d| d.resource_id.as_str()),
+        remote_addr,
+        reason,
+    )
+    .await;
+    if let Err(e) = result {
+        tracing::error!("failed to record rejected location update: {}", e);
+    }
+}
+
+/// Finds the active device a tracker's token belongs to. Unknown tokens are
+/// rejected and logged.
+pub async fn device_by_token(
+    pool: &Pool<Sqlite>,
+    token: &str,
+    protocol: &str,
+    remote_addr: Option<String>,
+) -> Result<Device, IngestError> {
+    match db::devices::get_by_token(pool, token).await {
+        Ok(Some(device)) => Ok(device),
+        Ok(None) => {
+            let reason = format!("invalid {} device token", protocol);
+            reject(pool, None, remote_addr, &reason).await;
+            Err(IngestError::UnknownDevice)
+        }
+        Err(e) => Err(IngestError::Database(e)),
+    }
+}
+
+/// Finds the active device with a tracker identifier, for protocols that
+/// can't send a token. Unknown trackers are rejected and logged.
+pub async fn device_by_external_id(
+    pool: &Pool<Sqlite>,
+    external_id: &str,
+    protocol: &str,
+    remote_addr: Option<String>,
+) -> Result<Device, IngestError> {
+    match db::devices::get_by_external_id(pool, external_id).await {
+        Ok(Some(device)) => Ok(device),
+        Ok(None) => {
+            let reason = format!("unknown {} tracker {}", protocol, external_id);
+            reject(pool, None, remote_addr, &reason).await;
+            Err(IngestError::UnknownDevice)
+        }
+        Err(e) => Err(IngestError::Database(e)),
+    }
+}
+
+/// Records a fix for the resource a device is bound to and notifies stream
+/// subscribers. A fix naming a different tracker than the device's own
+/// external id is rejected and logged.
+pub async fn ingest(
+    pool: &Pool<Sqlite>,
+    event_tx: &Arc<broadcast::Sender<Event>>,
+    device: &Device,
+    fix: Fix,
+    protocol: &str,
+    remote_addr: Option<String>,
+) -> Result<(), IngestError> {
+    if let (Some(claimed), Some(external_id)) = (&fix.external_id, &device.external_id) {
+        if db::devices::normalize_external_id(claimed) != *external_id {
+            let reason = format!(
+                "{} tracker {} sent a fix with device {}'s token",
+                protocol, claimed, device.id
+            );
+            reject(pool, Some(device), remote_addr, &reason).await;
+            return Err(IngestError::WrongDevice);
+        }
+    }
+    if !geo::valid_coordinates(fix.latitude, fix.longitude) {
+        return Err(IngestError::InvalidCoordinates);
+    }
+
+    let lat = fix.latitude.to_string();
+    let lon = fix.longitude.to_string();
+    let now = chrono::Utc::now().timestamp();
+    let result = match fix.at_time {
+        Some(at_time) if at_time <= now + MAX_CLOCK_SKEW => {
+            db::resources::set_location_at(pool, &device.resource_id, &lat, &lon, at_time).await
+        }
+        _ => db::resources::set_location(pool, &device.resource_id, &lat, &lon).await,
+    };
+    let location = result.map_err(IngestError::Database)?;
+
+    tracing::debug!(
+        "received {} location for resource {} from device {}",
+        protocol,
+        device.resource_id,
+        device.id
+    );
+    stream::publish_location(pool, event_tx, location).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{resources, test_pool, users};
+    use crate::permissions::Role;
+
+    fn fix(external_id: Option<&str>) -> Fix {
+        Fix {
+            external_id: external_id.map(String::from),
+            latitude: 43.08,
+            longitude: -77.67,
+            at_time: Some(1718000000),
+        }
+    }
+
+    #[tokio::test]
+    async fn trackers_authenticate_with_their_token() {
+        let pool = test_pool().await;
+        let (event_tx, _event_rx) = broadcast::channel(16);
+        let event_tx = Arc::new(event_tx);
+        let admin = users::create_user(
+            &pool,
+            "admin@example.com",
+            "password",
+            "Admin",
+            None,
+            Role::Admin,
+            None,
+        )
+        .await
+        .unwrap();
+        let resource = resources::create_resource(&pool, "Medic 1", None, None, vec![])
+            .await
+            .unwrap();
+        let (device, token) = db::devices::create(
+            &pool,
+            &resource.id,
+            "Medic 1 phone",
+            Some(String::from(" n0call-9 ")),
+            &admin.id,
+        )
+        .await
+        .unwrap();
+        assert_eq!(device.external_id.as_deref(), Some("N0CALL-9"));
+
+        let device = device_by_token(&pool, &token, "osmand", None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            device_by_token(&pool, "idv_wrong", "osmand", None).await,
+            Err(IngestError::UnknownDevice)
+        ));
+        assert_eq!(
+            device_by_external_id(&pool, "n0call-9", "aprs", None)
+                .await
+                .unwrap()
+                .id,
+            device.id
+        );
+
+        // The tracker's own id is optional, and matched without regard to case
+        ingest(&pool, &event_tx, &device, fix(None), "nmea", None)
+            .await
+            .unwrap();
+        ingest(
+            &pool,
+            &event_tx,
+            &device,
+            fix(Some("N0call-9")),
+            "osmand",
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            ingest(
+                &pool,
+                &event_tx,
+                &device,
+                fix(Some("N0CALL-7")),
+                "osmand",
+                None
+            )
+            .await,
+            Err(IngestError::WrongDevice)
+        ));
+
+        let rejections = db::devices::list_rejections(&pool).await.unwrap();
+        assert_eq!(rejections.len(), 2);
+        assert!(rejections
+            .iter()
+            .any(|r| r.device_id.as_deref() == Some(device.id.as_str())));
+    }
+}
@@ -0,0 +1,410 @@
+//! NMEA 0183 sentences streamed over TCP. NMEA doesn't identify the sender, so
+//! a connection must start by sending its device token on a line of its own,
+//! followed by `$GPRMC`/`$GPGGA` (or any other talker's) sentences.
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use chrono::NaiveDate;
+use sqlx::{Pool, Sqlite};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, Semaphore},
+};
+
+use super::{device_by_token, ingest, Fix, IngestError};
+use crate::db::devices::Device;
+use crate::routes::v0::stream::Event;
+
+/// Trackers commonly send a fix every second, far more than we need to keep
+const MIN_FIX_INTERVAL: i64 = 5;
+/// Sentences are at most 82 characters, and tokens are well under this, so
+/// anything longer isn't a tracker
+const MAX_LINE_LENGTH: usize = 256;
+const MAX_CONNECTIONS: usize = 512;
+/// How long a new connection has to send its device token
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a tracker can go without sending anything before it's dropped.
+/// Trackers that report less often than this should send keepalives.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Checks the `*hh` checksum on the end of a sentence, if it has one.
+pub fn checksum_valid(sentence: &str) -> bool {
+    let body = sentence.trim().trim_start_matches('$');
+    match body.split_once('*') {
+        Some((data, checksum)) => {
+            let computed = data.bytes().fold(0u8, |acc, b| acc ^ b);
+            u8::from_str_radix(checksum.trim(), 16).is_ok_and(|expected| expected == computed)
+        }
+        None => true,
+    }
+}
+
+/// Parses a `ddmm.mmmm` or `dddmm.mmmm` coordinate and its hemisphere.
+fn parse_coordinate(value: &str, hemisphere: &str, degree_digits: usize) -> Option<f64> {
+    let degrees = value.get(..degree_digits)?.parse::<f64>().ok()?;
+    let minutes = value.get(degree_digits..)?.parse::<f64>().ok()?;
+    let coordinate = degrees + minutes / 60.0;
+    match hemisphere {
+        "N" | "E" => Some(coordinate),
+        "S" | "W" => Some(-coordinate),
+        _ => None,
+    }
+}
+
+/// Parses the `hhmmss.ss` time and `ddmmyy` date fields of an RMC sentence.
+fn parse_timestamp(time: &str, date: &str) -> Option<i64> {
+    if !time.is_ascii() || !date.is_ascii() || time.len() < 6 || date.len() != 6 {
+        return None;
+    }
+    // Two-digit years, so anything from the 80s or later is last century
+    let year = match date[4..6].parse::<i32>().ok()? {
+        year @ 80.. => 1900 + year,
+        year => 2000 + year,
+    };
+    let date = NaiveDate::from_ymd_opt(year, date[2..4].parse().ok()?, date[0..2].parse().ok()?)?;
+    let time = date.and_hms_opt(
+        time[0..2].parse().ok()?,
+        time[2..4].parse().ok()?,
+        time[4..6].parse().ok()?,
+    )?;
+    Some(time.and_utc().timestamp())
+}
+
+/// Parses the position out of an RMC or GGA sentence, returning the latitude,
+/// longitude and (for RMC) the time of the fix. Sentences without a valid fix,
+/// with a bad checksum, or of any other type are ignored.
+pub fn parse_sentence(sentence: &str) -> Option<(f64, f64, Option<i64>)> {
+    let sentence = sentence.trim();
+    if !sentence.starts_with('$') || !checksum_valid(sentence) {
+        return None;
+    }
+    let data = sentence[1..].split('*').next()?;
+    let fields = data.split(',').collect::<Vec<_>>();
+    let kind = fields.first()?.get(2..)?;
+
+    match kind {
+        // $GPRMC,time,status,lat,N,lon,W,speed,course,date,...
+        "RMC" if fields.len() >= 10 => {
+            if fields[2] != "A" {
+                return None;
+            }
+            Some((
+                parse_coordinate(fields[3], fields[4], 2)?,
+                parse_coordinate(fields[5], fields[6], 3)?,
+                parse_timestamp(fields[1], fields[9]),
+            ))
+        }
+        // $GPGGA,time,lat,N,lon,W,quality,...
+        "GGA" if fields.len() >= 7 => {
+            if fields[6].is_empty() || fields[6] == "0" {
+                return None;
+            }
+            Some((
+                parse_coordinate(fields[2], fields[3], 2)?,
+                parse_coordinate(fields[4], fields[5], 3)?,
+                None,
+            ))
+        }
+        _ => None,
+    }
+}
+
+pub async fn listen(
+    bind_address: SocketAddr,
+    pool: Arc<Pool<Sqlite>>,
+    event_tx: Arc<broadcast::Sender<Event>>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(bind_address).await?;
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    tracing::info!("listening for NMEA trackers on {}", bind_address);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            tracing::warn!("too many NMEA connections, refusing {}", peer);
+            continue;
+        };
+        let pool = pool.clone();
+        let event_tx = event_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, peer, pool, event_tx).await {
+                tracing::debug!("NMEA connection from {} closed: {}", peer, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Reads a line of at most `MAX_LINE_LENGTH` bytes, failing if it's longer or
+/// doesn't arrive within `timeout`. Returns false at the end of the stream.
+async fn read_line(
+    reader: &mut BufReader<TcpStream>,
+    line: &mut String,
+    timeout: Duration,
+) -> io::Result<bool> {
+    line.clear();
+    let read = tokio::time::timeout(
+        timeout,
+        reader.take(MAX_LINE_LENGTH as u64 + 1).read_line(line),
+    )
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for a line"))??;
+    if read > MAX_LINE_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(read > 0)
+}
+
+/// Reads the device token from the first line that isn't blank. Returns
+/// `None` if the connection closes first.
+async fn read_token(
+    reader: &mut BufReader<TcpStream>,
+    line: &mut String,
+    pool: &Pool<Sqlite>,
+    remote_addr: &str,
+) -> io::Result<Option<Device>> {
+    loop {
+        if !read_line(reader, line, IDLE_TIMEOUT).await? {
+            return Ok(None);
+        }
+        let token = line.trim();
+        if token.is_empty() {
+            continue;
+        }
+        return match device_by_token(pool, token, "nmea", Some(remote_addr.to_string())).await {
+            Ok(device) => Ok(Some(device)),
+            Err(IngestError::Database(e)) => Err(io::Error::other(e.to_string())),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "invalid device token",
+            )),
+        };
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    pool: Arc<Pool<Sqlite>>,
+    event_tx: Arc<broadcast::Sender<Event>>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let remote_addr = peer.ip().to_string();
+
+    // Connections that don't authenticate quickly would otherwise hold a
+    // slot forever, however they stall
+    let device = tokio::time::timeout(
+        AUTH_TIMEOUT,
+        read_token(&mut reader, &mut line, &pool, &remote_addr),
+    )
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for a token"))??;
+    let Some(device) = device else {
+        return Ok(());
+    };
+
+    let mut last_fix = i64::MIN;
+    while read_line(&mut reader, &mut line, IDLE_TIMEOUT).await? {
+        let Some((latitude, longitude, at_time)) = parse_sentence(&line) else {
+            continue;
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        if now - last_fix < MIN_FIX_INTERVAL {
+            continue;
+        }
+        last_fix = now;
+
+        let fix = Fix {
+            external_id: None,
+            latitude,
+            longitude,
+            at_time,
+        };
+        if let Err(e) = ingest(
+            &pool,
+            &event_tx,
+            &device,
+            fix,
+            "nmea",
+            Some(remote_addr.clone()),
+        )
+        .await
+        {
+            tracing::warn!("failed to ingest NMEA fix from {}: {}", peer, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn parses_gga() {
+        let (lat, lon, at_time) =
+            parse_sentence("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47")
+                .unwrap();
+        assert_close(lat, 48.1173);
+        assert_close(lon, 11.516666);
+        assert_eq!(at_time, None);
+    }
+
+    #[test]
+    fn parses_rmc() {
+        let (lat, lon, at_time) = parse_sentence(
+            "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n",
+        )
+        .unwrap();
+        assert_close(lat, 48.1173);
+        assert_close(lon, 11.516666);
+        assert_eq!(at_time, Some(764426119));
+
+        let (lat, lon, at_time) =
+            parse_sentence("$GNRMC,001031.00,A,4404.13993,N,12118.86023,W,0.146,,100117,,,A*7B")
+                .unwrap();
+        assert_close(lat, 44.068998);
+        assert_close(lon, -121.314337);
+        assert_eq!(at_time, Some(1484007031));
+    }
+
+    #[test]
+    fn ignores_sentences_without_a_fix() {
+        // Void RMC and GGA with no fix quality
+        assert!(parse_sentence(
+            "$GPRMC,123519,V,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*7D"
+        )
+        .is_none());
+        assert!(parse_sentence(
+            "$GPGGA,092750.000,5321.6802,N,00630.3372,W,0,8,1.03,61.7,M,55.2,M,,*77"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_sentences() {
+        // Bad checksum
+        assert!(parse_sentence(
+            "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*00"
+        )
+        .is_none());
+        // Not a position
+        assert!(parse_sentence(
+            "$GPGSV,2,1,08,01,40,083,46,02,17,308,41,12,07,344,39,14,22,228,45*75"
+        )
+        .is_none());
+        // Truncated
+        assert!(parse_sentence("$GPGGA,123519,4807.038,N").is_none());
+        assert!(parse_sentence("$GPGGA,1,4,N,01131.000,E,1").is_none());
+        assert!(parse_sentence("$GPGGA,1,4807.038,X,01131.000,E,1").is_none());
+        assert!(parse_sentence("GPGGA,123519,4807.038,N,01131.000,E,1").is_none());
+        assert!(parse_sentence("").is_none());
+        assert!(parse_sentence("$").is_none());
+    }
+
+    #[test]
+    fn rejects_non_ascii_sentences() {
+        assert!(parse_sentence("$GPGGA,1,4é07.038,N,01131.000,E,1").is_none());
+        assert!(parse_sentence("$GPGGA,1,4807.038,N,0é131.000,E,1").is_none());
+        // The position is fine, but the time can't be read
+        let (_, _, at_time) =
+            parse_sentence("$GPRMC,1235é,A,4807.038,N,01131.000,E,0,0,2303é,0,W").unwrap();
+        assert_eq!(at_time, None);
+        assert!(parse_sentence("$Gé").is_none());
+    }
+
+    #[test]
+    fn checks_checksums() {
+        assert!(checksum_valid(
+            "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47"
+        ));
+        assert!(!checksum_valid(
+            "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*48"
+        ));
+        assert!(!checksum_valid("$GPGGA,123519*zz"));
+        assert!(checksum_valid("$GPGGA,123519"));
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(server);
+        let mut line = String::new();
+
+        client.write_all(b"idv_token\r\n").await.unwrap();
+        assert!(read_line(&mut reader, &mut line, IDLE_TIMEOUT)
+            .await
+            .unwrap());
+        assert_eq!(line, "idv_token\r\n");
+
+        client
+            .write_all(&[b'A'; MAX_LINE_LENGTH + 1])
+            .await
+            .unwrap();
+        let err = read_line(&mut reader, &mut line, IDLE_TIMEOUT)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn idle_connections_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(server);
+        let mut line = String::new();
+
+        // Half a line and then nothing
+        client.write_all(b"$GPGGA,1235").await.unwrap();
+        let err = read_line(&mut reader, &mut line, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_connections_are_dropped() {
+        let pool = Arc::new(crate::db::test_pool().await);
+        // Skip through the wait
+        tokio::time::pause();
+        let (event_tx, _) = broadcast::channel(16);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, peer) = listener.accept().await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        let connection = tokio::spawn(handle_connection(server, peer, pool, Arc::new(event_tx)));
+        // Blank lines never get it past waiting for a token
+        let result = loop {
+            if connection.is_finished() {
+                break connection.await.unwrap();
+            }
+            client.write_all(b"\r\n").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        };
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= AUTH_TIMEOUT);
+    }
+}
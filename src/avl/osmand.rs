@@ -0,0 +1,113 @@
+//! The OsmAnd protocol used by Traccar Client and many phone apps: a plain
+//! HTTP request with the fix in its query string, like
+//! `?id=123456&lat=43.08&lon=-77.67&timestamp=1718000000`.
+//!
+//! The protocol has no credentials of its own, so trackers send their device
+//! token as a `token` parameter, or as a bearer token if the client can set
+//! headers. The `id` is optional, and checked against the device's external id
+//! when both are set.
+
+use std::collections::HashMap;
+
+use super::Fix;
+
+/// Parses an OsmAnd request's parameters. Newer clients send the position as
+/// `location=lat,lon` rather than separate `lat` and `lon` parameters.
+pub fn parse(params: &HashMap<String, String>) -> Option<Fix> {
+    let external_id = params.get("id").or_else(|| params.get("deviceid"));
+
+    let (latitude, longitude) = match (params.get("lat"), params.get("lon")) {
+        (Some(lat), Some(lon)) => (lat.parse().ok()?, lon.parse().ok()?),
+        _ => {
+            let (lat, lon) = params.get("location")?.split_once(',')?;
+            (lat.trim().parse().ok()?, lon.trim().parse().ok()?)
+        }
+    };
+
+    Some(Fix {
+        external_id: external_id.cloned(),
+        latitude,
+        longitude,
+        at_time: params.get("timestamp").and_then(|t| parse_timestamp(t)),
+    })
+}
+
+/// Timestamps are unix seconds, unix milliseconds, or RFC 3339 depending on the client
+fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    match timestamp.parse::<i64>() {
+        Ok(t) if t > 100_000_000_000 => Some(t / 1000),
+        Ok(t) => Some(t),
+        Err(_) => chrono::DateTime::parse_from_rfc3339(timestamp)
+            .ok()
+            .map(|t| t.timestamp()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(query: &str) -> HashMap<String, String> {
+        url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect()
+    }
+
+    #[test]
+    fn parses_separate_coordinates() {
+        let fix = parse(&query(
+            "id=123456&lat=43.0845&lon=-77.6749&timestamp=1718000000&speed=0.0&bearing=0.0&altitude=150.0&accuracy=12.0&batt=87.0",
+        ))
+        .unwrap();
+        assert_eq!(
+            fix,
+            Fix {
+                external_id: Some(String::from("123456")),
+                latitude: 43.0845,
+                longitude: -77.6749,
+                at_time: Some(1718000000),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_location_parameter() {
+        let fix = parse(&query(
+            "deviceid=123456&location=43.0845%2C-77.6749&timestamp=1718000000123&token=idv_abc",
+        ))
+        .unwrap();
+        assert_eq!(fix.external_id.as_deref(), Some("123456"));
+        assert_eq!((fix.latitude, fix.longitude), (43.0845, -77.6749));
+        assert_eq!(fix.at_time, Some(1718000000));
+    }
+
+    #[test]
+    fn parses_rfc3339_timestamps() {
+        let fix = parse(&query(
+            "lat=43.0845&lon=-77.6749&timestamp=2024-06-10T06%3A13%3A20Z",
+        ))
+        .unwrap();
+        assert_eq!(fix.external_id, None);
+        assert_eq!(fix.at_time, Some(1718000000));
+
+        let fix = parse(&query("lat=43.0845&lon=-77.6749&timestamp=yesterday")).unwrap();
+        assert_eq!(fix.at_time, None);
+    }
+
+    #[test]
+    fn rejects_malformed_positions() {
+        assert!(parse(&query("id=123456")).is_none());
+        assert!(parse(&query("id=123456&lat=43.0845")).is_none());
+        assert!(parse(&query("id=123456&lat=north&lon=-77.6749")).is_none());
+        assert!(parse(&query("id=123456&location=43.0845")).is_none());
+        assert!(parse(&query("id=123456&location=43.0845%2Cwest")).is_none());
+    }
+
+    #[test]
+    fn rejects_non_ascii_positions() {
+        assert!(parse(&query("id=123456&lat=4%C3%A93.08&lon=-77.67")).is_none());
+        assert!(parse(&query("id=123456&location=43.08%2C-7%C3%A97.67")).is_none());
+        let fix = parse(&query("id=%C3%A9t%C3%A9&lat=43.08&lon=-77.67")).unwrap();
+        assert_eq!(fix.external_id.as_deref(), Some("été"));
+    }
+}
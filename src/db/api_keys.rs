@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use snowflake::SnowflakeGenerator;
+use sqlx::{types::Json, FromRow, Pool, Sqlite};
+
+use super::{audit, strings, tokens};
+use crate::permissions::{ApiKeyScope, Permission};
+
+pub const KEY_PREFIX: &str = "ik_";
+
+/// A credential for scripts and integrations. Requests made with it act as
+/// the user who created it, but can only do what both its scopes and that
+/// user's current role allow.
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub user_id: String,
+    pub scopes: Json<Vec<ApiKeyScope>>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+    pub revoked_by: Option<String>,
+}
+
+impl ApiKey {
+    pub fn permissions(&self) -> Vec<Permission> {
+        self.scopes.iter().map(|scope| scope.permission()).collect()
+    }
+}
+
+pub async fn list(pool: &Pool<Sqlite>) -> Result<Vec<ApiKey>, sqlx::Error> {
+    let keys = sqlx::query_as::<_, ApiKey>(&strings::GET_API_KEYS)
+        .fetch_all(pool)
+        .await?;
+    Ok(keys)
+}
+
+/// Creates a key, returning it along with the secret. The secret is only
+/// stored hashed, so this is the only time it's available.
+pub async fn create(
+    pool: &Pool<Sqlite>,
+    name: &str,
+    scopes: &[ApiKeyScope],
+    expires_at: Option<i64>,
+    created_by: &str,
+) -> Result<(ApiKey, String), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
+    let secret = tokens::generate(KEY_PREFIX);
+
+    let key = sqlx::query_as::<_, ApiKey>(&strings::CREATE_API_KEY)
+        .bind(&id)
+        .bind(name)
+        .bind(created_by)
+        .bind(tokens::hash(&secret))
+        .bind(Json(scopes))
+        .bind(expires_at)
+        .fetch_one(&mut *transaction)
+        .await?;
+    audit::record(
+        &mut *transaction,
+        Some(created_by),
+        "api_key.created",
+        "api_key",
+        Some(&id),
+        Some(json!({"name": name, "scopes": scopes, "expiresAt": expires_at})),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok((key, secret))
+}
+
+pub async fn revoke(pool: &Pool<Sqlite>, id: &str, revoked_by: &str) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query(&strings::REVOKE_API_KEY)
+        .bind(revoked_by)
+        .bind(id)
+        .fetch_one(&mut *transaction)
+        .await?;
+    audit::record(
+        &mut *transaction,
+        Some(revoked_by),
+        "api_key.revoked",
+        "api_key",
+        Some(id),
+        None,
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Finds the live key a secret belongs to, marking it as used. Revoked and
+/// expired keys aren't returned.
+pub async fn get_by_secret(
+    pool: &Pool<Sqlite>,
+    secret: &str,
+) -> Result<Option<ApiKey>, sqlx::Error> {
+    let key = sqlx::query_as::<_, ApiKey>(&strings::TOUCH_API_KEY_BY_HASH)
+        .bind(tokens::hash(secret))
+        .fetch_optional(pool)
+        .await?;
+    Ok(key)
+}
@@ -4,7 +4,7 @@ use sqlx::{FromRow, Pool, Sqlite};
 
 use super::strings;
 
-#[derive(Serialize, Deserialize, Default, Debug, FromRow)]
+#[derive(Serialize, Deserialize, Default, Debug, FromRow, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Assignment {
     pub id: String,
@@ -14,6 +14,12 @@ pub struct Assignment {
     pub removed_at: Option<i64>,
     pub assigned_by: String,
     pub removed_by: Option<String>,
+    pub en_route_at: Option<i64>,
+    pub on_scene_at: Option<i64>,
+    pub cleared_at: Option<i64>,
+    /// When the unit confirmed it received the assignment
+    pub acknowledged_at: Option<i64>,
+    pub acknowledged_by: Option<String>,
 }
 
 pub async fn get_active_assignments(pool: &Pool<Sqlite>) -> Result<Vec<Assignment>, sqlx::Error> {
@@ -34,6 +40,17 @@ pub async fn get_assignments_for_job(
     Ok(assignments)
 }
 
+pub async fn get_active_assignment_for_resource(
+    pool: &Pool<Sqlite>,
+    resource_id: &str,
+) -> Result<Option<Assignment>, sqlx::Error> {
+    let assignment = sqlx::query_as::<_, Assignment>(&strings::GET_ACTIVE_ASSIGNMENT_FOR_RESOURCE)
+        .bind(resource_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(assignment)
+}
+
 pub async fn assign(
     pool: &Pool<Sqlite>,
     job_id: &str,
@@ -56,11 +73,34 @@ pub async fn unassign(
     pool: &Pool<Sqlite>,
     assignment_id: &str,
     assigned_by: &str,
-) -> Result<(), sqlx::Error> {
-    let _assignment = sqlx::query(&strings::REMOVE_ASSIGNMENT)
+) -> Result<Assignment, sqlx::Error> {
+    let assignment = sqlx::query_as::<_, Assignment>(&strings::REMOVE_ASSIGNMENT)
         .bind(assigned_by)
         .bind(assignment_id)
-        .execute(pool)
+        .fetch_one(pool)
         .await?;
-    Ok(())
+    Ok(assignment)
+}
+
+pub async fn get_by_id(pool: &Pool<Sqlite>, id: &str) -> Result<Option<Assignment>, sqlx::Error> {
+    let assignment = sqlx::query_as::<_, Assignment>(&strings::GET_ASSIGNMENT_BY_ID)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(assignment)
+}
+
+/// Records that a unit has received its assignment. Returns `None` if the
+/// assignment has been removed or was already acknowledged.
+pub async fn acknowledge(
+    pool: &Pool<Sqlite>,
+    id: &str,
+    acknowledged_by: &str,
+) -> Result<Option<Assignment>, sqlx::Error> {
+    let assignment = sqlx::query_as::<_, Assignment>(&strings::ACKNOWLEDGE_ASSIGNMENT)
+        .bind(acknowledged_by)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(assignment)
 }
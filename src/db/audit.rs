@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+use snowflake::SnowflakeGenerator;
+use sqlx::{types::Json, FromRow, Pool, Sqlite};
+
+use super::strings;
+
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub id: String,
+    pub at_time: i64,
+    pub actor_id: Option<String>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<String>,
+    pub details: Option<Json<serde_json::Value>>,
+}
+
+/// Records an entry in the audit log. Takes any executor so entries can be
+/// written in the same transaction as the change they describe.
+pub async fn record<'e, E>(
+    executor: E,
+    actor_id: Option<&str>,
+    action: &str,
+    target_type: &str,
+    target_id: Option<&str>,
+    details: Option<serde_json::Value>,
+) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Sqlite>,
+{
+    let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
+
+    sqlx::query(&strings::ADD_AUDIT_ENTRY)
+        .bind(&id)
+        .bind(actor_id)
+        .bind(action)
+        .bind(target_type)
+        .bind(target_id)
+        .bind(details.map(Json))
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+pub async fn list(
+    pool: &Pool<Sqlite>,
+    target_type: Option<&str>,
+    target_id: Option<&str>,
+) -> Result<Vec<AuditEntry>, sqlx::Error> {
+    let entries = sqlx::query_as::<_, AuditEntry>(&strings::GET_AUDIT_ENTRIES)
+        .bind(target_type)
+        .bind(target_id)
+        .fetch_all(pool)
+        .await?;
+    Ok(entries)
+}
@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+use snowflake::SnowflakeGenerator;
+use sqlx::{FromRow, Pool, Sqlite};
+
+use super::{strings, tokens};
+
+const TOKEN_PREFIX: &str = "idv_";
+/// How long repeated rejections are counted on the same row
+const REJECTION_WINDOW_SECONDS: i64 = 10 * 60;
+
+/// A GPS tracker or phone allowed to post locations for a single resource.
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub id: String,
+    pub resource_id: String,
+    pub name: String,
+    pub created_at: i64,
+    pub created_by: Option<String>,
+    pub revoked_at: Option<i64>,
+    pub revoked_by: Option<String>,
+    pub last_seen_at: Option<i64>,
+    /// The identifier a tracker reports itself with, like an IMEI or APRS
+    /// callsign, for protocols that can't carry a device token
+    pub external_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RejectedLocationUpdate {
+    pub id: String,
+    /// When the first of these rejections happened
+    pub at_time: i64,
+    pub device_id: Option<String>,
+    pub resource_id: Option<String>,
+    pub remote_addr: Option<String>,
+    pub reason: String,
+    /// When the most recent one happened
+    pub last_at: i64,
+    /// How many times the same device or address was rejected for the same
+    /// reason since `at_time`
+    pub count: i64,
+}
+
+pub async fn list(
+    pool: &Pool<Sqlite>,
+    resource_id: Option<&str>,
+) -> Result<Vec<Device>, sqlx::Error> {
+    let devices = sqlx::query_as::<_, Device>(&strings::GET_DEVICES)
+        .bind(resource_id)
+        .fetch_all(pool)
+        .await?;
+    Ok(devices)
+}
+
+/// Tracker identifiers are compared without regard to case or surrounding
+/// whitespace, since APRS callsigns arrive uppercased whatever was entered.
+pub fn normalize_external_id(external_id: &str) -> String {
+    external_id.trim().to_uppercase()
+}
+
+/// Creates a device for a resource, returning it along with its token. The
+/// token is only stored hashed, so this is the only time it's available.
+pub async fn create(
+    pool: &Pool<Sqlite>,
+    resource_id: &str,
+    name: &str,
+    external_id: Option<String>,
+    created_by: &str,
+) -> Result<(Device, String), sqlx::Error> {
+    let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
+    let token = tokens::generate(TOKEN_PREFIX);
+
+    let device = sqlx::query_as::<_, Device>(&strings::CREATE_DEVICE)
+        .bind(&id)
+        .bind(resource_id)
+        .bind(name)
+        .bind(tokens::hash(&token))
+        .bind(created_by)
+        .bind(
+            external_id
+                .map(|id| normalize_external_id(&id))
+                .filter(|id| !id.is_empty()),
+        )
+        .fetch_one(pool)
+        .await?;
+    Ok((device, token))
+}
+
+/// Replaces a device's token. The old token stops working immediately.
+pub async fn rotate(pool: &Pool<Sqlite>, id: &str) -> Result<(Device, String), sqlx::Error> {
+    let token = tokens::generate(TOKEN_PREFIX);
+
+    let device = sqlx::query_as::<_, Device>(&strings::ROTATE_DEVICE_TOKEN)
+        .bind(tokens::hash(&token))
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+    Ok((device, token))
+}
+
+pub async fn revoke(pool: &Pool<Sqlite>, id: &str, revoked_by: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(&strings::REVOKE_DEVICE)
+        .bind(revoked_by)
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+    Ok(())
+}
+
+/// Finds the active device a token belongs to, marking it as seen.
+pub async fn get_by_token(pool: &Pool<Sqlite>, token: &str) -> Result<Option<Device>, sqlx::Error> {
+    let device = sqlx::query_as::<_, Device>(&strings::TOUCH_DEVICE_BY_TOKEN_HASH)
+        .bind(tokens::hash(token))
+        .fetch_optional(pool)
+        .await?;
+    Ok(device)
+}
+
+/// Finds the active device with a tracker identifier, marking it as seen.
+pub async fn get_by_external_id(
+    pool: &Pool<Sqlite>,
+    external_id: &str,
+) -> Result<Option<Device>, sqlx::Error> {
+    let device = sqlx::query_as::<_, Device>(&strings::TOUCH_DEVICE_BY_EXTERNAL_ID)
+        .bind(normalize_external_id(external_id))
+        .fetch_optional(pool)
+        .await?;
+    Ok(device)
+}
+
+pub async fn list_external_ids(pool: &Pool<Sqlite>) -> Result<Vec<String>, sqlx::Error> {
+    let ids = sqlx::query_scalar::<_, String>(&strings::GET_DEVICE_EXTERNAL_IDS)
+        .fetch_all(pool)
+        .await?;
+    Ok(ids)
+}
+
+/// Records a rejected location update. Repeats from the same device or
+/// address for the same reason are counted on one row per
+/// `REJECTION_WINDOW_SECONDS`, so a misconfigured tracker retrying every few
+/// seconds can't fill the table.
+pub async fn log_rejection(
+    pool: &Pool<Sqlite>,
+    device_id: Option<&str>,
+    resource_id: Option<&str>,
+    remote_addr: Option<String>,
+    reason: &str,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let repeated = sqlx::query(&strings::REPEAT_REJECTED_LOCATION_UPDATE)
+        .bind(device_id)
+        .bind(&remote_addr)
+        .bind(reason)
+        .bind(chrono::Utc::now().timestamp() - REJECTION_WINDOW_SECONDS)
+        .execute(&mut *transaction)
+        .await?;
+    if repeated.rows_affected() == 0 {
+        let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
+        sqlx::query(&strings::ADD_REJECTED_LOCATION_UPDATE)
+            .bind(&id)
+            .bind(device_id)
+            .bind(resource_id)
+            .bind(remote_addr)
+            .bind(reason)
+            .execute(&mut *transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Forgets rejections that haven't repeated since `before`.
+pub async fn prune_rejections(pool: &Pool<Sqlite>, before: i64) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(&strings::PRUNE_REJECTED_LOCATION_UPDATES)
+        .bind(before)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+pub async fn list_rejections(
+    pool: &Pool<Sqlite>,
+) -> Result<Vec<RejectedLocationUpdate>, sqlx::Error> {
+    let rejections =
+        sqlx::query_as::<_, RejectedLocationUpdate>(&strings::GET_REJECTED_LOCATION_UPDATES)
+            .fetch_all(pool)
+            .await?;
+    Ok(rejections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    #[tokio::test]
+    async fn repeated_rejections_are_counted() {
+        let pool = test_pool().await;
+        let addr = || Some(String::from("192.0.2.1"));
+        for _ in 0..3 {
+            log_rejection(&pool, None, None, addr(), "invalid device token")
+                .await
+                .unwrap();
+        }
+        log_rejection(&pool, None, None, addr(), "missing auth header")
+            .await
+            .unwrap();
+        log_rejection(
+            &pool,
+            None,
+            None,
+            Some(String::from("192.0.2.2")),
+            "invalid device token",
+        )
+        .await
+        .unwrap();
+
+        let rejections = list_rejections(&pool).await.unwrap();
+        assert_eq!(rejections.len(), 3);
+        let repeated = rejections
+            .iter()
+            .find(|r| {
+                r.remote_addr.as_deref() == Some("192.0.2.1") && r.reason == "invalid device token"
+            })
+            .unwrap();
+        assert_eq!(repeated.count, 3);
+        assert!(rejections
+            .iter()
+            .filter(|r| r.id != repeated.id)
+            .all(|r| r.count == 1));
+    }
+
+    #[tokio::test]
+    async fn prune_rejections_by_last_rejection() {
+        let pool = test_pool().await;
+        log_rejection(&pool, None, None, None, "missing auth header")
+            .await
+            .unwrap();
+        let now = chrono::Utc::now().timestamp();
+
+        assert_eq!(prune_rejections(&pool, now - 60).await.unwrap(), 0);
+        assert_eq!(prune_rejections(&pool, now + 60).await.unwrap(), 1);
+        assert!(list_rejections(&pool).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow, Pool, Sqlite};
+
+use super::{jobs::Job, resources::Resource, resources::ResourceLocation, strings};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Closed,
+    Assigned,
+    Unassigned,
+}
+
+impl ChangeKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            ChangeKind::Created => "created",
+            ChangeKind::Updated => "updated",
+            ChangeKind::Closed => "closed",
+            ChangeKind::Assigned => "assigned",
+            ChangeKind::Unassigned => "unassigned",
+        }
+    }
+}
+
+/// The state of whatever changed, as of the change.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Entity {
+    Job(Job),
+    /// Resources are sent without their reverse-geocoded location, since
+    /// new fixes are sent on their own as `ResourceLocation`s
+    Resource(Resource),
+    ResourceLocation(ResourceLocation),
+}
+
+impl Entity {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Entity::Job(_) => "job",
+            Entity::Resource(_) => "resource",
+            Entity::ResourceLocation(_) => "resource_location",
+        }
+    }
+}
+
+/// A change sent to streaming clients. Ids only ever increase, so a client
+/// can ask for everything after the last one it saw.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub id: i64,
+    pub at_time: i64,
+    pub kind: ChangeKind,
+    pub entity_id: String,
+    #[serde(flatten)]
+    pub entity: Entity,
+}
+
+impl Event {
+    /// The entity type and kind of change, like `job.created`
+    pub fn name(&self) -> String {
+        format!("{}.{}", self.entity.type_name(), self.kind.as_str())
+    }
+}
+
+#[derive(FromRow)]
+struct EventRow {
+    id: i64,
+    at_time: i64,
+    kind: ChangeKind,
+    entity_id: String,
+    payload: Json<Entity>,
+}
+
+impl From<EventRow> for Event {
+    fn from(row: EventRow) -> Self {
+        Event {
+            id: row.id,
+            at_time: row.at_time,
+            kind: row.kind,
+            entity_id: row.entity_id,
+            entity: row.payload.0,
+        }
+    }
+}
+
+pub async fn record(
+    pool: &Pool<Sqlite>,
+    kind: ChangeKind,
+    entity_id: &str,
+    entity: Entity,
+) -> Result<Event, sqlx::Error> {
+    let row = sqlx::query_as::<_, EventRow>(&strings::RECORD_EVENT)
+        .bind(kind)
+        .bind(entity.type_name())
+        .bind(entity_id)
+        .bind(Json(&entity))
+        .fetch_one(pool)
+        .await?;
+    Ok(row.into())
+}
+
+/// Events after `id`, oldest first, if the log can still provide all of them
+/// within `limit`. `None` means some have been pruned or there are too many,
+/// and the client is better off fetching the current state.
+pub async fn list_since(
+    pool: &Pool<Sqlite>,
+    id: i64,
+    limit: i64,
+) -> Result<Option<Vec<Event>>, sqlx::Error> {
+    let oldest = sqlx::query_scalar::<_, Option<i64>>(&strings::GET_OLDEST_EVENT_ID)
+        .fetch_one(pool)
+        .await?;
+    if oldest.is_some_and(|oldest| oldest > id + 1) {
+        return Ok(None);
+    }
+
+    let rows = sqlx::query_as::<_, EventRow>(&strings::GET_EVENTS_SINCE)
+        .bind(id)
+        .bind(limit + 1)
+        .fetch_all(pool)
+        .await?;
+    if rows.len() as i64 > limit {
+        return Ok(None);
+    }
+    Ok(Some(rows.into_iter().map(Event::from).collect()))
+}
+
+pub async fn prune(pool: &Pool<Sqlite>, before: i64) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(&strings::PRUNE_EVENTS)
+        .bind(before)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
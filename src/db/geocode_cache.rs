@@ -0,0 +1,43 @@
+use sqlx::{types::Json, Pool, Sqlite};
+
+use super::{resources::GeocodeResponse, strings};
+
+/// Looks up a cached reverse-geocode result, ignoring entries created before
+/// `not_before`.
+pub async fn get(
+    pool: &Pool<Sqlite>,
+    lat_key: &str,
+    lon_key: &str,
+    not_before: i64,
+) -> Result<Option<GeocodeResponse>, sqlx::Error> {
+    let resp = sqlx::query_scalar::<_, Json<GeocodeResponse>>(&strings::GET_GEOCODE_CACHE_ENTRY)
+        .bind(lat_key)
+        .bind(lon_key)
+        .bind(not_before)
+        .fetch_optional(pool)
+        .await?;
+    Ok(resp.map(|r| r.0))
+}
+
+pub async fn put(
+    pool: &Pool<Sqlite>,
+    lat_key: &str,
+    lon_key: &str,
+    response: &GeocodeResponse,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(&strings::PUT_GEOCODE_CACHE_ENTRY)
+        .bind(lat_key)
+        .bind(lon_key)
+        .bind(Json(response))
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn prune(pool: &Pool<Sqlite>, not_before: i64) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(&strings::PRUNE_GEOCODE_CACHE)
+        .bind(not_before)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
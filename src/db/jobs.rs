@@ -1,11 +1,14 @@
+use std::fmt;
+
 use crate::db::strings;
 use serde::{Deserialize, Serialize};
 use snowflake::SnowflakeGenerator;
-use sqlx::{FromRow, Pool, Sqlite};
+use sqlx::{types::Json, FromRow, Pool, QueryBuilder, Sqlite};
 
 use super::assignments::{get_assignments_for_job, Assignment};
+use super::resources::RadarAddress;
 
-#[derive(Serialize, Deserialize, FromRow, Debug, Default)]
+#[derive(Serialize, Deserialize, FromRow, Debug, Default, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Job {
     pub id: String,
@@ -17,13 +20,113 @@ pub struct Job {
     pub closed_at: Option<i64>,
     pub created_by: String,
     pub closed_by: Option<String>,
+    pub status: JobStatus,
+    pub nature_code: Option<String>,
+    pub priority: Option<i64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub address: Option<Json<RadarAddress>>,
+    pub cross_street: Option<String>,
+    pub location_notes: Option<String>,
+    /// How sure the geocoder was of the coordinates, from 0 to 1
+    pub geocode_confidence: Option<f64>,
+    /// Whether a dispatcher set the coordinates by hand
+    pub location_overridden: bool,
     #[sqlx(skip)]
     pub comments: Vec<Comment>,
     #[sqlx(skip)]
     pub assignments: Vec<Assignment>,
+    #[sqlx(skip)]
+    pub status_history: Vec<JobStatusChange>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum JobStatus {
+    #[default]
+    Pending,
+    Dispatched,
+    EnRoute,
+    OnScene,
+    Cleared,
+    Closed,
+    Cancelled,
 }
 
-#[derive(Default, Serialize, Deserialize, FromRow, Debug)]
+impl JobStatus {
+    /// Closed and cancelled jobs can't be moved to any other status.
+    pub fn is_terminal(self) -> bool {
+        matches!(self, JobStatus::Closed | JobStatus::Cancelled)
+    }
+
+    pub fn can_transition_to(self, next: JobStatus) -> bool {
+        use JobStatus::*;
+        match (self, next) {
+            (Closed | Cancelled, _) => false,
+            (_, Closed) => true,
+            (Pending, Dispatched | Cancelled) => true,
+            (Dispatched, Pending | EnRoute | OnScene | Cleared | Cancelled) => true,
+            (EnRoute, Dispatched | OnScene | Cleared | Cancelled) => true,
+            (OnScene, Cleared) => true,
+            (Cleared, Dispatched) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Dispatched => "dispatched",
+            JobStatus::EnRoute => "en_route",
+            JobStatus::OnScene => "on_scene",
+            JobStatus::Cleared => "cleared",
+            JobStatus::Closed => "closed",
+            JobStatus::Cancelled => "cancelled",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Serialize, Deserialize, FromRow, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatusChange {
+    pub id: String,
+    pub job_id: String,
+    pub from_status: Option<JobStatus>,
+    pub to_status: JobStatus,
+    pub changed_at: i64,
+    pub changed_by: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum JobStatusError {
+    NotFound,
+    InvalidTransition { from: JobStatus, to: JobStatus },
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for JobStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobStatusError::NotFound => write!(f, "that job does not exist"),
+            JobStatusError::InvalidTransition { from, to } => {
+                write!(f, "cannot change job status from {} to {}", from, to)
+            }
+            JobStatusError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for JobStatusError {
+    fn from(e: sqlx::Error) -> Self {
+        JobStatusError::Database(e)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize, FromRow, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Comment {
     pub id: String,
@@ -33,10 +136,42 @@ pub struct Comment {
     pub created_by: String,
 }
 
-pub async fn get_all_jobs(pool: &Pool<Sqlite>) -> Result<Vec<Job>, sqlx::Error> {
-    let jobs = sqlx::query_as::<_, Job>(&strings::GET_ALL_JOBS)
-        .fetch_all(pool)
-        .await?;
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobSort {
+    #[default]
+    CreatedAt,
+    Priority,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobFilter {
+    pub nature_code: Option<String>,
+    pub priority: Option<i64>,
+    #[serde(default)]
+    pub sort: JobSort,
+}
+
+pub async fn get_all_jobs(
+    pool: &Pool<Sqlite>,
+    filter: &JobFilter,
+) -> Result<Vec<Job>, sqlx::Error> {
+    let mut query = QueryBuilder::<Sqlite>::new(*strings::GET_ALL_JOBS);
+    query.push(" WHERE 1 = 1");
+    if let Some(nature_code) = &filter.nature_code {
+        query.push(" AND nature_code = ").push_bind(nature_code);
+    }
+    if let Some(priority) = filter.priority {
+        query.push(" AND priority = ").push_bind(priority);
+    }
+    match filter.sort {
+        JobSort::CreatedAt => query.push(" ORDER BY created_at, id"),
+        // Lower numbers are more urgent; unprioritized jobs go last
+        JobSort::Priority => query.push(" ORDER BY priority IS NULL, priority, created_at, id"),
+    };
+
+    let jobs = query.build_query_as::<Job>().fetch_all(pool).await?;
     Ok(jobs)
 }
 
@@ -55,18 +190,33 @@ pub async fn get_job_by_id(pool: &Pool<Sqlite>, id: &str) -> Result<Option<Job>,
             let assignments = get_assignments_for_job(pool, id).await.unwrap_or_default();
             job.comments = comments;
             job.assignments = assignments;
+            job.status_history = get_status_history(pool, id).await?;
             Ok(Some(job))
         }
         None => Ok(None),
     }
 }
 
+#[derive(Debug, Default)]
+pub struct NewJob {
+    pub synopsis: String,
+    pub location: Option<String>,
+    pub caller_name: Option<String>,
+    pub caller_phone: Option<String>,
+    pub nature_code: Option<String>,
+    pub priority: Option<i64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub address: Option<RadarAddress>,
+    pub cross_street: Option<String>,
+    pub location_notes: Option<String>,
+    pub geocode_confidence: Option<f64>,
+    pub location_overridden: bool,
+}
+
 pub async fn create_job(
     pool: &Pool<Sqlite>,
-    synopsis: &str,
-    location: Option<String>,
-    caller_name: Option<String>,
-    caller_phone: Option<String>,
+    job: NewJob,
     created_by: &str,
 ) -> Result<Job, sqlx::Error> {
     let mut transaction = pool.begin().await?;
@@ -75,11 +225,20 @@ pub async fn create_job(
 
     let user = sqlx::query_as::<_, Job>(&strings::CREATE_JOB)
         .bind(id)
-        .bind(synopsis)
-        .bind(location)
-        .bind(caller_name)
-        .bind(caller_phone)
+        .bind(job.synopsis)
+        .bind(job.location)
+        .bind(job.caller_name)
+        .bind(job.caller_phone)
         .bind(created_by)
+        .bind(job.nature_code)
+        .bind(job.priority)
+        .bind(job.latitude)
+        .bind(job.longitude)
+        .bind(job.address.map(Json))
+        .bind(job.cross_street)
+        .bind(job.location_notes)
+        .bind(job.geocode_confidence)
+        .bind(job.location_overridden)
         .fetch_one(&mut *transaction)
         .await?;
 
@@ -106,20 +265,227 @@ pub async fn add_comment(
     Ok(new_comment)
 }
 
-pub async fn close_job(
+/// Sets a job's coordinates by hand, overriding whatever the geocoder found.
+/// The geocoded address and confidence are cleared, since they no longer
+/// describe the coordinates.
+pub async fn override_location(
     pool: &Pool<Sqlite>,
     job_id: &str,
-    closed_by: &str,
-) -> Result<(), sqlx::Error> {
-    sqlx::query(&strings::CLOSE_JOB)
-        .bind(closed_by)
+    latitude: f64,
+    longitude: f64,
+    cross_street: Option<String>,
+    location_notes: Option<String>,
+) -> Result<Job, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(&strings::UPDATE_JOB_LOCATION)
+        .bind(latitude)
+        .bind(longitude)
+        .bind(cross_street)
+        .bind(location_notes)
         .bind(job_id)
-        .execute(pool)
+        .fetch_one(pool)
         .await?;
-    sqlx::query(&strings::CLOSE_ASSIGNMENTS_FOR_JOB)
-        .bind(closed_by)
+    Ok(job)
+}
+
+/// Places a job where the geocoder found its location. Returns `None` if a
+/// dispatcher has set its coordinates by hand in the meantime.
+pub async fn set_geocoded_location(
+    pool: &Pool<Sqlite>,
+    job_id: &str,
+    latitude: f64,
+    longitude: f64,
+    address: RadarAddress,
+    confidence: f64,
+) -> Result<Option<Job>, sqlx::Error> {
+    let job = sqlx::query_as::<_, Job>(&strings::SET_JOB_GEOCODE)
+        .bind(latitude)
+        .bind(longitude)
+        .bind(Json(address))
+        .bind(confidence)
         .bind(job_id)
-        .execute(pool)
+        .fetch_optional(pool)
         .await?;
-    Ok(())
+    Ok(job)
+}
+
+/// Changes a job's priority, leaving a comment on the job noting the change.
+pub async fn set_priority(
+    pool: &Pool<Sqlite>,
+    job_id: &str,
+    priority: i64,
+    changed_by: &str,
+) -> Result<Option<Comment>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let current = sqlx::query_scalar::<_, Option<i64>>(&strings::GET_JOB_PRIORITY)
+        .bind(job_id)
+        .fetch_one(&mut *transaction)
+        .await?;
+    if current == Some(priority) {
+        return Ok(None);
+    }
+
+    sqlx::query(&strings::UPDATE_JOB_PRIORITY)
+        .bind(priority)
+        .bind(job_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    let comment = match current {
+        Some(current) => format!("Priority changed from P{} to P{}", current, priority),
+        None => format!("Priority set to P{}", priority),
+    };
+    let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
+    let comment = sqlx::query_as::<_, Comment>(&strings::ADD_COMMENT)
+        .bind(&id)
+        .bind(job_id)
+        .bind(comment)
+        .bind(changed_by)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+    transaction.commit().await?;
+    Ok(Some(comment))
+}
+
+pub async fn get_status_history(
+    pool: &Pool<Sqlite>,
+    job_id: &str,
+) -> Result<Vec<JobStatusChange>, sqlx::Error> {
+    let history = sqlx::query_as::<_, JobStatusChange>(&strings::GET_STATUS_HISTORY_FOR_JOB)
+        .bind(job_id)
+        .fetch_all(pool)
+        .await?;
+    Ok(history)
+}
+
+/// Moves a job to a new status, recording the transition in the job's status
+/// history. Closing or cancelling a job also releases any units assigned to it.
+pub async fn set_status(
+    pool: &Pool<Sqlite>,
+    job_id: &str,
+    status: JobStatus,
+    changed_by: &str,
+) -> Result<JobStatusChange, JobStatusError> {
+    let mut transaction = pool.begin().await?;
+
+    let current = sqlx::query_scalar::<_, JobStatus>(&strings::GET_JOB_STATUS)
+        .bind(job_id)
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(JobStatusError::NotFound)?;
+
+    if !current.can_transition_to(status) {
+        return Err(JobStatusError::InvalidTransition {
+            from: current,
+            to: status,
+        });
+    }
+
+    if status.is_terminal() {
+        sqlx::query(&strings::UPDATE_JOB_STATUS_TERMINAL)
+            .bind(status)
+            .bind(changed_by)
+            .bind(job_id)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(&strings::CLOSE_ASSIGNMENTS_FOR_JOB)
+            .bind(changed_by)
+            .bind(job_id)
+            .execute(&mut *transaction)
+            .await?;
+    } else {
+        sqlx::query(&strings::UPDATE_JOB_STATUS)
+            .bind(status)
+            .bind(job_id)
+            .execute(&mut *transaction)
+            .await?;
+    }
+
+    let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
+    let change = sqlx::query_as::<_, JobStatusChange>(&strings::ADD_JOB_STATUS_CHANGE)
+        .bind(&id)
+        .bind(job_id)
+        .bind(current)
+        .bind(status)
+        .bind(changed_by)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+    transaction.commit().await?;
+    Ok(change)
+}
+
+pub async fn close_job(
+    pool: &Pool<Sqlite>,
+    job_id: &str,
+    closed_by: &str,
+) -> Result<JobStatusChange, JobStatusError> {
+    set_status(pool, job_id, JobStatus::Closed, closed_by).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_pool, users};
+    use crate::permissions::Role;
+
+    #[tokio::test]
+    async fn override_location_clears_the_geocoded_address() {
+        let pool = test_pool().await;
+        let user = users::create_user(
+            &pool,
+            "dispatch@example.com",
+            "password",
+            "Dispatch",
+            None,
+            Role::Dispatcher,
+            None,
+        )
+        .await
+        .unwrap();
+        let job = create_job(
+            &pool,
+            NewJob {
+                synopsis: String::from("Fall"),
+                location: Some(String::from("1 Lomb Memorial Dr")),
+                latitude: Some(43.08),
+                longitude: Some(-77.67),
+                address: Some(RadarAddress {
+                    street: Some(String::from("Lomb Memorial Dr")),
+                    ..Default::default()
+                }),
+                geocode_confidence: Some(0.8),
+                ..Default::default()
+            },
+            &user.id,
+        )
+        .await
+        .unwrap();
+
+        let job = override_location(
+            &pool,
+            &job.id,
+            43.09,
+            -77.68,
+            Some(String::from("Andrews Memorial Dr")),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(job.latitude, Some(43.09));
+        assert_eq!(job.longitude, Some(-77.68));
+        assert!(job.address.is_none());
+        assert!(job.geocode_confidence.is_none());
+        assert!(job.location_overridden);
+        assert_eq!(job.location.as_deref(), Some("1 Lomb Memorial Dr"));
+        assert_eq!(job.cross_street.as_deref(), Some("Andrews Memorial Dr"));
+    }
+
+    #[tokio::test]
+    async fn override_location_of_a_missing_job() {
+        let pool = test_pool().await;
+        let result = override_location(&pool, "0", 43.09, -77.68, None, None).await;
+        assert!(matches!(result, Err(sqlx::Error::RowNotFound)));
+    }
 }
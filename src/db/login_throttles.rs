@@ -0,0 +1,130 @@
+use std::env;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Sqlite};
+
+use super::strings;
+
+/// Failures allowed before backoff kicks in
+const FREE_ATTEMPTS: i64 = 3;
+/// The longest a caller is made to wait between attempts without a lockout
+const MAX_BACKOFF_SECONDS: i64 = 15 * 60;
+/// Failures older than this are forgotten rather than added to
+const FAILURE_WINDOW_SECONDS: i64 = 24 * 60 * 60;
+
+lazy_static! {
+    /// Failures for one account before it's locked out
+    pub static ref ACCOUNT_LOCKOUT_THRESHOLD: i64 = env::var("LOGIN_LOCKOUT_THRESHOLD")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(10);
+    /// Failures from one address before it's locked out. Higher than the
+    /// account threshold since a whole station can share an address.
+    pub static ref IP_LOCKOUT_THRESHOLD: i64 = env::var("LOGIN_IP_LOCKOUT_THRESHOLD")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(50);
+    pub static ref LOCKOUT_MINUTES: i64 = env::var("LOGIN_LOCKOUT_MINUTES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(15);
+}
+
+/// Failed logins against an account or from an address, keyed by
+/// [`account_key`] or [`ip_key`].
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginThrottle {
+    pub key: String,
+    pub failures: i64,
+    pub last_failure_at: i64,
+    pub locked_until: Option<i64>,
+}
+
+impl LoginThrottle {
+    /// Seconds until another attempt is allowed, if any. Each failure past
+    /// the first few doubles the wait, up to a cap.
+    pub fn retry_after(&self, now: i64) -> Option<i64> {
+        if let Some(locked_until) = self.locked_until.filter(|until| *until > now) {
+            return Some(locked_until - now);
+        }
+        if self.failures < FREE_ATTEMPTS {
+            return None;
+        }
+        let exponent = (self.failures - FREE_ATTEMPTS).min(16) as u32;
+        let backoff = 2i64.pow(exponent).min(MAX_BACKOFF_SECONDS);
+        let ready_at = self.last_failure_at + backoff;
+        (ready_at > now).then_some(ready_at - now)
+    }
+}
+
+pub fn account_key(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+pub fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+/// The longest wait across the given keys before another attempt is allowed.
+pub async fn retry_after(pool: &Pool<Sqlite>, keys: &[&str]) -> Result<Option<i64>, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+    let mut wait = None;
+    for key in keys {
+        let throttle = sqlx::query_as::<_, LoginThrottle>(&strings::GET_LOGIN_THROTTLE)
+            .bind(key)
+            .fetch_optional(pool)
+            .await?;
+        if let Some(seconds) = throttle.and_then(|t| t.retry_after(now)) {
+            wait = wait.max(Some(seconds));
+        }
+    }
+    Ok(wait)
+}
+
+/// Counts a failed attempt against a key, locking it once it reaches
+/// `threshold`. Returns the throttle and whether this failure locked it.
+pub async fn record_failure(
+    pool: &Pool<Sqlite>,
+    key: &str,
+    threshold: i64,
+) -> Result<(LoginThrottle, bool), sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+
+    let throttle = sqlx::query_as::<_, LoginThrottle>(&strings::RECORD_LOGIN_FAILURE)
+        .bind(key)
+        .bind(now - FAILURE_WINDOW_SECONDS)
+        .fetch_one(pool)
+        .await?;
+    if throttle.failures < threshold || throttle.locked_until.is_some_and(|until| until > now) {
+        return Ok((throttle, false));
+    }
+
+    let locked_until =
+        (chrono::Utc::now() + chrono::Duration::minutes(*LOCKOUT_MINUTES)).timestamp();
+    let throttle = sqlx::query_as::<_, LoginThrottle>(&strings::LOCK_LOGIN_THROTTLE)
+        .bind(locked_until)
+        .bind(key)
+        .fetch_one(pool)
+        .await?;
+    Ok((throttle, true))
+}
+
+/// Forgets failures against a key, returning whether there were any.
+pub async fn clear(pool: &Pool<Sqlite>, key: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(&strings::CLEAR_LOGIN_THROTTLE)
+        .bind(key)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Keys with recent failures, for admins looking into a lockout.
+pub async fn list(pool: &Pool<Sqlite>) -> Result<Vec<LoginThrottle>, sqlx::Error> {
+    let throttles = sqlx::query_as::<_, LoginThrottle>(&strings::GET_LOGIN_THROTTLES)
+        .bind(chrono::Utc::now().timestamp() - FAILURE_WINDOW_SECONDS)
+        .fetch_all(pool)
+        .await?;
+    Ok(throttles)
+}
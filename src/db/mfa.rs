@@ -0,0 +1,291 @@
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use snowflake::SnowflakeGenerator;
+use sqlx::{FromRow, Pool, Sqlite};
+
+use super::{audit, strings, tokens};
+use crate::permissions::Role;
+
+const CHALLENGE_PREFIX: &str = "imc_";
+const CHALLENGE_TTL_MINUTES: i64 = 5;
+const CHALLENGE_MAX_ATTEMPTS: i64 = 5;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// A password login waiting on a second factor.
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Challenge {
+    pub id: String,
+    pub user_id: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub attempts: i64,
+    pub used_at: Option<i64>,
+}
+
+/// Recovery codes are short enough to type, so they're compared without
+/// dashes, spaces or case.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let code = hex::encode(bytes);
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+/// Stores a new secret for a user who hasn't finished enrolling. Returns
+/// false if they already have MFA enabled.
+pub async fn begin_enrollment(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+    secret: &str,
+) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query_scalar::<_, String>(&strings::BEGIN_MFA_ENROLLMENT)
+        .bind(secret)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(updated.is_some())
+}
+
+pub async fn get_secret(pool: &Pool<Sqlite>, user_id: &str) -> Result<Option<String>, sqlx::Error> {
+    let secret = sqlx::query_scalar::<_, Option<String>>(&strings::GET_MFA_SECRET)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(secret)
+}
+
+async fn replace_recovery_codes(
+    transaction: &mut sqlx::Transaction<'_, Sqlite>,
+    user_id: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query(&strings::DELETE_MFA_RECOVERY_CODES)
+        .bind(user_id)
+        .execute(&mut **transaction)
+        .await?;
+
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let code = generate_recovery_code();
+        sqlx::query(&strings::ADD_MFA_RECOVERY_CODE)
+            .bind(SnowflakeGenerator::new(0, 0).generate().to_string())
+            .bind(user_id)
+            .bind(tokens::hash(&normalize_recovery_code(&code)))
+            .execute(&mut **transaction)
+            .await?;
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+/// Turns MFA on once the user has proven their app works with a code from
+/// `step`, returning a fresh set of recovery codes. Returns `None` if there's
+/// no pending enrollment.
+pub async fn confirm_enrollment(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+    step: i64,
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let enabled = sqlx::query_scalar::<_, String>(&strings::ENABLE_MFA)
+        .bind(step)
+        .bind(user_id)
+        .fetch_optional(&mut *transaction)
+        .await?;
+    if enabled.is_none() {
+        return Ok(None);
+    }
+    let codes = replace_recovery_codes(&mut transaction, user_id).await?;
+    audit::record(
+        &mut *transaction,
+        Some(user_id),
+        "user.mfa_enabled",
+        "user",
+        Some(user_id),
+        None,
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(Some(codes))
+}
+
+pub async fn regenerate_recovery_codes(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let codes = replace_recovery_codes(&mut transaction, user_id).await?;
+    audit::record(
+        &mut *transaction,
+        Some(user_id),
+        "user.mfa_recovery_codes_regenerated",
+        "user",
+        Some(user_id),
+        None,
+    )
+    .await?;
+    transaction.commit().await?;
+    Ok(codes)
+}
+
+pub async fn disable(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+    disabled_by: &str,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query(&strings::DISABLE_MFA)
+        .bind(user_id)
+        .fetch_one(&mut *transaction)
+        .await?;
+    sqlx::query(&strings::DELETE_MFA_RECOVERY_CODES)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+    audit::record(
+        &mut *transaction,
+        Some(disabled_by),
+        "user.mfa_disabled",
+        "user",
+        Some(user_id),
+        None,
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Records the time step a code was accepted for. Returns false if that step
+/// (or a later one) was already used, so a code can't be replayed.
+pub async fn record_step(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+    step: i64,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(&strings::RECORD_MFA_STEP)
+        .bind(step)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Uses up one of a user's recovery codes, returning whether it was valid.
+pub async fn use_recovery_code(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let used = sqlx::query_scalar::<_, String>(&strings::USE_MFA_RECOVERY_CODE)
+        .bind(user_id)
+        .bind(tokens::hash(&normalize_recovery_code(code)))
+        .fetch_optional(pool)
+        .await?;
+    Ok(used.is_some())
+}
+
+/// Starts the second step of a login, returning the challenge token the
+/// client has to send back with a code.
+pub async fn create_challenge(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+) -> Result<(Challenge, String), sqlx::Error> {
+    let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
+    let token = tokens::generate(CHALLENGE_PREFIX);
+    let expires_at =
+        (chrono::Utc::now() + chrono::Duration::minutes(CHALLENGE_TTL_MINUTES)).timestamp();
+
+    let challenge = sqlx::query_as::<_, Challenge>(&strings::CREATE_MFA_CHALLENGE)
+        .bind(&id)
+        .bind(user_id)
+        .bind(tokens::hash(&token))
+        .bind(expires_at)
+        .fetch_one(pool)
+        .await?;
+    Ok((challenge, token))
+}
+
+/// Counts an attempt against a challenge, returning it if it's still open.
+/// Challenges stop accepting codes after a handful of wrong guesses.
+pub async fn attempt_challenge(
+    pool: &Pool<Sqlite>,
+    token: &str,
+) -> Result<Option<Challenge>, sqlx::Error> {
+    let challenge = sqlx::query_as::<_, Challenge>(&strings::ATTEMPT_MFA_CHALLENGE)
+        .bind(tokens::hash(token))
+        .bind(CHALLENGE_MAX_ATTEMPTS)
+        .fetch_optional(pool)
+        .await?;
+    Ok(challenge)
+}
+
+/// Closes a challenge once it's been passed. Returns false if another request
+/// got there first.
+pub async fn complete_challenge(pool: &Pool<Sqlite>, id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(&strings::COMPLETE_MFA_CHALLENGE)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn required_roles(pool: &Pool<Sqlite>) -> Result<Vec<Role>, sqlx::Error> {
+    let roles = sqlx::query_scalar::<_, Role>(&strings::GET_MFA_REQUIRED_ROLES)
+        .fetch_all(pool)
+        .await?;
+    Ok(roles)
+}
+
+pub async fn is_required(pool: &Pool<Sqlite>, role: Role) -> Result<bool, sqlx::Error> {
+    let required = sqlx::query_scalar::<_, bool>(&strings::IS_MFA_REQUIRED_FOR_ROLE)
+        .bind(role)
+        .fetch_one(pool)
+        .await?;
+    Ok(required)
+}
+
+pub async fn set_required(
+    pool: &Pool<Sqlite>,
+    role: Role,
+    required: bool,
+    set_by: &str,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    if required {
+        sqlx::query(&strings::REQUIRE_MFA_FOR_ROLE)
+            .bind(role)
+            .bind(set_by)
+            .execute(&mut *transaction)
+            .await?;
+    } else {
+        sqlx::query(&strings::UNREQUIRE_MFA_FOR_ROLE)
+            .bind(role)
+            .execute(&mut *transaction)
+            .await?;
+    }
+    audit::record(
+        &mut *transaction,
+        Some(set_by),
+        "mfa.role_requirement_changed",
+        "role",
+        Some(&role.to_string()),
+        Some(json!({ "required": required })),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(())
+}
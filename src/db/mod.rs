@@ -1,6 +1,46 @@
+pub mod api_keys;
 pub mod assignments;
+pub mod audit;
+pub mod devices;
+pub mod events;
+pub mod geocode_cache;
 pub mod jobs;
+pub mod login_throttles;
+pub mod mfa;
+pub mod nature_codes;
+pub mod oidc;
+pub mod password_resets;
 pub mod resources;
+pub mod sessions;
 pub mod users;
+pub mod webhooks;
 
 mod strings;
+mod tokens;
+
+/// An empty in-memory database with every migration applied, for tests.
+#[cfg(test)]
+pub(crate) async fn test_pool() -> sqlx::Pool<sqlx::Sqlite> {
+    use sqlx::Executor;
+
+    // Every connection to :memory: is its own database, so keep just the one
+    let pool = sqlx::pool::PoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    let mut migrations = std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    migrations.sort();
+    for migration in migrations {
+        let sql = std::fs::read_to_string(&migration).unwrap();
+        if let Err(e) = pool.execute(sql.as_str()).await {
+            panic!("failed to apply {}: {}", migration.display(), e);
+        }
+    }
+    pool
+}
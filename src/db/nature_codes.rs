@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow, Pool, Sqlite};
+
+use super::strings;
+
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NatureCode {
+    pub code: String,
+    pub description: String,
+    pub default_priority: i64,
+    pub recommended_resource_types: Json<Vec<String>>,
+    pub response_plan: Option<String>,
+    pub enabled: bool,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+pub async fn list(pool: &Pool<Sqlite>) -> Result<Vec<NatureCode>, sqlx::Error> {
+    let codes = sqlx::query_as::<_, NatureCode>(&strings::GET_NATURE_CODES)
+        .fetch_all(pool)
+        .await?;
+    Ok(codes)
+}
+
+pub async fn get(pool: &Pool<Sqlite>, code: &str) -> Result<Option<NatureCode>, sqlx::Error> {
+    let code = sqlx::query_as::<_, NatureCode>(&strings::GET_NATURE_CODE)
+        .bind(code)
+        .fetch_optional(pool)
+        .await?;
+    Ok(code)
+}
+
+pub async fn create(
+    pool: &Pool<Sqlite>,
+    code: &str,
+    description: &str,
+    default_priority: i64,
+    recommended_resource_types: Vec<String>,
+    response_plan: Option<String>,
+) -> Result<NatureCode, sqlx::Error> {
+    let code = sqlx::query_as::<_, NatureCode>(&strings::CREATE_NATURE_CODE)
+        .bind(code)
+        .bind(description)
+        .bind(default_priority)
+        .bind(Json(recommended_resource_types))
+        .bind(response_plan)
+        .fetch_one(pool)
+        .await?;
+    Ok(code)
+}
+
+pub async fn update(
+    pool: &Pool<Sqlite>,
+    code: &str,
+    description: &str,
+    default_priority: i64,
+    recommended_resource_types: Vec<String>,
+    response_plan: Option<String>,
+    enabled: bool,
+) -> Result<NatureCode, sqlx::Error> {
+    let code = sqlx::query_as::<_, NatureCode>(&strings::UPDATE_NATURE_CODE)
+        .bind(description)
+        .bind(default_priority)
+        .bind(Json(recommended_resource_types))
+        .bind(response_plan)
+        .bind(enabled)
+        .bind(code)
+        .fetch_one(pool)
+        .await?;
+    Ok(code)
+}
+
+/// Nature codes are referenced by jobs, so they're disabled rather than deleted.
+pub async fn disable(pool: &Pool<Sqlite>, code: &str) -> Result<NatureCode, sqlx::Error> {
+    let code = sqlx::query_as::<_, NatureCode>(&strings::DISABLE_NATURE_CODE)
+        .bind(code)
+        .fetch_one(pool)
+        .await?;
+    Ok(code)
+}
@@ -0,0 +1,88 @@
+use sqlx::{FromRow, Pool, Sqlite};
+
+use super::{audit, strings, users::User};
+
+/// Login attempts that haven't come back from the provider after this long
+/// are abandoned
+const LOGIN_TTL_SECONDS: i64 = 10 * 60;
+
+#[derive(Debug, FromRow, Clone)]
+pub struct OidcLogin {
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+pub async fn create_login(
+    pool: &Pool<Sqlite>,
+    state: &str,
+    nonce: &str,
+    code_verifier: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(&strings::PRUNE_OIDC_LOGINS)
+        .bind(chrono::Utc::now().timestamp() - LOGIN_TTL_SECONDS)
+        .execute(pool)
+        .await?;
+    sqlx::query(&strings::CREATE_OIDC_LOGIN)
+        .bind(state)
+        .bind(nonce)
+        .bind(code_verifier)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Removes a pending login by its state, returning it if it hadn't expired.
+/// Each state can only be used once.
+pub async fn take_login(
+    pool: &Pool<Sqlite>,
+    state: &str,
+) -> Result<Option<OidcLogin>, sqlx::Error> {
+    let login = sqlx::query_as::<_, OidcLogin>(&strings::TAKE_OIDC_LOGIN)
+        .bind(state)
+        .bind(chrono::Utc::now().timestamp() - LOGIN_TTL_SECONDS)
+        .fetch_optional(pool)
+        .await?;
+    Ok(login)
+}
+
+pub async fn get_user_by_subject(
+    pool: &Pool<Sqlite>,
+    subject: &str,
+) -> Result<Option<User>, sqlx::Error> {
+    let user = sqlx::query_as::<_, User>(&strings::GET_USER_BY_OIDC_SUBJECT)
+        .bind(subject)
+        .fetch_optional(pool)
+        .await?;
+    Ok(user)
+}
+
+/// Links an existing user to their identity at the provider. Users can only
+/// be linked once; returns `None` if this one already was.
+pub async fn link_subject(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+    subject: &str,
+) -> Result<Option<User>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let user = sqlx::query_as::<_, User>(&strings::LINK_OIDC_SUBJECT)
+        .bind(subject)
+        .bind(user_id)
+        .fetch_optional(&mut *transaction)
+        .await?;
+    if user.is_some() {
+        audit::record(
+            &mut *transaction,
+            Some(user_id),
+            "user.oidc_linked",
+            "user",
+            Some(user_id),
+            None,
+        )
+        .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(user)
+}
@@ -0,0 +1,82 @@
+use std::env;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use snowflake::SnowflakeGenerator;
+use sqlx::{FromRow, Pool, Sqlite};
+
+use super::{audit, strings, tokens};
+
+const TOKEN_PREFIX: &str = "ipr_";
+
+lazy_static! {
+    /// How long a password reset code can be used for
+    pub static ref PASSWORD_RESET_TTL_HOURS: i64 = env::var("PASSWORD_RESET_TTL_HOURS")
+        .ok()
+        .and_then(|hours| hours.parse().ok())
+        .unwrap_or(24);
+}
+
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordReset {
+    pub id: String,
+    pub user_id: String,
+    pub created_at: i64,
+    pub created_by: Option<String>,
+    pub expires_at: i64,
+    pub used_at: Option<i64>,
+}
+
+/// Issues a one-time reset code for a user, cancelling any earlier ones. The
+/// code is only stored hashed, so this is the only time it's available.
+pub async fn create(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+    created_by: &str,
+) -> Result<(PasswordReset, String), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
+    let code = tokens::generate(TOKEN_PREFIX);
+    let expires_at =
+        (chrono::Utc::now() + chrono::Duration::hours(*PASSWORD_RESET_TTL_HOURS)).timestamp();
+
+    sqlx::query(&strings::CANCEL_PASSWORD_RESETS_FOR_USER)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+    let reset = sqlx::query_as::<_, PasswordReset>(&strings::CREATE_PASSWORD_RESET)
+        .bind(&id)
+        .bind(user_id)
+        .bind(tokens::hash(&code))
+        .bind(expires_at)
+        .bind(created_by)
+        .fetch_one(&mut *transaction)
+        .await?;
+    audit::record(
+        &mut *transaction,
+        Some(created_by),
+        "user.password_reset_issued",
+        "user",
+        Some(user_id),
+        None,
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok((reset, code))
+}
+
+/// Marks a reset code as used, returning the reset it belongs to if it was
+/// still valid.
+pub async fn consume<'e, E>(executor: E, code: &str) -> Result<Option<PasswordReset>, sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Sqlite>,
+{
+    let reset = sqlx::query_as::<_, PasswordReset>(&strings::CONSUME_PASSWORD_RESET)
+        .bind(tokens::hash(code))
+        .fetch_optional(executor)
+        .await?;
+    Ok(reset)
+}
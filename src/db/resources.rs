@@ -1,16 +1,27 @@
+use std::{collections::HashMap, fmt};
+
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use snowflake::SnowflakeGenerator;
-use sqlx::{sqlite::SqliteRow, FromRow, Pool, Row, Sqlite};
+use sqlx::{sqlite::SqliteRow, types::Json, FromRow, Pool, Row, Sqlite};
 
-use super::{assignments::Assignment, strings};
+use super::{assignments::Assignment, audit, strings};
+use crate::geo;
+use crate::geocoder::{Geocoder, Point};
 
-#[derive(Serialize, Deserialize, Default, Debug, FromRow)]
+#[derive(Serialize, Deserialize, Default, Debug, FromRow, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Resource {
     pub id: String,
     pub display_name: String,
     pub comment: Option<String>,
     pub in_service: bool,
+    pub status: ResourceStatus,
+    pub status_changed_at: Option<i64>,
+    pub resource_type: Option<String>,
+    pub capabilities: Json<Vec<String>>,
+    #[sqlx(skip)]
+    pub time_in_status: Option<i64>,
     #[sqlx(skip)]
     pub current_assignment: Option<Assignment>,
     #[sqlx(skip)]
@@ -27,10 +38,71 @@ pub struct ResourceLocation {
     pub longitude: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum ResourceStatus {
+    Available,
+    EnRoute,
+    OnScene,
+    Transporting,
+    AtHospital,
+    OutOfService,
+    #[default]
+    OffDuty,
+}
+
+impl ResourceStatus {
+    pub fn is_in_service(self) -> bool {
+        !matches!(self, ResourceStatus::OutOfService | ResourceStatus::OffDuty)
+    }
+}
+
+impl fmt::Display for ResourceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ResourceStatus::Available => "available",
+            ResourceStatus::EnRoute => "en_route",
+            ResourceStatus::OnScene => "on_scene",
+            ResourceStatus::Transporting => "transporting",
+            ResourceStatus::AtHospital => "at_hospital",
+            ResourceStatus::OutOfService => "out_of_service",
+            ResourceStatus::OffDuty => "off_duty",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceStatusChange {
+    pub id: String,
+    pub resource_id: String,
+    pub status: ResourceStatus,
+    pub note: Option<String>,
+    pub changed_at: i64,
+    pub changed_by: Option<String>,
+}
+
+/// An in-service, unassigned resource along with its most recent location fix.
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AvailableResource {
+    pub id: String,
+    pub display_name: String,
+    pub resource_type: Option<String>,
+    pub capabilities: Json<Vec<String>>,
+    pub at_time: i64,
+    pub latitude: String,
+    pub longitude: String,
+}
+
 pub async fn create_resource(
     pool: &Pool<Sqlite>,
     display_name: &str,
     comment: Option<String>,
+    resource_type: Option<String>,
+    capabilities: Vec<String>,
 ) -> Result<Resource, sqlx::Error> {
     let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
 
@@ -38,34 +110,223 @@ pub async fn create_resource(
         .bind(&id)
         .bind(display_name)
         .bind(comment)
+        .bind(resource_type)
+        .bind(Json(capabilities))
         .fetch_one(pool)
         .await?;
 
     Ok(resource)
 }
 
+/// A user crewing a unit. Field units can only update the units they're bound to.
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceUserBinding {
+    pub id: String,
+    pub resource_id: String,
+    pub user_id: String,
+    pub created_at: i64,
+    pub removed_at: Option<i64>,
+    pub created_by: Option<String>,
+    pub removed_by: Option<String>,
+}
+
+/// Current crew bindings, optionally for one resource or one user.
+pub async fn list_bindings(
+    pool: &Pool<Sqlite>,
+    resource_id: Option<&str>,
+    user_id: Option<&str>,
+) -> Result<Vec<ResourceUserBinding>, sqlx::Error> {
+    let bindings = sqlx::query_as::<_, ResourceUserBinding>(&strings::GET_RESOURCE_USER_BINDINGS)
+        .bind(resource_id)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+    Ok(bindings)
+}
+
+/// Puts a user on a unit's crew.
+pub async fn bind_user(
+    pool: &Pool<Sqlite>,
+    resource_id: &str,
+    user_id: &str,
+    bound_by: &str,
+) -> Result<ResourceUserBinding, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
+    let binding = sqlx::query_as::<_, ResourceUserBinding>(&strings::BIND_USER_TO_RESOURCE)
+        .bind(&id)
+        .bind(resource_id)
+        .bind(user_id)
+        .bind(bound_by)
+        .fetch_one(&mut *transaction)
+        .await?;
+    audit::record(
+        &mut *transaction,
+        Some(bound_by),
+        "resource.user_bound",
+        "resource",
+        Some(resource_id),
+        Some(json!({"userId": user_id})),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(binding)
+}
+
+/// Takes a user off a unit's crew.
+pub async fn unbind_user(
+    pool: &Pool<Sqlite>,
+    resource_id: &str,
+    user_id: &str,
+    unbound_by: &str,
+) -> Result<ResourceUserBinding, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let binding = sqlx::query_as::<_, ResourceUserBinding>(&strings::UNBIND_USER_FROM_RESOURCE)
+        .bind(unbound_by)
+        .bind(resource_id)
+        .bind(user_id)
+        .fetch_one(&mut *transaction)
+        .await?;
+    audit::record(
+        &mut *transaction,
+        Some(unbound_by),
+        "resource.user_unbound",
+        "resource",
+        Some(resource_id),
+        Some(json!({"userId": user_id})),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(binding)
+}
+
+/// The resources a user is currently crewing.
+pub async fn bound_to_user(pool: &Pool<Sqlite>, user_id: &str) -> Result<Vec<String>, sqlx::Error> {
+    let resources = sqlx::query_scalar::<_, String>(&strings::GET_RESOURCES_BOUND_TO_USER)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+    Ok(resources)
+}
+
+/// Whether a user is currently bound to a resource, i.e. is crewing that unit.
+pub async fn is_bound_to_user(
+    pool: &Pool<Sqlite>,
+    resource_id: &str,
+    user_id: &str,
+) -> Result<bool, sqlx::Error> {
+    let bound = sqlx::query_scalar::<_, bool>(&strings::GET_RESOURCE_USER_BINDING)
+        .bind(resource_id)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(bound)
+}
+
 pub async fn set_in_service(
     pool: &Pool<Sqlite>,
     resource_id: &str,
     in_service: bool,
     assigned_by: &str,
 ) -> Result<(), sqlx::Error> {
-    sqlx::query(&strings::UPDATE_RESOURCE_IN_SERVICE)
-        .bind(in_service)
+    let current = sqlx::query_scalar::<_, ResourceStatus>(&strings::GET_RESOURCE_STATUS)
         .bind(resource_id)
-        .execute(pool)
+        .fetch_one(pool)
         .await?;
 
-    if !in_service {
-        sqlx::query(&strings::UPDATE_ASSIGNMENTS_RESOURCE_OOS)
-            .bind(assigned_by)
-            .bind(resource_id)
-            .execute(pool)
-            .await?;
+    if current.is_in_service() == in_service {
+        return Ok(());
     }
+
+    let status = match in_service {
+        true => ResourceStatus::Available,
+        false => ResourceStatus::OutOfService,
+    };
+    set_status(pool, resource_id, status, None, assigned_by).await?;
     Ok(())
 }
 
+/// Changes a resource's status and records it in the status history. If the
+/// resource is assigned to a job, the assignment's timestamps are updated to
+/// match, and the assignment is removed once the unit clears or goes out of
+/// service.
+pub async fn set_status(
+    pool: &Pool<Sqlite>,
+    resource_id: &str,
+    status: ResourceStatus,
+    note: Option<String>,
+    changed_by: &str,
+) -> Result<ResourceStatusChange, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query(&strings::UPDATE_RESOURCE_STATUS)
+        .bind(status)
+        .bind(status.is_in_service())
+        .bind(resource_id)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+    match status {
+        ResourceStatus::EnRoute => {
+            sqlx::query(&strings::UPDATE_ASSIGNMENT_EN_ROUTE)
+                .bind(resource_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        ResourceStatus::OnScene => {
+            sqlx::query(&strings::UPDATE_ASSIGNMENT_ON_SCENE)
+                .bind(resource_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        ResourceStatus::Available => {
+            sqlx::query(&strings::UPDATE_ASSIGNMENT_CLEARED)
+                .bind(changed_by)
+                .bind(resource_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        ResourceStatus::OutOfService | ResourceStatus::OffDuty => {
+            sqlx::query(&strings::UPDATE_ASSIGNMENTS_RESOURCE_OOS)
+                .bind(changed_by)
+                .bind(resource_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        ResourceStatus::Transporting | ResourceStatus::AtHospital => {}
+    }
+
+    let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
+    let change = sqlx::query_as::<_, ResourceStatusChange>(&strings::ADD_RESOURCE_STATUS_CHANGE)
+        .bind(&id)
+        .bind(resource_id)
+        .bind(status)
+        .bind(note)
+        .bind(changed_by)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+    transaction.commit().await?;
+    Ok(change)
+}
+
+pub async fn get_status_history(
+    pool: &Pool<Sqlite>,
+    resource_id: &str,
+) -> Result<Vec<ResourceStatusChange>, sqlx::Error> {
+    let history =
+        sqlx::query_as::<_, ResourceStatusChange>(&strings::GET_STATUS_HISTORY_FOR_RESOURCE)
+            .bind(resource_id)
+            .fetch_all(pool)
+            .await?;
+    Ok(history)
+}
+
 #[derive(Serialize, Deserialize, FromRow, Debug, Default, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct GeocodeResponse {
@@ -77,85 +338,394 @@ pub struct GeocodeResponse {
 #[derive(Serialize, Deserialize, FromRow, Debug, Default, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct RadarAddress {
-    address_label: Option<String>,
-    city: Option<String>,
-    country: Option<String>,
-    country_code: Option<String>,
-    county: Option<String>,
-    formatted_address: Option<String>,
-    latitude: Option<f64>,
-    layer: Option<String>,
-    longitude: Option<f64>,
-    number: Option<String>,
-    postal_code: Option<String>,
-    state: Option<String>,
-    state_code: Option<String>,
-    street: Option<String>,
-}
-
-pub async fn list(pool: &Pool<Sqlite>) -> Result<Vec<Resource>, sqlx::Error> {
-    let locations = sqlx::query_as::<_, ResourceLocation>(
-        "SELECT * FROM resource_locations GROUP BY resource_id HAVING at_time = MAX(at_time)",
-    )
-    .fetch_all(pool)
-    .await
-    .unwrap();
+    pub address_label: Option<String>,
+    pub city: Option<String>,
+    pub country: Option<String>,
+    pub country_code: Option<String>,
+    pub county: Option<String>,
+    pub formatted_address: Option<String>,
+    pub latitude: Option<f64>,
+    pub layer: Option<String>,
+    pub longitude: Option<f64>,
+    pub number: Option<String>,
+    pub postal_code: Option<String>,
+    pub state: Option<String>,
+    pub state_code: Option<String>,
+    pub street: Option<String>,
+}
+
+/// Lists every resource along with its current assignment and the
+/// reverse-geocoded address of its latest location. Resources are still listed,
+/// without a location, if the geocoder is unavailable.
+pub async fn list(
+    pool: &Pool<Sqlite>,
+    geocoder: &dyn Geocoder,
+) -> Result<Vec<Resource>, sqlx::Error> {
+    let locations = sqlx::query_as::<_, ResourceLocation>(&strings::GET_LATEST_RESOURCE_LOCATIONS)
+        .fetch_all(pool)
+        .await?;
 
-    let resp = ureq::post("http://127.0.0.1:8081/api/v0/geocode/reverse/bulk")
-        .send_json(ureq::json!(locations
+    let points = locations
+        .iter()
+        .map(|loc| Point {
+            lat: loc.latitude.clone(),
+            lon: loc.longitude.clone(),
+        })
+        .collect::<Vec<_>>();
+    let geocoded: HashMap<&str, GeocodeResponse> = match geocoder.reverse(&points).await {
+        Ok(resp) => locations
             .iter()
-            .map(|loc| ureq::json!({"lat": loc.latitude, "lon": loc.longitude}))
-            .collect::<Vec<_>>()))
-        .unwrap()
-        .into_json::<Vec<GeocodeResponse>>()
-        .unwrap();
+            .zip(resp)
+            .filter_map(|(loc, gcr)| gcr.map(|gcr| (loc.resource_id.as_str(), gcr)))
+            .collect(),
+        Err(e) => {
+            tracing::warn!("failed to reverse geocode resource locations: {}", e);
+            HashMap::new()
+        }
+    };
 
+    let now = chrono::Utc::now().timestamp();
     let resources = sqlx::query(&strings::GET_RESOURCES)
         .map(|row: SqliteRow| Resource {
-            id: row.get("resource_id"),
-            display_name: row.get("display_name"),
-            in_service: row.get("in_service"),
-            comment: row.get("comment"),
-            current_assignment: match row.get::<Option<String>, _>("aa_id") {
-                Some(_) => Some(Assignment {
-                    id: row.get("aa_id"),
-                    job_id: row.get("job_id"),
-                    resource_id: row.get("resource_id"),
-                    assigned_at: row.get("assigned_at"),
-                    removed_at: row.get("removed_at"),
-                    assigned_by: row.get("assigned_by"),
-                    removed_by: row.get("removed_by"),
-                }),
-                None => None,
-            },
-            location: resp
-                .iter()
-                .find(|gcr| {
-                    let a = locations
-                        .iter()
-                        .find(|l| l.resource_id == row.get::<String, _>("resource_id"));
-                    a.is_some_and(|b| b.latitude == gcr.lat && b.longitude == gcr.lon)
-                })
-                .cloned(),
+            location: geocoded.get(row.get::<&str, _>("resource_id")).cloned(),
+            ..resource_from_row(&row, now)
         })
         .fetch_all(pool)
         .await?;
     Ok(resources)
 }
 
+/// A single resource with its current assignment, but without the geocoded
+/// location `list` includes.
+pub async fn get(pool: &Pool<Sqlite>, id: &str) -> Result<Option<Resource>, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+    let resource = sqlx::query(&strings::GET_RESOURCE_BY_ID)
+        .bind(id)
+        .map(|row: SqliteRow| resource_from_row(&row, now))
+        .fetch_optional(pool)
+        .await?;
+    Ok(resource)
+}
+
+fn resource_from_row(row: &SqliteRow, now: i64) -> Resource {
+    Resource {
+        id: row.get("resource_id"),
+        display_name: row.get("display_name"),
+        in_service: row.get("in_service"),
+        comment: row.get("comment"),
+        status: row.get("status"),
+        status_changed_at: row.get("status_changed_at"),
+        resource_type: row.get("resource_type"),
+        capabilities: row.get("capabilities"),
+        time_in_status: row
+            .get::<Option<i64>, _>("status_changed_at")
+            .map(|changed_at| now - changed_at),
+        current_assignment: match row.get::<Option<String>, _>("aa_id") {
+            Some(_) => Some(Assignment {
+                id: row.get("aa_id"),
+                job_id: row.get("job_id"),
+                resource_id: row.get("resource_id"),
+                assigned_at: row.get("assigned_at"),
+                removed_at: row.get("removed_at"),
+                assigned_by: row.get("assigned_by"),
+                removed_by: row.get("removed_by"),
+                en_route_at: row.get("en_route_at"),
+                on_scene_at: row.get("on_scene_at"),
+                cleared_at: row.get("cleared_at"),
+                acknowledged_at: row.get("acknowledged_at"),
+                acknowledged_by: row.get("acknowledged_by"),
+            }),
+            None => None,
+        },
+        location: None,
+    }
+}
+
+pub async fn list_available_with_location(
+    pool: &Pool<Sqlite>,
+) -> Result<Vec<AvailableResource>, sqlx::Error> {
+    let resources =
+        sqlx::query_as::<_, AvailableResource>(&strings::GET_AVAILABLE_RESOURCES_WITH_LOCATION)
+            .fetch_all(pool)
+            .await?;
+    Ok(resources)
+}
+
+/// Every location fix for a resource between two timestamps, oldest first.
+pub async fn get_track(
+    pool: &Pool<Sqlite>,
+    resource_id: &str,
+    from: i64,
+    to: i64,
+) -> Result<Vec<ResourceLocation>, sqlx::Error> {
+    let track = sqlx::query_as::<_, ResourceLocation>(&strings::GET_RESOURCE_TRACK)
+        .bind(resource_id)
+        .bind(from)
+        .bind(to)
+        .fetch_all(pool)
+        .await?;
+    Ok(track)
+}
+
+/// Thins out a track, dropping fixes that are closer than `min_interval`
+/// seconds or `min_distance` meters to the last fix kept. The first and last
+/// fixes are always kept so the track still starts and ends in the right place.
+pub fn downsample(
+    track: Vec<ResourceLocation>,
+    min_interval: Option<i64>,
+    min_distance: Option<f64>,
+) -> Vec<ResourceLocation> {
+    if track.len() <= 2 || (min_interval.is_none() && min_distance.is_none()) {
+        return track;
+    }
+
+    let last_index = track.len() - 1;
+    let mut kept: Vec<ResourceLocation> = Vec::new();
+    for (i, fix) in track.into_iter().enumerate() {
+        let keep = match kept.last() {
+            None => true,
+            Some(_) if i == last_index => true,
+            Some(prev) => {
+                let too_soon =
+                    min_interval.is_some_and(|interval| fix.at_time - prev.at_time < interval);
+                let too_close = min_distance.is_some_and(|distance| {
+                    match (
+                        prev.latitude.parse::<f64>(),
+                        prev.longitude.parse::<f64>(),
+                        fix.latitude.parse::<f64>(),
+                        fix.longitude.parse::<f64>(),
+                    ) {
+                        (Ok(lat1), Ok(lon1), Ok(lat2), Ok(lon2)) => {
+                            geo::distance_m(lat1, lon1, lat2, lon2) < distance
+                        }
+                        _ => true,
+                    }
+                });
+                !too_soon && !too_close
+            }
+        };
+        if keep {
+            kept.push(fix);
+        }
+    }
+    kept
+}
+
+/// Deletes location fixes older than `before`, except each resource's most
+/// recent fix.
+pub async fn prune_locations(pool: &Pool<Sqlite>, before: i64) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(&strings::PRUNE_RESOURCE_LOCATIONS)
+        .bind(before)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
 
 pub async fn set_location(
     pool: &Pool<Sqlite>,
     resource_id: &str,
     latitude: &str,
     longitude: &str,
-) -> Result<(), sqlx::Error> {
-    sqlx::query("INSERT INTO resource_locations(resource_id,latitude,longitude) VALUES (?, ?, ?)")
+) -> Result<ResourceLocation, sqlx::Error> {
+    let location = sqlx::query_as::<_, ResourceLocation>(&strings::SET_RESOURCE_LOCATION)
         .bind(resource_id)
         .bind(latitude)
         .bind(longitude)
-        .execute(pool)
+        .fetch_one(pool)
         .await?;
 
-    Ok(())
+    Ok(location)
+}
+
+/// Records a location fix taken at a specific time, for trackers that report
+/// when the fix was taken. A later fix in the same second replaces the earlier one.
+pub async fn set_location_at(
+    pool: &Pool<Sqlite>,
+    resource_id: &str,
+    latitude: &str,
+    longitude: &str,
+    at_time: i64,
+) -> Result<ResourceLocation, sqlx::Error> {
+    let location = sqlx::query_as::<_, ResourceLocation>(&strings::SET_RESOURCE_LOCATION_AT)
+        .bind(resource_id)
+        .bind(latitude)
+        .bind(longitude)
+        .bind(at_time)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(location)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::async_trait;
+
+    use super::*;
+    use crate::db::{test_pool, users};
+    use crate::geocoder::MockGeocoder;
+    use crate::permissions::Role;
+
+    struct FailingGeocoder;
+
+    #[async_trait]
+    impl Geocoder for FailingGeocoder {
+        async fn reverse(&self, _points: &[Point]) -> anyhow::Result<Vec<Option<GeocodeResponse>>> {
+            anyhow::bail!("geocoder is down")
+        }
+    }
+
+    #[tokio::test]
+    async fn list_geocodes_latest_locations() {
+        let pool = test_pool().await;
+        let located = create_resource(&pool, "Medic 1", None, None, vec![])
+            .await
+            .unwrap();
+        let unlocated = create_resource(&pool, "Medic 2", None, None, vec![])
+            .await
+            .unwrap();
+        set_location_at(&pool, &located.id, "43.0800", "-77.6700", 100)
+            .await
+            .unwrap();
+        set_location_at(&pool, &located.id, "43.0850", "-77.6750", 200)
+            .await
+            .unwrap();
+
+        let resources = list(&pool, &MockGeocoder).await.unwrap();
+        assert_eq!(resources.len(), 2);
+
+        let located = resources.iter().find(|r| r.id == located.id).unwrap();
+        let location = located.location.as_ref().unwrap();
+        assert_eq!(location.lat, "43.0850");
+        assert_eq!(location.lon, "-77.6750");
+        assert_eq!(
+            location.address.address_label.as_deref(),
+            Some("43.0850, -77.6750")
+        );
+
+        let unlocated = resources.iter().find(|r| r.id == unlocated.id).unwrap();
+        assert!(unlocated.location.is_none());
+    }
+
+    #[tokio::test]
+    async fn list_without_geocoder() {
+        let pool = test_pool().await;
+        let resource = create_resource(&pool, "Engine 1", None, None, vec![])
+            .await
+            .unwrap();
+        set_location(&pool, &resource.id, "43.08", "-77.67")
+            .await
+            .unwrap();
+
+        let resources = list(&pool, &FailingGeocoder).await.unwrap();
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources[0].id, resource.id);
+        assert!(resources[0].location.is_none());
+    }
+
+    fn track() -> Vec<ResourceLocation> {
+        [
+            (0, "43.0800"),
+            (10, "43.0801"),
+            (20, "43.0900"),
+            (30, "43.0901"),
+            (40, "43.0902"),
+        ]
+        .into_iter()
+        .map(|(at_time, latitude)| ResourceLocation {
+            resource_id: "medic-1".to_string(),
+            at_time,
+            latitude: latitude.to_string(),
+            longitude: "-77.6700".to_string(),
+        })
+        .collect()
+    }
+
+    fn times(track: &[ResourceLocation]) -> Vec<i64> {
+        track.iter().map(|fix| fix.at_time).collect()
+    }
+
+    #[test]
+    fn downsample_drops_close_fixes() {
+        // 0.0001 degrees of latitude is about 11 metres
+        assert_eq!(times(&downsample(track(), None, Some(50.0))), [0, 20, 40]);
+        assert_eq!(times(&downsample(track(), Some(15), None)), [0, 20, 40]);
+        assert_eq!(times(&downsample(track(), Some(60), None)), [0, 40]);
+        assert_eq!(times(&downsample(track(), None, None)), [0, 10, 20, 30, 40]);
+    }
+
+    #[tokio::test]
+    async fn users_are_bound_to_units() {
+        let pool = test_pool().await;
+        let admin = users::create_user(
+            &pool,
+            "admin@example.com",
+            "password",
+            "Admin",
+            None,
+            Role::Admin,
+            None,
+        )
+        .await
+        .unwrap();
+        let crew = users::create_user(
+            &pool,
+            "medic@example.com",
+            "password",
+            "Medic",
+            None,
+            Role::FieldUnit,
+            Some(&admin.id),
+        )
+        .await
+        .unwrap();
+        let resource = create_resource(&pool, "Medic 1", None, None, vec![])
+            .await
+            .unwrap();
+        assert!(!is_bound_to_user(&pool, &resource.id, &crew.id)
+            .await
+            .unwrap());
+
+        bind_user(&pool, &resource.id, &crew.id, &admin.id)
+            .await
+            .unwrap();
+        assert!(is_bound_to_user(&pool, &resource.id, &crew.id)
+            .await
+            .unwrap());
+        assert_eq!(
+            bound_to_user(&pool, &crew.id).await.unwrap(),
+            vec![resource.id.clone()]
+        );
+        let crew_list = list_bindings(&pool, Some(&resource.id), None)
+            .await
+            .unwrap();
+        assert_eq!(crew_list.len(), 1);
+        assert_eq!(crew_list[0].user_id, crew.id);
+        assert_eq!(crew_list[0].created_by.as_deref(), Some(admin.id.as_str()));
+
+        match bind_user(&pool, &resource.id, &crew.id, &admin.id).await {
+            Err(sqlx::Error::Database(e)) => assert!(e.is_unique_violation()),
+            other => panic!("bound twice: {:?}", other),
+        }
+        match bind_user(&pool, &resource.id, "0", &admin.id).await {
+            Err(sqlx::Error::Database(e)) => assert!(e.is_foreign_key_violation()),
+            other => panic!("bound a missing user: {:?}", other),
+        }
+
+        let binding = unbind_user(&pool, &resource.id, &crew.id, &admin.id)
+            .await
+            .unwrap();
+        assert!(binding.removed_at.is_some());
+        assert!(!is_bound_to_user(&pool, &resource.id, &crew.id)
+            .await
+            .unwrap());
+        assert!(matches!(
+            unbind_user(&pool, &resource.id, &crew.id, &admin.id).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        // Binding again after being taken off is fine
+        bind_user(&pool, &resource.id, &crew.id, &admin.id)
+            .await
+            .unwrap();
+    }
 }
@@ -0,0 +1,194 @@
+use std::env;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use snowflake::SnowflakeGenerator;
+use sqlx::{FromRow, Pool, Sqlite};
+
+use super::{audit, strings, tokens};
+
+const TOKEN_PREFIX: &str = "irt_";
+
+lazy_static! {
+    /// How long a session lasts without being refreshed
+    pub static ref SESSION_TTL_DAYS: i64 = env::var("SESSION_TTL_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(30);
+}
+
+/// A signed-in device. Access tokens are short-lived and tied to a session;
+/// the refresh token is rotated every time it's used.
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub created_at: i64,
+    pub last_seen_at: i64,
+    pub expires_at: i64,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub revoked_at: Option<i64>,
+    pub revoked_by: Option<String>,
+    /// Whether this is the session making the request
+    #[sqlx(skip)]
+    pub current: bool,
+}
+
+fn expires_at() -> i64 {
+    (chrono::Utc::now() + chrono::Duration::days(*SESSION_TTL_DAYS)).timestamp()
+}
+
+/// Starts a session, returning it along with its refresh token. The token is
+/// only stored hashed, so this is the only time it's available.
+pub async fn create(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+    user_agent: Option<&str>,
+    ip_address: Option<&str>,
+) -> Result<(Session, String), sqlx::Error> {
+    let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
+    let token = tokens::generate(TOKEN_PREFIX);
+
+    let session = sqlx::query_as::<_, Session>(&strings::CREATE_SESSION)
+        .bind(&id)
+        .bind(user_id)
+        .bind(tokens::hash(&token))
+        .bind(expires_at())
+        .bind(user_agent)
+        .bind(ip_address)
+        .fetch_one(pool)
+        .await?;
+    Ok((session, token))
+}
+
+/// Exchanges a refresh token for a new one, extending the session.
+///
+/// A refresh token that has already been exchanged means it was copied, so
+/// the session it belonged to is revoked rather than refreshed.
+pub async fn refresh(
+    pool: &Pool<Sqlite>,
+    refresh_token: &str,
+    user_agent: Option<&str>,
+    ip_address: Option<&str>,
+) -> Result<Option<(Session, String)>, sqlx::Error> {
+    let token = tokens::generate(TOKEN_PREFIX);
+    let presented = tokens::hash(refresh_token);
+
+    let session = sqlx::query_as::<_, Session>(&strings::ROTATE_SESSION)
+        .bind(tokens::hash(&token))
+        .bind(expires_at())
+        .bind(user_agent)
+        .bind(ip_address)
+        .bind(&presented)
+        .fetch_optional(pool)
+        .await?;
+    if let Some(session) = session {
+        return Ok(Some((session, token)));
+    }
+
+    let reused = sqlx::query_scalar::<_, String>(&strings::REVOKE_SESSION_BY_PREVIOUS_TOKEN)
+        .bind(&presented)
+        .fetch_optional(pool)
+        .await?;
+    if let Some(session_id) = reused {
+        tracing::warn!(
+            session = session_id,
+            "refresh token reused, revoking session"
+        );
+    }
+    Ok(None)
+}
+
+/// Whether a session is still live, marking it as seen. Touches are limited
+/// to once a minute so every request doesn't turn into a write.
+pub async fn is_active(pool: &Pool<Sqlite>, id: &str, user_id: &str) -> Result<bool, sqlx::Error> {
+    let session = sqlx::query_as::<_, Session>(&strings::GET_ACTIVE_SESSION)
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    let Some(session) = session else {
+        return Ok(false);
+    };
+
+    if chrono::Utc::now().timestamp() - session.last_seen_at >= 60 {
+        sqlx::query(&strings::TOUCH_SESSION)
+            .bind(id)
+            .execute(pool)
+            .await?;
+    }
+    Ok(true)
+}
+
+pub async fn list_for_user(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+) -> Result<Vec<Session>, sqlx::Error> {
+    let sessions = sqlx::query_as::<_, Session>(&strings::GET_SESSIONS_FOR_USER)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+    Ok(sessions)
+}
+
+pub async fn revoke(
+    pool: &Pool<Sqlite>,
+    id: &str,
+    user_id: &str,
+    revoked_by: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(&strings::REVOKE_SESSION)
+        .bind(revoked_by)
+        .bind(id)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(())
+}
+
+/// Logs a user out everywhere, optionally keeping the session making the
+/// request. Takes any executor so it can run alongside other account changes,
+/// like disabling the user.
+pub async fn revoke_all<'e, E>(
+    executor: E,
+    user_id: &str,
+    revoked_by: &str,
+    except: Option<&str>,
+) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'e, Database = Sqlite>,
+{
+    let result = sqlx::query(&strings::REVOKE_SESSIONS_FOR_USER)
+        .bind(revoked_by)
+        .bind(user_id)
+        .bind(except)
+        .execute(executor)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Logs a user out everywhere and records who did it.
+pub async fn revoke_all_audited(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+    revoked_by: &str,
+) -> Result<u64, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let revoked = revoke_all(&mut *transaction, user_id, revoked_by, None).await?;
+    audit::record(
+        &mut *transaction,
+        Some(revoked_by),
+        "user.sessions_revoked",
+        "user",
+        Some(user_id),
+        Some(json!({ "count": revoked })),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(revoked)
+}
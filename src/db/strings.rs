@@ -2,19 +2,54 @@ use lazy_static::lazy_static;
 
 lazy_static! {
     pub(crate) static ref GET_USER_BY_EMAIL: &'static str = r"
-        SELECT id,email,display_name,phone,password,created_at,admin,enabled
+        SELECT id,email,display_name,phone,password,created_at,admin,enabled,role,mfa_enabled
             FROM users
             WHERE email = ?1
     ";
     pub(crate) static ref GET_USER_BY_ID: &'static str = r"SELECT * FROM users WHERE id = ?1";
     pub(crate) static ref CREATE_USER: &'static str = r"
-        INSERT INTO users(id,email,password,display_name) VALUES (?, ?, ?, ?) RETURNING *
+        INSERT INTO users(id,email,password,display_name,phone,role,admin,dispatcher) VALUES (?, ?, ?, ?, ?, ?, ?, ?) RETURNING *
     ";
+    pub(crate) static ref GET_ALL_USERS: &'static str =
+        r"SELECT * FROM users ORDER BY display_name";
+    pub(crate) static ref UPDATE_USER: &'static str = r"UPDATE users
+            SET email = COALESCE(?, email), display_name = COALESCE(?, display_name), phone = COALESCE(?, phone), role = COALESCE(?, role), admin = COALESCE(?, admin), dispatcher = COALESCE(?, dispatcher)
+            WHERE id = ?
+            RETURNING *";
+    pub(crate) static ref SET_USER_ENABLED: &'static str =
+        r"UPDATE users SET enabled = ? WHERE id = ? RETURNING *";
+    pub(crate) static ref SET_USER_PASSWORD: &'static str =
+        r"UPDATE users SET password = ? WHERE id = ? RETURNING *";
+    pub(crate) static ref ADD_AUDIT_ENTRY: &'static str = r"INSERT INTO audit_log(id,actor_id,action,target_type,target_id,details) VALUES (?, ?, ?, ?, ?, ?)";
+    pub(crate) static ref GET_AUDIT_ENTRIES: &'static str = r"
+        SELECT * FROM audit_log
+            WHERE (?1 IS NULL OR target_type = ?1) AND (?2 IS NULL OR target_id = ?2)
+            ORDER BY at_time DESC, id DESC
+            LIMIT 500";
     pub(crate) static ref GET_ALL_JOBS: &'static str = r"SELECT * FROM jobs";
-    pub(crate) static ref CREATE_JOB: &'static str = r"INSERT INTO jobs(id,synopsis,location,caller_name,caller_phone,created_by) VALUES (?, ?, ?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref CREATE_JOB: &'static str = r"INSERT INTO jobs(id,synopsis,location,caller_name,caller_phone,created_by,nature_code,priority,latitude,longitude,address,cross_street,location_notes,geocode_confidence,location_overridden) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref UPDATE_JOB_LOCATION: &'static str = r"UPDATE jobs
+            SET latitude = ?, longitude = ?, address = NULL, geocode_confidence = NULL, cross_street = COALESCE(?, cross_street), location_notes = COALESCE(?, location_notes), location_overridden = true
+            WHERE id = ?
+            RETURNING *";
+    pub(crate) static ref SET_JOB_GEOCODE: &'static str = r"UPDATE jobs
+            SET latitude = ?, longitude = ?, address = ?, geocode_confidence = ?
+            WHERE id = ? AND NOT location_overridden
+            RETURNING *";
+    pub(crate) static ref GET_JOB_PRIORITY: &'static str =
+        r"SELECT priority FROM jobs WHERE id = ?";
+    pub(crate) static ref UPDATE_JOB_PRIORITY: &'static str =
+        r"UPDATE jobs SET priority = ? WHERE id = ?";
     pub(crate) static ref GET_JOB_BY_ID: &'static str = r"SELECT * FROM jobs WHERE id = ?";
-    pub(crate) static ref CLOSE_JOB: &'static str =
-        r"UPDATE jobs SET closed_at = (strftime('%s','now')), closed_by = ? WHERE id = ?";
+    pub(crate) static ref GET_JOB_STATUS: &'static str = r"SELECT status FROM jobs WHERE id = ?";
+    pub(crate) static ref UPDATE_JOB_STATUS: &'static str =
+        r"UPDATE jobs SET status = ? WHERE id = ?";
+    pub(crate) static ref UPDATE_JOB_STATUS_TERMINAL: &'static str = r"UPDATE jobs
+            SET status = ?, closed_at = (strftime('%s','now')), closed_by = ?
+            WHERE id = ?";
+    pub(crate) static ref ADD_JOB_STATUS_CHANGE: &'static str = r"INSERT INTO job_status_history(id,job_id,from_status,to_status,changed_by) VALUES (?, ?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref GET_STATUS_HISTORY_FOR_JOB: &'static str =
+        r"SELECT * FROM job_status_history WHERE job_id = ? ORDER BY changed_at, id";
     pub(crate) static ref GET_COMMENTS_FOR_JOB: &'static str =
         r"SELECT * FROM comments WHERE job_id = ?";
     pub(crate) static ref ADD_COMMENT: &'static str =
@@ -22,24 +57,297 @@ lazy_static! {
     pub(crate) static ref GET_ACTIVE_ASSIGNMENTS: &'static str = r"SELECT * FROM assignments WHERE removed_at IS NULL AND job_id IN (SELECT id FROM jobs WHERE closed_at IS NULL);";
     pub(crate) static ref GET_ASSIGNMENTS_BY_JOBID: &'static str =
         r"SELECT * FROM assignments WHERE job_id = ?";
+    pub(crate) static ref GET_ACTIVE_ASSIGNMENT_FOR_RESOURCE: &'static str = r"SELECT * FROM assignments WHERE resource_id = ? AND removed_at IS NULL AND job_id IN (SELECT id FROM jobs WHERE closed_at IS NULL)";
     pub(crate) static ref CREATE_ASSIGNMENT: &'static str = r"INSERT INTO assignments(id,job_id,resource_id,assigned_by) VALUES (?, ?, ?, ?) RETURNING *";
-    pub(crate) static ref REMOVE_ASSIGNMENT: &'static str =
-        r"UPDATE assignments SET removed_at = (strftime('%s','now')), removed_by = ? WHERE id = ?";
+    pub(crate) static ref REMOVE_ASSIGNMENT: &'static str = r"UPDATE assignments SET removed_at = (strftime('%s','now')), removed_by = ? WHERE id = ? RETURNING *";
+    pub(crate) static ref GET_ASSIGNMENT_BY_ID: &'static str =
+        r"SELECT * FROM assignments WHERE id = ?";
+    pub(crate) static ref ACKNOWLEDGE_ASSIGNMENT: &'static str = r"UPDATE assignments
+            SET acknowledged_at = (strftime('%s','now')), acknowledged_by = ?
+            WHERE id = ? AND removed_at IS NULL AND acknowledged_at IS NULL
+            RETURNING *";
     pub(crate) static ref CLOSE_ASSIGNMENTS_FOR_JOB: &'static str = r"UPDATE assignments SET removed_at = (strftime('%s','now')), removed_by = ? WHERE job_id = ? AND removed_at IS NULL";
-    pub(crate) static ref CREATE_RESOURCE: &'static str =
-        r"INSERT INTO resources(id,display_name,comment) VALUES (?, ?, ?) RETURNING *";
-    pub(crate) static ref UPDATE_RESOURCE_IN_SERVICE: &'static str =
-        r"UPDATE resources SET in_service = ? WHERE id = ?";
+    pub(crate) static ref CREATE_RESOURCE: &'static str = r"INSERT INTO resources(id,display_name,comment,resource_type,capabilities) VALUES (?, ?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref GET_RESOURCE_USER_BINDING: &'static str = r"SELECT EXISTS(SELECT 1 FROM resource_user_bindings WHERE resource_id = ? AND user_id = ? AND removed_at IS NULL)";
+    pub(crate) static ref GET_RESOURCES_BOUND_TO_USER: &'static str =
+        r"SELECT resource_id FROM resource_user_bindings WHERE user_id = ? AND removed_at IS NULL";
+    pub(crate) static ref GET_RESOURCE_USER_BINDINGS: &'static str = r"
+        SELECT * FROM resource_user_bindings
+            WHERE removed_at IS NULL AND (?1 IS NULL OR resource_id = ?1) AND (?2 IS NULL OR user_id = ?2)
+            ORDER BY created_at";
+    pub(crate) static ref BIND_USER_TO_RESOURCE: &'static str = r"INSERT INTO resource_user_bindings(id,resource_id,user_id,created_by) VALUES (?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref UNBIND_USER_FROM_RESOURCE: &'static str = r"UPDATE resource_user_bindings
+            SET removed_at = (strftime('%s','now')), removed_by = ?
+            WHERE resource_id = ? AND user_id = ? AND removed_at IS NULL
+            RETURNING *";
+    pub(crate) static ref GET_RESOURCE_STATUS: &'static str =
+        r"SELECT status FROM resources WHERE id = ?";
+    pub(crate) static ref UPDATE_RESOURCE_STATUS: &'static str = r"UPDATE resources
+            SET status = ?, in_service = ?, status_changed_at = (strftime('%s','now'))
+            WHERE id = ?
+            RETURNING id";
+    pub(crate) static ref ADD_RESOURCE_STATUS_CHANGE: &'static str = r"INSERT INTO resource_status_history(id,resource_id,status,note,changed_by) VALUES (?, ?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref GET_STATUS_HISTORY_FOR_RESOURCE: &'static str = r"SELECT * FROM resource_status_history WHERE resource_id = ? ORDER BY changed_at DESC, id DESC";
+    pub(crate) static ref UPDATE_ASSIGNMENT_EN_ROUTE: &'static str = r"UPDATE assignments
+            SET en_route_at = COALESCE(en_route_at, strftime('%s','now'))
+            WHERE resource_id = ? AND removed_at IS NULL";
+    pub(crate) static ref UPDATE_ASSIGNMENT_ON_SCENE: &'static str = r"UPDATE assignments
+            SET on_scene_at = COALESCE(on_scene_at, strftime('%s','now'))
+            WHERE resource_id = ? AND removed_at IS NULL";
+    pub(crate) static ref UPDATE_ASSIGNMENT_CLEARED: &'static str = r"UPDATE assignments
+            SET cleared_at = (strftime('%s','now')), removed_at = (strftime('%s','now')), removed_by = ?
+            WHERE resource_id = ? AND removed_at IS NULL";
     pub(crate) static ref UPDATE_ASSIGNMENTS_RESOURCE_OOS: &'static str = r"UPDATE assignments
             SET removed_at = (strftime('%s','now')), removed_by = ?
             WHERE resource_id = ? AND removed_at IS NULL";
+    pub(crate) static ref GET_NATURE_CODES: &'static str =
+        r"SELECT * FROM nature_codes ORDER BY code";
+    pub(crate) static ref GET_NATURE_CODE: &'static str =
+        r"SELECT * FROM nature_codes WHERE code = ?";
+    pub(crate) static ref CREATE_NATURE_CODE: &'static str = r"INSERT INTO nature_codes(code,description,default_priority,recommended_resource_types,response_plan) VALUES (?, ?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref UPDATE_NATURE_CODE: &'static str = r"UPDATE nature_codes
+            SET description = ?, default_priority = ?, recommended_resource_types = ?, response_plan = ?, enabled = ?, updated_at = (strftime('%s','now'))
+            WHERE code = ?
+            RETURNING *";
+    pub(crate) static ref DISABLE_NATURE_CODE: &'static str = r"UPDATE nature_codes
+            SET enabled = false, updated_at = (strftime('%s','now'))
+            WHERE code = ?
+            RETURNING *";
     pub(crate) static ref GET_RESOURCES: &'static str = r"
         WITH aa AS (
-            SELECT id,job_id,resource_id,assigned_at,removed_at,assigned_by,removed_by
+            SELECT id,job_id,resource_id,assigned_at,removed_at,assigned_by,removed_by,en_route_at,on_scene_at,cleared_at,acknowledged_at,acknowledged_by
                 FROM assignments
                 WHERE removed_at IS NULL
                     AND job_id IN (SELECT id FROM jobs WHERE closed_at IS NULL))
-        SELECT resources.id as resource_id,resources.display_name,resources.in_service,resources.comment,aa.id as aa_id,aa.job_id,aa.assigned_at,aa.assigned_by,aa.removed_at,aa.removed_by FROM resources
+        SELECT resources.id as resource_id,resources.display_name,resources.in_service,resources.comment,resources.status,resources.status_changed_at,resources.resource_type,resources.capabilities,aa.id as aa_id,aa.job_id,aa.assigned_at,aa.assigned_by,aa.removed_at,aa.removed_by,aa.en_route_at,aa.on_scene_at,aa.cleared_at,aa.acknowledged_at,aa.acknowledged_by FROM resources
             LEFT OUTER JOIN aa
                 ON resources.id = aa.resource_id;";
+    pub(crate) static ref GET_RESOURCE_BY_ID: &'static str = r"
+        WITH aa AS (
+            SELECT id,job_id,resource_id,assigned_at,removed_at,assigned_by,removed_by,en_route_at,on_scene_at,cleared_at,acknowledged_at,acknowledged_by
+                FROM assignments
+                WHERE removed_at IS NULL
+                    AND job_id IN (SELECT id FROM jobs WHERE closed_at IS NULL))
+        SELECT resources.id as resource_id,resources.display_name,resources.in_service,resources.comment,resources.status,resources.status_changed_at,resources.resource_type,resources.capabilities,aa.id as aa_id,aa.job_id,aa.assigned_at,aa.assigned_by,aa.removed_at,aa.removed_by,aa.en_route_at,aa.on_scene_at,aa.cleared_at,aa.acknowledged_at,aa.acknowledged_by FROM resources
+            LEFT OUTER JOIN aa
+                ON resources.id = aa.resource_id
+            WHERE resources.id = ?;";
+}
+
+lazy_static! {
+    pub(crate) static ref GET_LATEST_RESOURCE_LOCATIONS: &'static str =
+        r"SELECT * FROM resource_locations GROUP BY resource_id HAVING at_time = MAX(at_time)";
+    pub(crate) static ref GET_GEOCODE_CACHE_ENTRY: &'static str =
+        r"SELECT response FROM geocode_cache WHERE lat_key = ? AND lon_key = ? AND created_at >= ?";
+    pub(crate) static ref PUT_GEOCODE_CACHE_ENTRY: &'static str =
+        r"INSERT OR REPLACE INTO geocode_cache(lat_key,lon_key,response) VALUES (?, ?, ?)";
+    pub(crate) static ref PRUNE_GEOCODE_CACHE: &'static str =
+        r"DELETE FROM geocode_cache WHERE created_at < ?";
+    pub(crate) static ref GET_RESOURCE_TRACK: &'static str = r"SELECT * FROM resource_locations WHERE resource_id = ? AND at_time >= ? AND at_time <= ? ORDER BY at_time";
+    pub(crate) static ref PRUNE_RESOURCE_LOCATIONS: &'static str = r"
+        DELETE FROM resource_locations
+            WHERE at_time < ?
+                AND (resource_id, at_time) NOT IN (
+                    SELECT resource_id, MAX(at_time) FROM resource_locations GROUP BY resource_id)";
+    pub(crate) static ref SET_RESOURCE_LOCATION: &'static str = r"INSERT INTO resource_locations(resource_id,latitude,longitude) VALUES (?, ?, ?) RETURNING *";
+    pub(crate) static ref SET_RESOURCE_LOCATION_AT: &'static str = r"INSERT OR REPLACE INTO resource_locations(resource_id,latitude,longitude,at_time) VALUES (?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref GET_AVAILABLE_RESOURCES_WITH_LOCATION: &'static str = r"
+        WITH loc AS (
+            SELECT resource_id,MAX(at_time) AS at_time,latitude,longitude
+                FROM resource_locations
+                GROUP BY resource_id)
+        SELECT resources.id,resources.display_name,resources.resource_type,resources.capabilities,loc.at_time,loc.latitude,loc.longitude FROM resources
+            INNER JOIN loc
+                ON resources.id = loc.resource_id
+            WHERE resources.status = 'available'
+                AND resources.id NOT IN (
+                    SELECT resource_id FROM assignments
+                        WHERE removed_at IS NULL
+                            AND job_id IN (SELECT id FROM jobs WHERE closed_at IS NULL));";
+    pub(crate) static ref GET_DEVICES: &'static str =
+        r"SELECT * FROM devices WHERE ?1 IS NULL OR resource_id = ?1 ORDER BY created_at";
+    pub(crate) static ref CREATE_DEVICE: &'static str = r"INSERT INTO devices(id,resource_id,name,token_hash,created_by,external_id) VALUES (?, ?, ?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref ROTATE_DEVICE_TOKEN: &'static str =
+        r"UPDATE devices SET token_hash = ? WHERE id = ? AND revoked_at IS NULL RETURNING *";
+    pub(crate) static ref REVOKE_DEVICE: &'static str = r"UPDATE devices
+            SET revoked_at = (strftime('%s','now')), revoked_by = ?
+            WHERE id = ? AND revoked_at IS NULL
+            RETURNING id";
+    pub(crate) static ref TOUCH_DEVICE_BY_TOKEN_HASH: &'static str = r"UPDATE devices
+            SET last_seen_at = (strftime('%s','now'))
+            WHERE token_hash = ? AND revoked_at IS NULL
+            RETURNING *";
+    pub(crate) static ref TOUCH_DEVICE_BY_EXTERNAL_ID: &'static str = r"UPDATE devices
+            SET last_seen_at = (strftime('%s','now'))
+            WHERE external_id = ? AND revoked_at IS NULL
+            RETURNING *";
+    pub(crate) static ref GET_DEVICE_EXTERNAL_IDS: &'static str =
+        r"SELECT external_id FROM devices WHERE external_id IS NOT NULL AND revoked_at IS NULL";
+    pub(crate) static ref ADD_REJECTED_LOCATION_UPDATE: &'static str = r"INSERT INTO rejected_location_updates(id,device_id,resource_id,remote_addr,reason) VALUES (?, ?, ?, ?, ?)";
+    pub(crate) static ref REPEAT_REJECTED_LOCATION_UPDATE: &'static str = r"
+        UPDATE rejected_location_updates
+            SET count = count + 1, last_at = (strftime('%s','now'))
+            WHERE device_id IS ? AND remote_addr IS ? AND reason = ? AND at_time >= ?";
+    pub(crate) static ref GET_REJECTED_LOCATION_UPDATES: &'static str =
+        r"SELECT * FROM rejected_location_updates ORDER BY last_at DESC LIMIT 500";
+    pub(crate) static ref PRUNE_REJECTED_LOCATION_UPDATES: &'static str =
+        r"DELETE FROM rejected_location_updates WHERE last_at < ?";
+}
+
+lazy_static! {
+    pub(crate) static ref CREATE_SESSION: &'static str = r"INSERT INTO sessions(id,user_id,refresh_token_hash,expires_at,user_agent,ip_address) VALUES (?, ?, ?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref ROTATE_SESSION: &'static str = r"UPDATE sessions
+            SET previous_token_hash = refresh_token_hash, refresh_token_hash = ?, expires_at = ?,
+                last_seen_at = (strftime('%s','now')), user_agent = COALESCE(?, user_agent), ip_address = COALESCE(?, ip_address)
+            WHERE refresh_token_hash = ? AND revoked_at IS NULL AND expires_at > (strftime('%s','now'))
+            RETURNING *";
+    pub(crate) static ref REVOKE_SESSION_BY_PREVIOUS_TOKEN: &'static str = r"UPDATE sessions
+            SET revoked_at = (strftime('%s','now'))
+            WHERE previous_token_hash = ? AND revoked_at IS NULL
+            RETURNING id";
+    pub(crate) static ref GET_ACTIVE_SESSION: &'static str = r"SELECT * FROM sessions
+            WHERE id = ? AND user_id = ? AND revoked_at IS NULL AND expires_at > (strftime('%s','now'))";
+    pub(crate) static ref TOUCH_SESSION: &'static str =
+        r"UPDATE sessions SET last_seen_at = (strftime('%s','now')) WHERE id = ?";
+    pub(crate) static ref GET_SESSIONS_FOR_USER: &'static str = r"SELECT * FROM sessions
+            WHERE user_id = ? AND revoked_at IS NULL AND expires_at > (strftime('%s','now'))
+            ORDER BY last_seen_at DESC";
+    pub(crate) static ref REVOKE_SESSION: &'static str = r"UPDATE sessions
+            SET revoked_at = (strftime('%s','now')), revoked_by = ?
+            WHERE id = ? AND user_id = ? AND revoked_at IS NULL
+            RETURNING id";
+    pub(crate) static ref REVOKE_SESSIONS_FOR_USER: &'static str = r"UPDATE sessions
+            SET revoked_at = (strftime('%s','now')), revoked_by = ?1
+            WHERE user_id = ?2 AND revoked_at IS NULL AND (?3 IS NULL OR id != ?3)";
+    pub(crate) static ref CREATE_PASSWORD_RESET: &'static str = r"INSERT INTO password_resets(id,user_id,token_hash,expires_at,created_by) VALUES (?, ?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref CANCEL_PASSWORD_RESETS_FOR_USER: &'static str = r"UPDATE password_resets
+            SET used_at = (strftime('%s','now'))
+            WHERE user_id = ? AND used_at IS NULL";
+    pub(crate) static ref CONSUME_PASSWORD_RESET: &'static str = r"UPDATE password_resets
+            SET used_at = (strftime('%s','now'))
+            WHERE token_hash = ? AND used_at IS NULL AND expires_at > (strftime('%s','now'))
+            RETURNING *";
+    pub(crate) static ref BEGIN_MFA_ENROLLMENT: &'static str =
+        r"UPDATE users SET mfa_secret = ? WHERE id = ? AND NOT mfa_enabled RETURNING id";
+    pub(crate) static ref GET_MFA_SECRET: &'static str =
+        r"SELECT mfa_secret FROM users WHERE id = ?";
+    pub(crate) static ref ENABLE_MFA: &'static str = r"UPDATE users
+            SET mfa_enabled = true, mfa_last_step = ?
+            WHERE id = ? AND NOT mfa_enabled AND mfa_secret IS NOT NULL
+            RETURNING id";
+    pub(crate) static ref DISABLE_MFA: &'static str = r"UPDATE users
+            SET mfa_enabled = false, mfa_secret = NULL, mfa_last_step = NULL
+            WHERE id = ?
+            RETURNING id";
+    pub(crate) static ref RECORD_MFA_STEP: &'static str = r"UPDATE users
+            SET mfa_last_step = ?1
+            WHERE id = ?2 AND (mfa_last_step IS NULL OR mfa_last_step < ?1)";
+    pub(crate) static ref DELETE_MFA_RECOVERY_CODES: &'static str =
+        r"DELETE FROM mfa_recovery_codes WHERE user_id = ?";
+    pub(crate) static ref ADD_MFA_RECOVERY_CODE: &'static str =
+        r"INSERT INTO mfa_recovery_codes(id,user_id,code_hash) VALUES (?, ?, ?)";
+    pub(crate) static ref USE_MFA_RECOVERY_CODE: &'static str = r"UPDATE mfa_recovery_codes
+            SET used_at = (strftime('%s','now'))
+            WHERE id = (SELECT id FROM mfa_recovery_codes WHERE user_id = ? AND code_hash = ? AND used_at IS NULL LIMIT 1)
+            RETURNING id";
+    pub(crate) static ref CREATE_MFA_CHALLENGE: &'static str = r"INSERT INTO mfa_challenges(id,user_id,token_hash,expires_at) VALUES (?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref ATTEMPT_MFA_CHALLENGE: &'static str = r"UPDATE mfa_challenges
+            SET attempts = attempts + 1
+            WHERE token_hash = ? AND used_at IS NULL AND expires_at > (strftime('%s','now')) AND attempts < ?
+            RETURNING *";
+    pub(crate) static ref COMPLETE_MFA_CHALLENGE: &'static str = r"UPDATE mfa_challenges SET used_at = (strftime('%s','now')) WHERE id = ? AND used_at IS NULL";
+    pub(crate) static ref GET_MFA_REQUIRED_ROLES: &'static str =
+        r"SELECT role FROM mfa_required_roles ORDER BY role";
+    pub(crate) static ref IS_MFA_REQUIRED_FOR_ROLE: &'static str =
+        r"SELECT EXISTS(SELECT 1 FROM mfa_required_roles WHERE role = ?)";
+    pub(crate) static ref REQUIRE_MFA_FOR_ROLE: &'static str =
+        r"INSERT OR IGNORE INTO mfa_required_roles(role,set_by) VALUES (?, ?)";
+    pub(crate) static ref UNREQUIRE_MFA_FOR_ROLE: &'static str =
+        r"DELETE FROM mfa_required_roles WHERE role = ?";
+    pub(crate) static ref GET_LOGIN_THROTTLE: &'static str =
+        r"SELECT * FROM login_throttles WHERE key = ?";
+    pub(crate) static ref RECORD_LOGIN_FAILURE: &'static str = r"
+        INSERT INTO login_throttles(key,failures,last_failure_at) VALUES (?1, 1, (strftime('%s','now')))
+            ON CONFLICT(key) DO UPDATE
+            SET failures = CASE WHEN last_failure_at < ?2 THEN 1 ELSE failures + 1 END,
+                last_failure_at = (strftime('%s','now'))
+            RETURNING *";
+    pub(crate) static ref LOCK_LOGIN_THROTTLE: &'static str =
+        r"UPDATE login_throttles SET locked_until = ? WHERE key = ? RETURNING *";
+    pub(crate) static ref CLEAR_LOGIN_THROTTLE: &'static str =
+        r"DELETE FROM login_throttles WHERE key = ?";
+    pub(crate) static ref GET_LOGIN_THROTTLES: &'static str =
+        r"SELECT * FROM login_throttles WHERE last_failure_at > ? ORDER BY last_failure_at DESC";
+    pub(crate) static ref CREATE_OIDC_LOGIN: &'static str =
+        r"INSERT INTO oidc_logins(state,nonce,code_verifier) VALUES (?, ?, ?)";
+    pub(crate) static ref TAKE_OIDC_LOGIN: &'static str = r"DELETE FROM oidc_logins
+            WHERE state = ? AND created_at > ?
+            RETURNING *";
+    pub(crate) static ref PRUNE_OIDC_LOGINS: &'static str =
+        r"DELETE FROM oidc_logins WHERE created_at <= ?";
+    pub(crate) static ref GET_USER_BY_OIDC_SUBJECT: &'static str =
+        r"SELECT * FROM users WHERE oidc_subject = ?";
+    pub(crate) static ref LINK_OIDC_SUBJECT: &'static str =
+        r"UPDATE users SET oidc_subject = ? WHERE id = ? AND oidc_subject IS NULL RETURNING *";
+    pub(crate) static ref GET_API_KEYS: &'static str =
+        r"SELECT * FROM api_keys ORDER BY created_at DESC";
+    pub(crate) static ref CREATE_API_KEY: &'static str = r"INSERT INTO api_keys(id,name,user_id,key_hash,scopes,expires_at) VALUES (?, ?, ?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref REVOKE_API_KEY: &'static str = r"UPDATE api_keys
+            SET revoked_at = (strftime('%s','now')), revoked_by = ?
+            WHERE id = ? AND revoked_at IS NULL
+            RETURNING id";
+    pub(crate) static ref TOUCH_API_KEY_BY_HASH: &'static str = r"UPDATE api_keys
+            SET last_used_at = (strftime('%s','now'))
+            WHERE key_hash = ? AND revoked_at IS NULL
+                AND (expires_at IS NULL OR expires_at > (strftime('%s','now')))
+            RETURNING *";
+    pub(crate) static ref RECORD_EVENT: &'static str = r"INSERT INTO events(kind,entity_type,entity_id,payload) VALUES (?, ?, ?, ?)
+            RETURNING id,at_time,kind,entity_id,payload";
+    pub(crate) static ref GET_EVENTS_SINCE: &'static str =
+        r"SELECT id,at_time,kind,entity_id,payload FROM events WHERE id > ? ORDER BY id LIMIT ?";
+    pub(crate) static ref GET_OLDEST_EVENT_ID: &'static str = r"SELECT MIN(id) FROM events";
+    pub(crate) static ref PRUNE_EVENTS: &'static str = r"DELETE FROM events WHERE at_time < ?";
+    pub(crate) static ref GET_WEBHOOKS: &'static str =
+        r"SELECT * FROM webhooks ORDER BY created_at";
+    pub(crate) static ref GET_ENABLED_WEBHOOKS: &'static str =
+        r"SELECT * FROM webhooks WHERE enabled = 1";
+    pub(crate) static ref CREATE_WEBHOOK: &'static str =
+        r"INSERT INTO webhooks(id,url,events,secret,created_by) VALUES (?, ?, ?, ?, ?) RETURNING *";
+    pub(crate) static ref UPDATE_WEBHOOK: &'static str = r"UPDATE webhooks
+            SET url = COALESCE(?, url), events = COALESCE(?, events), enabled = COALESCE(?, enabled)
+            WHERE id = ?
+            RETURNING *";
+    pub(crate) static ref DELETE_WEBHOOK: &'static str =
+        r"DELETE FROM webhooks WHERE id = ? RETURNING id";
+    pub(crate) static ref ADD_WEBHOOK_DELIVERY: &'static str = r"INSERT INTO webhook_deliveries(id,webhook_id,event_id,event,payload) VALUES (?, ?, ?, ?, ?)";
+    pub(crate) static ref GET_DUE_WEBHOOK_DELIVERIES: &'static str = r"
+        SELECT id, webhook_id, url, secret, event, payload, attempts FROM (
+            SELECT webhook_deliveries.id, webhook_deliveries.webhook_id, webhooks.url, webhooks.secret, webhook_deliveries.event, webhook_deliveries.payload, webhook_deliveries.attempts, webhook_deliveries.next_attempt_at, webhook_deliveries.event_id,
+                ROW_NUMBER() OVER (PARTITION BY webhook_deliveries.webhook_id ORDER BY webhook_deliveries.next_attempt_at, webhook_deliveries.event_id) AS position
+            FROM webhook_deliveries
+            JOIN webhooks ON webhooks.id = webhook_deliveries.webhook_id
+            WHERE webhook_deliveries.state = 'pending' AND webhook_deliveries.next_attempt_at <= ? AND webhooks.enabled = 1
+        )
+        WHERE position <= ?
+        ORDER BY next_attempt_at, event_id";
+    pub(crate) static ref COMPLETE_WEBHOOK_DELIVERY: &'static str = r"UPDATE webhook_deliveries
+            SET state = 'delivered', attempts = attempts + 1, last_attempt_at = (strftime('%s','now')), last_status = ?, last_error = NULL, delivered_at = (strftime('%s','now'))
+            WHERE id = ?";
+    pub(crate) static ref FAIL_WEBHOOK_DELIVERY: &'static str = r"UPDATE webhook_deliveries
+            SET state = ?, attempts = attempts + 1, last_attempt_at = (strftime('%s','now')), last_status = ?, last_error = ?, next_attempt_at = ?
+            WHERE id = ?";
+    pub(crate) static ref POSTPONE_WEBHOOK_DELIVERIES: &'static str = r"UPDATE webhook_deliveries
+            SET next_attempt_at = MAX(next_attempt_at, ?)
+            WHERE webhook_id = ? AND state = 'pending'";
+    pub(crate) static ref GET_WEBHOOK_DELIVERIES: &'static str = r"
+        SELECT * FROM webhook_deliveries
+        WHERE (?1 IS NULL OR webhook_id = ?1) AND (?2 IS NULL OR state = ?2)
+        ORDER BY created_at DESC, event_id DESC
+        LIMIT 500";
+    pub(crate) static ref REDELIVER_WEBHOOK_DELIVERY: &'static str = r"UPDATE webhook_deliveries
+            SET state = 'pending', attempts = 0, next_attempt_at = (strftime('%s','now'))
+            WHERE id = ? AND state = 'dead'
+            RETURNING *";
+    pub(crate) static ref PRUNE_WEBHOOK_DELIVERIES: &'static str =
+        r"DELETE FROM webhook_deliveries WHERE state = 'delivered' AND delivered_at < ?";
 }
@@ -0,0 +1,16 @@
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// Generates a random bearer secret, prefixed so it's recognisable in logs
+/// and config files.
+pub(crate) fn generate(prefix: &str) -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{}{}", prefix, hex::encode(bytes))
+}
+
+/// Generated secrets are long and random, so a plain SHA-256 is enough to
+/// keep them safe at rest while still allowing lookups by hash.
+pub(crate) fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
@@ -1,8 +1,10 @@
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use snowflake::SnowflakeGenerator;
-use sqlx::{FromRow, Pool, Sqlite};
+use sqlx::{FromRow, Pool, Sqlite, Transaction};
 
-use crate::db::strings;
+use crate::db::{audit, password_resets, sessions, strings};
+use crate::permissions::{Permission, Role};
 
 #[cfg(debug_assertions)]
 const BCRYPT_COST: u32 = 8;
@@ -20,6 +22,18 @@ pub struct User {
     pub created_at: i64,
     pub admin: bool,
     pub enabled: bool,
+    pub role: Role,
+    pub mfa_enabled: bool,
+    /// The session the user authenticated with, when they came in on a token
+    #[sqlx(skip)]
+    #[serde(skip)]
+    pub session_id: Option<String>,
+    /// The permissions of the API key the request was made with, if any.
+    /// These narrow the user's role: a request needs both the scope and the
+    /// role's permission, except for posting locations, which only keys do.
+    #[sqlx(skip)]
+    #[serde(skip)]
+    pub scopes: Option<Vec<Permission>>,
 }
 
 pub async fn get_user(pool: &Pool<Sqlite>, id: &str) -> Result<User, sqlx::Error> {
@@ -38,11 +52,21 @@ pub async fn get_user_by_email(pool: &Pool<Sqlite>, email: &str) -> Result<User,
     Ok(user)
 }
 
+pub async fn list_users(pool: &Pool<Sqlite>) -> Result<Vec<User>, sqlx::Error> {
+    let users = sqlx::query_as::<_, User>(&strings::GET_ALL_USERS)
+        .fetch_all(pool)
+        .await?;
+    Ok(users)
+}
+
 pub async fn create_user(
     pool: &Pool<Sqlite>,
     email: &str,
     password: &str,
     display_name: &str,
+    phone: Option<&str>,
+    role: Role,
+    created_by: Option<&str>,
 ) -> Result<User, sqlx::Error> {
     let mut transaction = pool.begin().await?;
 
@@ -53,9 +77,287 @@ pub async fn create_user(
         .bind(email)
         .bind(bcrypt::hash(password, BCRYPT_COST).unwrap())
         .bind(display_name)
+        .bind(phone)
+        .bind(role)
+        .bind(role == Role::Admin)
+        .bind(role == Role::Dispatcher)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+    audit::record(
+        &mut *transaction,
+        created_by,
+        "user.created",
+        "user",
+        Some(&user.id),
+        Some(json!({"email": user.email, "displayName": user.display_name, "role": role})),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(user)
+}
+
+/// Creates a user the first time they sign in through SSO. They're given a
+/// password that no input hashes to, so they can only ever sign in through
+/// the provider.
+pub async fn create_sso_user(
+    pool: &Pool<Sqlite>,
+    email: &str,
+    display_name: &str,
+    role: Role,
+    oidc_subject: &str,
+) -> Result<User, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let id = SnowflakeGenerator::new(0, 0).generate().to_string();
+
+    sqlx::query_as::<_, User>(&strings::CREATE_USER)
+        .bind(&id)
+        .bind(email)
+        .bind("!")
+        .bind(display_name)
+        .bind(None::<String>)
+        .bind(role)
+        .bind(role == Role::Admin)
+        .bind(role == Role::Dispatcher)
+        .fetch_one(&mut *transaction)
+        .await?;
+    let user = sqlx::query_as::<_, User>(&strings::LINK_OIDC_SUBJECT)
+        .bind(oidc_subject)
+        .bind(&id)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+    audit::record(
+        &mut *transaction,
+        None,
+        "user.provisioned",
+        "user",
+        Some(&user.id),
+        Some(json!({"email": user.email, "displayName": user.display_name, "role": role})),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(user)
+}
+
+/// Fields an admin can change on an existing user. Unset fields are left alone.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UserUpdate {
+    pub email: Option<String>,
+    pub display_name: Option<String>,
+    pub phone: Option<String>,
+    pub role: Option<Role>,
+}
+
+pub async fn update_user(
+    pool: &Pool<Sqlite>,
+    id: &str,
+    update: &UserUpdate,
+    updated_by: &str,
+) -> Result<User, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    // The admin and dispatcher flags predate roles; keep them in step so
+    // older tokens and clients still see the right thing.
+    let user = sqlx::query_as::<_, User>(&strings::UPDATE_USER)
+        .bind(&update.email)
+        .bind(&update.display_name)
+        .bind(&update.phone)
+        .bind(update.role)
+        .bind(update.role.map(|role| role == Role::Admin))
+        .bind(update.role.map(|role| role == Role::Dispatcher))
+        .bind(id)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+    audit::record(
+        &mut *transaction,
+        Some(updated_by),
+        "user.updated",
+        "user",
+        Some(id),
+        Some(json!(update)),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(user)
+}
+
+pub async fn set_enabled(
+    pool: &Pool<Sqlite>,
+    id: &str,
+    enabled: bool,
+    changed_by: &str,
+) -> Result<User, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let user = sqlx::query_as::<_, User>(&strings::SET_USER_ENABLED)
+        .bind(enabled)
+        .bind(id)
         .fetch_one(&mut *transaction)
         .await?;
 
+    if !enabled {
+        sessions::revoke_all(&mut *transaction, id, changed_by, None).await?;
+    }
+
+    let action = if enabled {
+        "user.enabled"
+    } else {
+        "user.disabled"
+    };
+    audit::record(
+        &mut *transaction,
+        Some(changed_by),
+        action,
+        "user",
+        Some(id),
+        None,
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(user)
+}
+
+/// Replaces a user's password and signs them out, keeping `keep_session` if
+/// the user is changing it from that session.
+async fn replace_password(
+    transaction: &mut Transaction<'_, Sqlite>,
+    id: &str,
+    password: &str,
+    actor_id: &str,
+    keep_session: Option<&str>,
+    action: &str,
+) -> Result<User, sqlx::Error> {
+    let user = sqlx::query_as::<_, User>(&strings::SET_USER_PASSWORD)
+        .bind(bcrypt::hash(password, BCRYPT_COST).unwrap())
+        .bind(id)
+        .fetch_one(&mut **transaction)
+        .await?;
+
+    sessions::revoke_all(&mut **transaction, id, actor_id, keep_session).await?;
+    audit::record(
+        &mut **transaction,
+        Some(actor_id),
+        action,
+        "user",
+        Some(id),
+        None,
+    )
+    .await?;
+
+    Ok(user)
+}
+
+/// Sets a user's password on an admin's behalf.
+pub async fn set_password(
+    pool: &Pool<Sqlite>,
+    id: &str,
+    password: &str,
+    changed_by: &str,
+) -> Result<User, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let user = replace_password(
+        &mut transaction,
+        id,
+        password,
+        changed_by,
+        None,
+        "user.password_reset",
+    )
+    .await?;
+    transaction.commit().await?;
+    Ok(user)
+}
+
+/// Changes a user's own password, signing out every other session.
+pub async fn change_password(
+    pool: &Pool<Sqlite>,
+    id: &str,
+    password: &str,
+    current_session: Option<&str>,
+) -> Result<User, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let user = replace_password(
+        &mut transaction,
+        id,
+        password,
+        id,
+        current_session,
+        "user.password_changed",
+    )
+    .await?;
     transaction.commit().await?;
     Ok(user)
 }
+
+/// Sets a password using a one-time reset code. Returns `None` if the code is
+/// unknown, expired or already used.
+pub async fn reset_password_with_code(
+    pool: &Pool<Sqlite>,
+    code: &str,
+    password: &str,
+) -> Result<Option<User>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let Some(reset) = password_resets::consume(&mut *transaction, code).await? else {
+        return Ok(None);
+    };
+    let user = replace_password(
+        &mut transaction,
+        &reset.user_id,
+        password,
+        &reset.user_id,
+        None,
+        "user.password_reset_completed",
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(Some(user))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    #[tokio::test]
+    async fn reset_codes_work_once() {
+        let pool = test_pool().await;
+        let user = create_user(
+            &pool,
+            "medic@example.com",
+            "old password",
+            "Medic",
+            None,
+            Role::FieldUnit,
+            None,
+        )
+        .await
+        .unwrap();
+        let (_reset, code) = password_resets::create(&pool, &user.id, &user.id)
+            .await
+            .unwrap();
+
+        assert!(reset_password_with_code(&pool, "wrong", "new password")
+            .await
+            .unwrap()
+            .is_none());
+        let reset = reset_password_with_code(&pool, &code, "new password")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(bcrypt::verify("new password", &reset.password).unwrap());
+        assert!(reset_password_with_code(&pool, &code, "another password")
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
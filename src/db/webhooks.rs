@@ -0,0 +1,314 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use snowflake::SnowflakeGenerator;
+use sqlx::{types::Json, FromRow, Pool, Sqlite};
+
+use super::{audit, events::Event, strings, tokens};
+
+pub const SECRET_PREFIX: &str = "whsec_";
+
+/// An endpoint that's sent events as they happen. Its secret isn't included
+/// here: it signs each request, so unlike our own credentials it's stored
+/// as-is, and only read when sending.
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// Event names like `job.created`, `job.*` for every change to jobs, or
+    /// `*` for everything
+    pub events: Json<Vec<String>>,
+    pub enabled: bool,
+    pub created_at: i64,
+    pub created_by: Option<String>,
+}
+
+impl Webhook {
+    pub fn wants(&self, event_name: &str) -> bool {
+        self.events
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => event_name.starts_with(prefix),
+                None => pattern == event_name,
+            })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum DeliveryState {
+    Pending,
+    Delivered,
+    /// Gave up after too many failures. Left for an admin to redeliver.
+    Dead,
+}
+
+/// One event queued for one webhook, and how sending it has gone.
+#[derive(Serialize, Deserialize, Debug, FromRow, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Delivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub event_id: i64,
+    pub event: String,
+    pub state: DeliveryState,
+    pub attempts: i64,
+    pub created_at: i64,
+    pub next_attempt_at: i64,
+    pub last_attempt_at: Option<i64>,
+    /// The HTTP status of the last attempt, if the receiver answered at all
+    pub last_status: Option<i64>,
+    pub last_error: Option<String>,
+    pub delivered_at: Option<i64>,
+}
+
+/// A delivery that's ready to send, with what's needed to send it.
+#[derive(Debug, FromRow, Clone)]
+pub struct DueDelivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub url: String,
+    pub secret: String,
+    pub event: String,
+    /// The exact body that's signed and sent, kept so retries are identical
+    pub payload: String,
+    pub attempts: i64,
+}
+
+pub async fn list(pool: &Pool<Sqlite>) -> Result<Vec<Webhook>, sqlx::Error> {
+    let webhooks = sqlx::query_as::<_, Webhook>(&strings::GET_WEBHOOKS)
+        .fetch_all(pool)
+        .await?;
+    Ok(webhooks)
+}
+
+/// Creates a webhook, generating a secret if one isn't given. The secret is
+/// returned here and not listed afterwards.
+pub async fn create(
+    pool: &Pool<Sqlite>,
+    url: &str,
+    events: &[String],
+    secret: Option<String>,
+    created_by: &str,
+) -> Result<(Webhook, String), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let id: String = SnowflakeGenerator::new(0, 0).generate().to_string();
+    let secret = secret.unwrap_or_else(|| tokens::generate(SECRET_PREFIX));
+
+    let webhook = sqlx::query_as::<_, Webhook>(&strings::CREATE_WEBHOOK)
+        .bind(&id)
+        .bind(url)
+        .bind(Json(events))
+        .bind(&secret)
+        .bind(created_by)
+        .fetch_one(&mut *transaction)
+        .await?;
+    audit::record(
+        &mut *transaction,
+        Some(created_by),
+        "webhook.created",
+        "webhook",
+        Some(&id),
+        Some(json!({"url": url, "events": events})),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok((webhook, secret))
+}
+
+pub async fn update(
+    pool: &Pool<Sqlite>,
+    id: &str,
+    url: Option<&str>,
+    events: Option<&[String]>,
+    enabled: Option<bool>,
+    updated_by: &str,
+) -> Result<Webhook, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let webhook = sqlx::query_as::<_, Webhook>(&strings::UPDATE_WEBHOOK)
+        .bind(url)
+        .bind(events.map(Json))
+        .bind(enabled)
+        .bind(id)
+        .fetch_one(&mut *transaction)
+        .await?;
+    audit::record(
+        &mut *transaction,
+        Some(updated_by),
+        "webhook.updated",
+        "webhook",
+        Some(id),
+        Some(json!({"url": url, "events": events, "enabled": enabled})),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(webhook)
+}
+
+/// Deletes a webhook along with its queued and logged deliveries.
+pub async fn delete(pool: &Pool<Sqlite>, id: &str, deleted_by: &str) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query(&strings::DELETE_WEBHOOK)
+        .bind(id)
+        .fetch_one(&mut *transaction)
+        .await?;
+    audit::record(
+        &mut *transaction,
+        Some(deleted_by),
+        "webhook.deleted",
+        "webhook",
+        Some(id),
+        None,
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Queues an event for every enabled webhook that wants it, returning how
+/// many deliveries were queued.
+pub async fn enqueue(pool: &Pool<Sqlite>, event: &Event) -> Result<usize, sqlx::Error> {
+    let name = event.name();
+    let webhooks = sqlx::query_as::<_, Webhook>(&strings::GET_ENABLED_WEBHOOKS)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .filter(|webhook| webhook.wants(&name))
+        .collect::<Vec<_>>();
+    if webhooks.is_empty() {
+        return Ok(0);
+    }
+
+    let payload = serde_json::to_string(event).expect("events always serialize");
+    let mut transaction = pool.begin().await?;
+    for webhook in &webhooks {
+        sqlx::query(&strings::ADD_WEBHOOK_DELIVERY)
+            .bind(SnowflakeGenerator::new(0, 0).generate().to_string())
+            .bind(&webhook.id)
+            .bind(event.id)
+            .bind(&name)
+            .bind(&payload)
+            .execute(&mut *transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+    Ok(webhooks.len())
+}
+
+/// Deliveries waiting to be sent, oldest first, and at most `limit` for each
+/// webhook. Deliveries for disabled webhooks wait until they're enabled
+/// again.
+pub async fn due(pool: &Pool<Sqlite>, limit: i64) -> Result<Vec<DueDelivery>, sqlx::Error> {
+    let deliveries = sqlx::query_as::<_, DueDelivery>(&strings::GET_DUE_WEBHOOK_DELIVERIES)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    Ok(deliveries)
+}
+
+pub async fn record_success(pool: &Pool<Sqlite>, id: &str, status: u16) -> Result<(), sqlx::Error> {
+    sqlx::query(&strings::COMPLETE_WEBHOOK_DELIVERY)
+        .bind(status)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Records a failed attempt. Without a `retry_at` the delivery is given up on
+/// and becomes a dead letter.
+pub async fn record_failure(
+    pool: &Pool<Sqlite>,
+    id: &str,
+    status: Option<u16>,
+    error: &str,
+    retry_at: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    let state = match retry_at {
+        Some(_) => DeliveryState::Pending,
+        None => DeliveryState::Dead,
+    };
+    sqlx::query(&strings::FAIL_WEBHOOK_DELIVERY)
+        .bind(state)
+        .bind(status)
+        .bind(error)
+        .bind(retry_at.unwrap_or_else(|| chrono::Utc::now().timestamp()))
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Holds off on everything queued for a webhook until `until`, without
+/// counting it as an attempt. Used when its receiver is failing, so its
+/// backlog isn't sent to it one timeout at a time.
+pub async fn postpone(
+    pool: &Pool<Sqlite>,
+    webhook_id: &str,
+    until: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(&strings::POSTPONE_WEBHOOK_DELIVERIES)
+        .bind(until)
+        .bind(webhook_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// The most recent deliveries, optionally for one webhook or in one state.
+pub async fn list_deliveries(
+    pool: &Pool<Sqlite>,
+    webhook_id: Option<&str>,
+    state: Option<DeliveryState>,
+) -> Result<Vec<Delivery>, sqlx::Error> {
+    let deliveries = sqlx::query_as::<_, Delivery>(&strings::GET_WEBHOOK_DELIVERIES)
+        .bind(webhook_id)
+        .bind(state)
+        .fetch_all(pool)
+        .await?;
+    Ok(deliveries)
+}
+
+/// Puts a dead letter back in the queue with a fresh set of attempts.
+pub async fn redeliver(
+    pool: &Pool<Sqlite>,
+    id: &str,
+    redelivered_by: &str,
+) -> Result<Delivery, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let delivery = sqlx::query_as::<_, Delivery>(&strings::REDELIVER_WEBHOOK_DELIVERY)
+        .bind(id)
+        .fetch_one(&mut *transaction)
+        .await?;
+    audit::record(
+        &mut *transaction,
+        Some(redelivered_by),
+        "webhook.redelivered",
+        "webhook_delivery",
+        Some(id),
+        Some(json!({"webhookId": delivery.webhook_id, "eventId": delivery.event_id})),
+    )
+    .await?;
+
+    transaction.commit().await?;
+    Ok(delivery)
+}
+
+/// Forgets successful deliveries. Dead letters are kept until they're
+/// redelivered or the webhook is deleted.
+pub async fn prune_deliveries(pool: &Pool<Sqlite>, before: i64) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(&strings::PRUNE_WEBHOOK_DELIVERIES)
+        .bind(before)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
@@ -1,29 +1,35 @@
-use std::collections::BTreeMap;
 use std::env;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
-use axum::extract::FromRequestParts;
+use axum::extract::{ConnectInfo, FromRequestParts};
 use axum::http::{header, request::Parts, HeaderValue, StatusCode};
 use axum::response::IntoResponse;
 use axum::response::Response;
 use axum::{async_trait, Extension, RequestPartsExt};
-use hmac::{Hmac, Mac};
-use jwt::VerifyWithKey;
+use jsonwebtoken::errors::ErrorKind;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sha2::Sha256;
 use sqlx::{Pool, Sqlite};
 
 use crate::db;
+use crate::db::devices::Device;
 use crate::db::users::User;
+use crate::permissions::{Permission, RequiredPermission};
+use crate::signing;
 
 lazy_static! {
-    static ref JWT_SECRET: String = env::var("JWT_SECRET").unwrap();
+    /// Allowed clock skew when checking when a token was issued and expires
+    static ref JWT_LEEWAY_SECONDS: u64 = env::var("JWT_LEEWAY_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(60);
 }
 
 pub struct Json<T>(pub T);
 
+/// The claims in our access tokens.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Token {
     pub iss: String,
@@ -33,9 +39,123 @@ pub struct Token {
     pub dn: String,
     pub email: String,
     pub admin: bool,
+    pub sid: String,
 }
+
+/// Why an access token was refused. Each maps to a distinct `code` in the
+/// response so clients can tell a token that needs refreshing from one that
+/// never will work.
+#[derive(Debug)]
+pub enum TokenError {
+    Expired,
+    NotYetValid,
+    /// Not a JWT, or missing claims or with claims of the wrong type
+    Malformed,
+    InvalidSignature,
+    WrongIssuer,
+    SessionRevoked,
+    UnknownUser,
+    DisabledUser,
+    Database(sqlx::Error),
+}
+
+impl TokenError {
+    fn code(&self) -> &'static str {
+        match self {
+            TokenError::Expired => "token_expired",
+            TokenError::NotYetValid => "token_not_yet_valid",
+            TokenError::Malformed => "token_malformed",
+            TokenError::InvalidSignature => "token_invalid_signature",
+            TokenError::WrongIssuer => "token_wrong_issuer",
+            TokenError::SessionRevoked => "session_revoked",
+            TokenError::UnknownUser => "unknown_user",
+            TokenError::DisabledUser => "user_disabled",
+            TokenError::Database(_) => "database_error",
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            TokenError::Expired => "token has expired",
+            TokenError::NotYetValid => "token is not valid yet",
+            TokenError::Malformed => "token is malformed",
+            TokenError::InvalidSignature => "token signature is invalid",
+            TokenError::WrongIssuer => "token was not issued by integral",
+            TokenError::SessionRevoked => "session has been revoked",
+            TokenError::UnknownUser => "user does not exist",
+            TokenError::DisabledUser => "user is disabled",
+            TokenError::Database(_) => "error checking token",
+        }
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for TokenError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        match e.kind() {
+            ErrorKind::ExpiredSignature => TokenError::Expired,
+            ErrorKind::ImmatureSignature => TokenError::NotYetValid,
+            ErrorKind::InvalidIssuer => TokenError::WrongIssuer,
+            ErrorKind::InvalidSignature
+            | ErrorKind::InvalidAlgorithm
+            | ErrorKind::InvalidAlgorithmName
+            | ErrorKind::InvalidKeyFormat => TokenError::InvalidSignature,
+            _ => TokenError::Malformed,
+        }
+    }
+}
+
+impl From<TokenError> for (StatusCode, Json<serde_json::Value>) {
+    fn from(e: TokenError) -> Self {
+        match &e {
+            TokenError::Database(details) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": e.message(),
+                    "code": e.code(),
+                    "details": details.to_string(),
+                })),
+            ),
+            _ => (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": e.message(), "code": e.code()})),
+            ),
+        }
+    }
+}
+
+/// A signed-in user. API keys aren't accepted, so routes that manage the
+/// user's own account can't be reached by a leaked integration secret.
 pub struct Jwt(pub User);
 
+/// Finds the user behind the "Authorization" header, which can carry either
+/// an access token or an API key.
+async fn authenticate(
+    parts: &mut Parts,
+) -> Result<User, (axum::http::StatusCode, Json<serde_json::Value>)> {
+    // Grab the "Authorization" header from the request
+    let auth_header = parts
+        .headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+
+    match auth_header {
+        Some(header) => {
+            let token = header.replace("Bearer ", "");
+            let Extension(db_pool) = parts
+                .extract::<Extension<Arc<Pool<Sqlite>>>>()
+                .await
+                .map_err(|err| err.into_response())
+                .unwrap();
+
+            get_user_from_token(&db_pool, &token).await
+        }
+        None => Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "missing auth header"})),
+        )),
+    }
+}
+
 #[async_trait]
 impl<S> FromRequestParts<S> for Jwt
 where
@@ -44,74 +164,286 @@ where
     type Rejection = (axum::http::StatusCode, Json<serde_json::Value>);
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        // Grab the "Authorization" header from the request
-        let auth_header = parts
+        let user = authenticate(parts).await?;
+        if user.scopes.is_some() {
+            return Err((
+                StatusCode::FORBIDDEN,
+                Json(json!({"error": "API keys can't be used here"})),
+            ));
+        }
+        Ok(Self(user))
+    }
+}
+
+/// An authenticated user or API key that holds the permission the route
+/// declares with a [`RequiredPermission`] extension. Routes that don't declare
+/// one are refused.
+pub struct Authorized(pub User);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Authorized
+where
+    S: Send + Sync,
+{
+    type Rejection = (axum::http::StatusCode, Json<serde_json::Value>);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let user = authenticate(parts).await?;
+        authorize(&user, parts.extensions.get::<RequiredPermission>().copied())?;
+        if !user.mfa_enabled {
+            let Extension(db_pool) = parts
+                .extract::<Extension<Arc<Pool<Sqlite>>>>()
+                .await
+                .map_err(|err| err.into_response())
+                .unwrap();
+            check_mfa_enrolled(&db_pool, &user).await?;
+        }
+        Ok(Self(user))
+    }
+}
+
+/// Users whose role requires MFA can still sign in to enroll, but can't use
+/// anything that needs a permission until they have. API keys are for
+/// machines, so they're exempt.
+pub async fn check_mfa_enrolled(
+    pool: &Pool<Sqlite>,
+    user: &User,
+) -> Result<(), (axum::http::StatusCode, Json<serde_json::Value>)> {
+    if user.scopes.is_some() {
+        return Ok(());
+    }
+    match db::mfa::is_required(pool, user.role).await {
+        Ok(true) if !user.mfa_enabled => Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "mfa_enrollment_required"})),
+        )),
+        Ok(_) => Ok(()),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "error checking mfa requirement", "details": e.to_string()})),
+        )),
+    }
+}
+
+pub fn authorize(
+    user: &User,
+    required: Option<RequiredPermission>,
+) -> Result<(), (axum::http::StatusCode, Json<serde_json::Value>)> {
+    let Some(RequiredPermission(permission)) = required else {
+        tracing::error!("route is missing a required permission");
+        return Err((StatusCode::FORBIDDEN, Json(json!({"error": "forbidden"}))));
+    };
+    let allowed = match &user.scopes {
+        // A key never does more than its owner's role allows, so demoting
+        // someone also narrows the keys they've already made
+        Some(scopes) => {
+            scopes.contains(&permission)
+                && (permission == Permission::PostLocations || user.role.has(permission))
+        }
+        None => user.role.has(permission),
+    };
+    if !allowed {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "forbidden", "permission": permission})),
+        ));
+    }
+    Ok(())
+}
+
+/// A location-reporting device, authenticated by the device token in its
+/// "Authorization" header. Failed attempts are recorded for admins to review.
+pub struct DeviceToken(pub Device);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for DeviceToken
+where
+    S: Send + Sync,
+{
+    type Rejection = (axum::http::StatusCode, Json<serde_json::Value>);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Extension(db_pool) = parts
+            .extract::<Extension<Arc<Pool<Sqlite>>>>()
+            .await
+            .map_err(|err| err.into_response())
+            .unwrap();
+        let remote_addr = remote_addr(parts);
+
+        let token = parts
             .headers
             .get(axum::http::header::AUTHORIZATION)
-            .and_then(|value| value.to_str().ok());
-
-        match auth_header {
-            Some(header) => {
-                let token = header.replace("Bearer ", "");
-                let Extension(db_pool) = parts
-                    .extract::<Extension<Arc<Pool<Sqlite>>>>()
-                    .await
-                    .map_err(|err| err.into_response())
-                    .unwrap();
-
-                let user = get_user_from_token(&db_pool, &token).await;
-
-                match user {
-                    Ok(user) => Ok(Self(user)),
-                    Err(e) => Err(e),
-                }
-            }
-            None => {
-                return Err((
+            .and_then(|value| value.to_str().ok())
+            .map(|header| header.replace("Bearer ", ""));
+        let Some(token) = token else {
+            reject_device(&db_pool, None, remote_addr, "missing auth header").await;
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "missing auth header"})),
+            ));
+        };
+
+        match db::devices::get_by_token(&db_pool, &token).await {
+            Ok(Some(device)) => Ok(Self(device)),
+            Ok(None) => {
+                reject_device(&db_pool, None, remote_addr, "invalid device token").await;
+                Err((
                     StatusCode::UNAUTHORIZED,
-                    Json(json!({"error": "missing auth header"})),
+                    Json(json!({"error": "invalid device token"})),
                 ))
             }
+            Err(e) => Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "error fetching device from db", "details": e.to_string()})),
+            )),
         }
     }
 }
 
+/// Whoever is posting a location: a tracker bound to one resource, or an
+/// integration with an API key that can post for any resource.
+pub enum LocationReporter {
+    Device(Device),
+    ApiKey(User),
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for LocationReporter
+where
+    S: Send + Sync,
+{
+    type Rejection = (axum::http::StatusCode, Json<serde_json::Value>);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let api_key = parts
+            .headers
+            .get(axum::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .map(|header| header.replace("Bearer ", ""))
+            .filter(|token| token.starts_with(db::api_keys::KEY_PREFIX));
+        let Some(api_key) = api_key else {
+            let DeviceToken(device) = DeviceToken::from_request_parts(parts, state).await?;
+            return Ok(Self::Device(device));
+        };
+
+        let Extension(db_pool) = parts
+            .extract::<Extension<Arc<Pool<Sqlite>>>>()
+            .await
+            .map_err(|err| err.into_response())
+            .unwrap();
+        let user = get_user_from_api_key(&db_pool, &api_key).await?;
+        authorize(&user, Some(RequiredPermission(Permission::PostLocations)))?;
+        Ok(Self::ApiKey(user))
+    }
+}
+
+pub fn remote_addr(parts: &Parts) -> Option<String> {
+    parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+}
+
+/// Records a rejected location update. Failing to record it shouldn't change
+/// the response, so errors are only logged.
+pub async fn reject_device(
+    pool: &Pool<Sqlite>,
+    device: Option<&Device>,
+    remote_addr: Option<String>,
+    reason: &str,
+) {
+    tracing::warn!(
+        device = device.map(|d| d.id.as_str()),
+        remote_addr = remote_addr.as_deref(),
+        "rejected location update: {}",
+        reason
+    );
+    if let Err(e) = db::devices::log_rejection(
+        pool,
+        device.map(|d| d.id.as_str()),
+        device.map(|d| d.resource_id.as_str()),
+        remote_addr,
+        reason,
+    )
+    .await
+    {
+        tracing::error!("failed to record rejected location update: {}", e);
+    }
+}
+
 pub async fn get_user_from_token(
     pool: &Pool<Sqlite>,
     token: &str,
 ) -> Result<User, (axum::http::StatusCode, Json<serde_json::Value>)> {
-    let key: Hmac<Sha256> = Hmac::new_from_slice((*JWT_SECRET).as_bytes()).unwrap();
+    if token.starts_with(db::api_keys::KEY_PREFIX) {
+        return get_user_from_api_key(pool, token).await;
+    }
+
+    Ok(user_from_token(pool, token).await?)
+}
+
+/// Checks an access token's signature and claims, then that its session and
+/// user are still good.
+async fn user_from_token(pool: &Pool<Sqlite>, token: &str) -> Result<User, TokenError> {
+    let mut validation = jsonwebtoken::Validation::default();
+    validation.leeway = *JWT_LEEWAY_SECONDS;
+    validation.validate_aud = false;
+    validation.set_issuer(&[signing::ISSUER]);
+    validation.set_required_spec_claims(&["exp", "iss", "sub"]);
+
+    let token: Token = signing::KEYS.verify(token, validation)?.claims;
+    if token.iat > chrono::Utc::now().timestamp() + *JWT_LEEWAY_SECONDS as i64 {
+        return Err(TokenError::NotYetValid);
+    }
 
-    let claims: BTreeMap<String, String> = match token.verify_with_key(&key) {
-        Ok(claims) => claims,
-        Err(_) => {
+    if !db::sessions::is_active(pool, &token.sid, &token.sub)
+        .await
+        .map_err(TokenError::Database)?
+    {
+        return Err(TokenError::SessionRevoked);
+    }
+
+    match db::users::get_user(pool, &token.sub).await {
+        Ok(user) if !user.enabled => Err(TokenError::DisabledUser),
+        Ok(user) => Ok(User {
+            session_id: Some(token.sid),
+            ..user
+        }),
+        Err(sqlx::Error::RowNotFound) => Err(TokenError::UnknownUser),
+        Err(e) => Err(TokenError::Database(e)),
+    }
+}
+
+/// Finds the user behind an API key, limited to the key's scopes.
+async fn get_user_from_api_key(
+    pool: &Pool<Sqlite>,
+    secret: &str,
+) -> Result<User, (axum::http::StatusCode, Json<serde_json::Value>)> {
+    let key = match db::api_keys::get_by_secret(pool, secret).await {
+        Ok(Some(key)) => key,
+        Ok(None) => {
             return Err((
                 StatusCode::UNAUTHORIZED,
-                Json(json!({ "error": "Invalid token" })),
+                Json(json!({"error": "invalid or expired api key"})),
+            ))
+        }
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "error fetching api key from db", "details": e.to_string()})),
             ))
         }
     };
 
-    let token = Token {
-        iss: claims.get("iss").unwrap().to_string(),
-        sub: claims.get("sub").unwrap().to_string(),
-        iat: claims.get("iat").unwrap().parse().unwrap(),
-        exp: claims.get("exp").unwrap().parse().unwrap(),
-        dn: claims.get("dn").unwrap().to_string(),
-        email: claims.get("email").unwrap().to_string(),
-        admin: claims.get("admin").unwrap().parse().unwrap(),
-    };
-
-    let now = chrono::Utc::now().timestamp();
-    if token.iat > now || token.exp < now {
-        return Err((
+    match db::users::get_user(pool, &key.user_id).await {
+        Ok(user) if !user.enabled => Err((
             StatusCode::UNAUTHORIZED,
-            Json(json!({"error": "Invalid token"})),
-        ));
-    }
-
-    match db::users::get_user(&pool, &token.sub).await {
-        Ok(user) => Ok(user),
+            Json(json!({"error": "user is disabled"})),
+        )),
+        Ok(user) => Ok(User {
+            scopes: Some(key.permissions()),
+            ..user
+        }),
         Err(e) => Err((
             StatusCode::UNAUTHORIZED,
             Json(json!({"error": "error fetching user from db", "details": e.to_string()})),
@@ -145,3 +477,174 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::db::{sessions, test_pool, users};
+    use crate::permissions::Role;
+
+    /// A user with a live session, and claims for a token from it.
+    async fn signed_in(pool: &Pool<Sqlite>) -> (User, Value) {
+        let user = users::create_user(
+            pool,
+            "dispatch@example.com",
+            "password",
+            "Dispatch",
+            None,
+            Role::Dispatcher,
+            None,
+        )
+        .await
+        .unwrap();
+        let (session, _) = sessions::create(pool, &user.id, None, None).await.unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let claims = json!({
+            "iss": signing::ISSUER,
+            "sub": user.id,
+            "iat": now,
+            "exp": now + 900,
+            "dn": user.display_name,
+            "email": user.email,
+            "admin": false,
+            "sid": session.id,
+        });
+        (user, claims)
+    }
+
+    async fn check(pool: &Pool<Sqlite>, claims: &Value) -> Result<User, &'static str> {
+        let token = signing::KEYS.sign(claims).unwrap();
+        user_from_token(pool, &token).await.map_err(|e| e.code())
+    }
+
+    #[tokio::test]
+    async fn accepts_good_tokens() {
+        let pool = test_pool().await;
+        let (user, claims) = signed_in(&pool).await;
+        let found = check(&pool, &claims).await.unwrap();
+        assert_eq!(found.id, user.id);
+        assert_eq!(found.session_id.as_deref(), claims["sid"].as_str());
+    }
+
+    #[tokio::test]
+    async fn explains_bad_claims() {
+        let pool = test_pool().await;
+        let (_, claims) = signed_in(&pool).await;
+        let now = chrono::Utc::now().timestamp();
+
+        let mut expired = claims.clone();
+        expired["iat"] = json!(now - 7200);
+        expired["exp"] = json!(now - 3600);
+        assert_eq!(check(&pool, &expired).await.unwrap_err(), "token_expired");
+
+        let mut from_the_future = claims.clone();
+        from_the_future["iat"] = json!(now + 3600);
+        assert_eq!(
+            check(&pool, &from_the_future).await.unwrap_err(),
+            "token_not_yet_valid"
+        );
+
+        let mut wrong_issuer = claims.clone();
+        wrong_issuer["iss"] = json!("someone-else");
+        assert_eq!(
+            check(&pool, &wrong_issuer).await.unwrap_err(),
+            "token_wrong_issuer"
+        );
+
+        for claim in ["sid", "dn"] {
+            let mut missing = claims.clone();
+            missing.as_object_mut().unwrap().remove(claim);
+            assert_eq!(
+                check(&pool, &missing).await.unwrap_err(),
+                "token_malformed",
+                "without {}",
+                claim
+            );
+        }
+        assert_eq!(
+            user_from_token(&pool, "not a token")
+                .await
+                .unwrap_err()
+                .code(),
+            "token_malformed"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_other_signatures() {
+        let pool = test_pool().await;
+        let (_, claims) = signed_in(&pool).await;
+        let forged = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"someone else's secret"),
+        )
+        .unwrap();
+        assert_eq!(
+            user_from_token(&pool, &forged).await.unwrap_err().code(),
+            "token_invalid_signature"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_revoked_sessions() {
+        let pool = test_pool().await;
+        let (user, claims) = signed_in(&pool).await;
+        sessions::revoke(&pool, claims["sid"].as_str().unwrap(), &user.id, &user.id)
+            .await
+            .unwrap();
+        assert_eq!(check(&pool, &claims).await.unwrap_err(), "session_revoked");
+    }
+
+    #[tokio::test]
+    async fn refuses_missing_and_disabled_users() {
+        let pool = test_pool().await;
+        let (user, claims) = signed_in(&pool).await;
+
+        // Disabling through the API signs the user out first, so this is
+        // only reached if the session outlives it some other way
+        sqlx::query("UPDATE users SET enabled = false WHERE id = ?")
+            .bind(&user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(check(&pool, &claims).await.unwrap_err(), "user_disabled");
+
+        sqlx::query("PRAGMA foreign_keys = OFF")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM users WHERE id = ?")
+            .bind(&user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(check(&pool, &claims).await.unwrap_err(), "unknown_user");
+    }
+
+    #[tokio::test]
+    async fn limits_api_keys_to_their_owners_role() {
+        let pool = test_pool().await;
+        let (user, _) = signed_in(&pool).await;
+        let key = User {
+            scopes: Some(vec![Permission::CreateJobs, Permission::PostLocations]),
+            ..user
+        };
+        let allowed =
+            |user: &User, permission| authorize(user, Some(RequiredPermission(permission))).is_ok();
+        assert!(allowed(&key, Permission::CreateJobs));
+        assert!(allowed(&key, Permission::PostLocations));
+        assert!(!allowed(&key, Permission::ViewJobs));
+
+        let demoted = User {
+            role: Role::ReadOnly,
+            ..key
+        };
+        assert!(!allowed(&demoted, Permission::CreateJobs));
+        assert!(!allowed(&demoted, Permission::ViewJobs));
+        assert!(allowed(&demoted, Permission::PostLocations));
+    }
+}
@@ -4,4 +4,52 @@ use std::env;
 lazy_static! {
     pub static ref SIGNUPS_ENABLED: bool =
         env::var("SIGNUPS_ENABLED").unwrap_or_else(|_| String::from("false")) == "true";
+    /// Whether users signing in through SSO for the first time get an account
+    /// made for them. Otherwise an admin has to create it first.
+    pub static ref SSO_JIT_PROVISIONING: bool =
+        env::var("SSO_JIT_PROVISIONING").unwrap_or_else(|_| String::from("false")) == "true";
+    /// Whether positions are taken from APRS-IS. APRS senders can't be
+    /// authenticated, so this has to be turned on deliberately.
+    pub static ref APRS_ENABLED: bool =
+        env::var("APRS_ENABLED").unwrap_or_else(|_| String::from("false")) == "true";
+    /// Location fixes older than this many days are pruned. 0 keeps them forever.
+    pub static ref LOCATION_RETENTION_DAYS: i64 = env::var("LOCATION_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(0);
+    /// How long rejected location updates are kept for admins to review
+    pub static ref REJECTION_RETENTION_DAYS: i64 = env::var("REJECTION_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(30);
+    /// How long changes are kept in the event log for reconnecting clients
+    /// to replay.
+    pub static ref EVENT_RETENTION_HOURS: i64 = env::var("EVENT_RETENTION_HOURS")
+        .ok()
+        .and_then(|hours| hours.parse().ok())
+        .unwrap_or(24);
+    /// How many events each streaming client can fall behind by before it has
+    /// to catch up from the event log.
+    pub static ref EVENT_BUFFER_SIZE: usize = env::var("EVENT_BUFFER_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(1024);
+    /// How often idle streams get a keep-alive, so proxies don't close them
+    pub static ref STREAM_HEARTBEAT_SECONDS: u64 = env::var("STREAM_HEARTBEAT_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .filter(|seconds| *seconds > 0)
+        .unwrap_or(15);
+    /// How many times a webhook delivery is tried before it's a dead letter
+    pub static ref WEBHOOK_MAX_ATTEMPTS: i64 = env::var("WEBHOOK_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|attempts| attempts.parse().ok())
+        .filter(|attempts| *attempts > 0)
+        .unwrap_or(10);
+    /// How long successful webhook deliveries stay in the delivery log
+    pub static ref WEBHOOK_LOG_RETENTION_DAYS: i64 = env::var("WEBHOOK_LOG_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(7);
 }
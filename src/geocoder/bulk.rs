@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use axum::async_trait;
+
+use super::{Geocoder, Point};
+use crate::db::resources::GeocodeResponse;
+
+pub(super) const DEFAULT_URL: &str = "http://127.0.0.1:8081";
+const CONNECT_TIMEOUT_SECONDS: u64 = 5;
+const READ_TIMEOUT_SECONDS: u64 = 10;
+
+/// Client for the integral geocoding sidecar, which resolves a whole batch of
+/// points in a single request.
+pub struct BulkGeocoder {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl BulkGeocoder {
+    pub fn new(base_url: &str) -> Self {
+        Self::with_read_timeout(base_url, Duration::from_secs(READ_TIMEOUT_SECONDS))
+    }
+
+    fn with_read_timeout(base_url: &str, read_timeout: Duration) -> Self {
+        Self {
+            url: format!(
+                "{}/api/v0/geocode/reverse/bulk",
+                base_url.trim_end_matches('/')
+            ),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(CONNECT_TIMEOUT_SECONDS))
+                .timeout_read(read_timeout)
+                .build(),
+        }
+    }
+}
+
+#[async_trait]
+impl Geocoder for BulkGeocoder {
+    async fn reverse(&self, points: &[Point]) -> anyhow::Result<Vec<Option<GeocodeResponse>>> {
+        if points.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let url = self.url.clone();
+        let agent = self.agent.clone();
+        let body = points.to_vec();
+        let resp = tokio::task::spawn_blocking(move || {
+            agent
+                .post(&url)
+                .send_json(&body)?
+                .into_json::<Vec<GeocodeResponse>>()
+                .map_err(anyhow::Error::from)
+        })
+        .await??;
+
+        Ok(points
+            .iter()
+            .map(|p| {
+                resp.iter()
+                    .find(|gcr| gcr.lat == p.lat && gcr.lon == p.lon)
+                    .cloned()
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geocoder::ForwardUnsupported;
+
+    #[tokio::test]
+    async fn forward_is_unsupported() {
+        let geocoder = BulkGeocoder::new(DEFAULT_URL);
+        let err = geocoder.forward("1 Lomb Memorial Dr").await.unwrap_err();
+        assert!(err.is::<ForwardUnsupported>());
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_hung_sidecar() {
+        // Accepts connections and never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let _connections = listener.incoming().collect::<Vec<_>>();
+        });
+
+        let geocoder = BulkGeocoder::with_read_timeout(&url, Duration::from_millis(200));
+        let started = std::time::Instant::now();
+        let result = geocoder
+            .reverse(&[Point {
+                lat: String::from("43.08"),
+                lon: String::from("-77.67"),
+            }])
+            .await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use axum::async_trait;
+use sqlx::{Pool, Sqlite};
+
+use super::{ForwardGeocodeResponse, Geocoder, Point};
+use crate::{db, db::resources::GeocodeResponse, geo, metrics};
+
+/// Wraps another geocoder, keeping results in the `geocode_cache` table keyed
+/// on coordinates rounded to `precision` decimal places. Units parked in the
+/// same spot resolve from the cache instead of hitting the geocoder every time
+/// the resource board is loaded.
+pub struct CachingGeocoder {
+    inner: Arc<dyn Geocoder>,
+    pool: Arc<Pool<Sqlite>>,
+    ttl: i64,
+    precision: usize,
+}
+
+impl CachingGeocoder {
+    pub fn new(
+        inner: Arc<dyn Geocoder>,
+        pool: Arc<Pool<Sqlite>>,
+        ttl: i64,
+        precision: usize,
+    ) -> Self {
+        Self {
+            inner,
+            pool,
+            ttl,
+            precision,
+        }
+    }
+
+    fn key(&self, point: &Point) -> Option<(String, String)> {
+        let lat = point.lat.parse::<f64>().ok()?;
+        let lon = point.lon.parse::<f64>().ok()?;
+        Some((
+            format!("{:.*}", self.precision, lat),
+            format!("{:.*}", self.precision, lon),
+        ))
+    }
+}
+
+/// Fits a cached result to the point being looked up, which can be anywhere
+/// that rounds to the same key as the point it was cached for.
+fn for_point(mut resp: GeocodeResponse, point: &Point) -> GeocodeResponse {
+    resp.distance = match (
+        resp.address.latitude,
+        resp.address.longitude,
+        point.lat.parse::<f64>(),
+        point.lon.parse::<f64>(),
+    ) {
+        (Some(lat1), Some(lon1), Ok(lat2), Ok(lon2)) => geo::distance_m(lat1, lon1, lat2, lon2),
+        _ => 0.0,
+    };
+    resp.lat = point.lat.clone();
+    resp.lon = point.lon.clone();
+    resp
+}
+
+#[async_trait]
+impl Geocoder for CachingGeocoder {
+    async fn reverse(&self, points: &[Point]) -> anyhow::Result<Vec<Option<GeocodeResponse>>> {
+        let not_before = chrono::Utc::now().timestamp() - self.ttl;
+        let mut results = vec![None; points.len()];
+        let mut misses = Vec::new();
+
+        for (i, point) in points.iter().enumerate() {
+            let Some((lat_key, lon_key)) = self.key(point) else {
+                misses.push(i);
+                continue;
+            };
+            match db::geocode_cache::get(&self.pool, &lat_key, &lon_key, not_before).await {
+                Ok(Some(resp)) => results[i] = Some(for_point(resp, point)),
+                Ok(None) => misses.push(i),
+                Err(e) => {
+                    tracing::warn!("failed to read geocode cache: {}", e);
+                    misses.push(i);
+                }
+            }
+        }
+
+        metrics::GEOCODE_CACHE_HITS
+            .fetch_add((points.len() - misses.len()) as u64, Ordering::Relaxed);
+        metrics::GEOCODE_CACHE_MISSES.fetch_add(misses.len() as u64, Ordering::Relaxed);
+        if misses.is_empty() {
+            return Ok(results);
+        }
+
+        let miss_points = misses
+            .iter()
+            .map(|&i| points[i].clone())
+            .collect::<Vec<_>>();
+        let resolved = self.inner.reverse(&miss_points).await?;
+        for (i, resp) in misses.into_iter().zip(resolved) {
+            // Failed lookups aren't cached so they're retried next time
+            if let (Some(resp), Some((lat_key, lon_key))) = (&resp, self.key(&points[i])) {
+                if let Err(e) = db::geocode_cache::put(&self.pool, &lat_key, &lon_key, resp).await {
+                    tracing::warn!("failed to write geocode cache: {}", e);
+                }
+            }
+            results[i] = resp;
+        }
+
+        Ok(results)
+    }
+
+    async fn forward(&self, query: &str) -> anyhow::Result<Option<ForwardGeocodeResponse>> {
+        self.inner.forward(query).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+    use crate::geocoder::MockGeocoder;
+
+    fn point(lat: &str, lon: &str) -> Point {
+        Point {
+            lat: lat.to_string(),
+            lon: lon.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn hits_are_measured_from_the_point_looked_up() {
+        let pool = Arc::new(test_pool().await);
+        let geocoder = CachingGeocoder::new(Arc::new(MockGeocoder), pool, 60, 2);
+
+        let first = geocoder
+            .reverse(&[point("43.081", "-77.671")])
+            .await
+            .unwrap();
+        assert_eq!(first[0].as_ref().unwrap().distance, 0.0);
+
+        // Rounds to the same key, so it's answered with the first point's address
+        let second = geocoder
+            .reverse(&[point("43.084", "-77.674")])
+            .await
+            .unwrap();
+        let second = second[0].as_ref().unwrap();
+        assert_eq!(second.lat, "43.084");
+        assert_eq!(second.lon, "-77.674");
+        assert_eq!(
+            second.address.address_label.as_deref(),
+            Some("43.081, -77.671")
+        );
+        let expected = geo::distance_m(43.081, -77.671, 43.084, -77.674);
+        assert!((second.distance - expected).abs() < 0.01);
+        assert!(second.distance > 0.0);
+    }
+}
@@ -0,0 +1,129 @@
+use std::{env, fmt, sync::Arc};
+
+use axum::async_trait;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+use crate::db::{
+    self,
+    resources::{GeocodeResponse, RadarAddress},
+};
+
+mod bulk;
+mod cache;
+mod nominatim;
+mod offline;
+
+pub use bulk::BulkGeocoder;
+pub use cache::CachingGeocoder;
+pub use nominatim::NominatimGeocoder;
+pub use offline::{MockGeocoder, NoopGeocoder};
+
+lazy_static! {
+    static ref GEOCODER: String = env::var("GEOCODER").unwrap_or_else(|_| String::from("bulk"));
+    static ref GEOCODER_URL: Option<String> = env::var("GEOCODER_URL").ok();
+    /// How long cached reverse-geocode results are kept, in seconds. Set to 0 to disable the cache.
+    static ref GEOCODE_CACHE_TTL: i64 = env::var("GEOCODE_CACHE_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(7 * 24 * 60 * 60);
+    /// Decimal places coordinates are rounded to for cache keys. 4 places is about 11 meters.
+    static ref GEOCODE_CACHE_PRECISION: usize = env::var("GEOCODE_CACHE_PRECISION")
+        .ok()
+        .and_then(|precision| precision.parse().ok())
+        .unwrap_or(4);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Point {
+    pub lat: String,
+    pub lon: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ForwardGeocodeResponse {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// How sure the geocoder is of the match, from 0 to 1
+    pub confidence: f64,
+    pub address: RadarAddress,
+}
+
+#[async_trait]
+pub trait Geocoder: Send + Sync {
+    /// Reverse-geocodes a batch of points. The result has one entry per point,
+    /// in the same order, with `None` for points that couldn't be resolved.
+    async fn reverse(&self, points: &[Point]) -> anyhow::Result<Vec<Option<GeocodeResponse>>>;
+
+    /// Resolves a free-text address to its best match, if any. Backends that
+    /// can't forward-geocode fail with [`ForwardUnsupported`].
+    async fn forward(&self, _query: &str) -> anyhow::Result<Option<ForwardGeocodeResponse>> {
+        Err(ForwardUnsupported.into())
+    }
+}
+
+/// The configured geocoder can only reverse-geocode.
+#[derive(Debug)]
+pub struct ForwardUnsupported;
+
+impl fmt::Display for ForwardUnsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the {} geocoder can't look up addresses", name())
+    }
+}
+
+impl std::error::Error for ForwardUnsupported {}
+
+/// Builds the geocoder selected by the `GEOCODER` environment variable, one of
+/// `bulk` (the default), `nominatim`, `mock` or `none`. `GEOCODER_URL` points
+/// at the service, and has to be set for `nominatim` so nobody sends their
+/// traffic to a public instance by accident. Network-backed geocoders are
+/// wrapped in a cache unless `GEOCODE_CACHE_TTL` is 0.
+pub fn from_env(pool: Arc<Pool<Sqlite>>) -> Arc<dyn Geocoder> {
+    let geocoder = backend_from_env();
+    match GEOCODER.as_str() {
+        "bulk" | "nominatim" if *GEOCODE_CACHE_TTL > 0 => Arc::new(CachingGeocoder::new(
+            geocoder,
+            pool,
+            *GEOCODE_CACHE_TTL,
+            *GEOCODE_CACHE_PRECISION,
+        )),
+        _ => geocoder,
+    }
+}
+
+fn backend_from_env() -> Arc<dyn Geocoder> {
+    match GEOCODER.as_str() {
+        "bulk" => Arc::new(BulkGeocoder::new(
+            GEOCODER_URL.as_deref().unwrap_or(bulk::DEFAULT_URL),
+        )),
+        "nominatim" => match GEOCODER_URL.as_deref() {
+            Some(url) => Arc::new(NominatimGeocoder::new(url)),
+            None => {
+                tracing::warn!("GEOCODER_URL must be set to use nominatim, geocoding is disabled");
+                Arc::new(NoopGeocoder)
+            }
+        },
+        "mock" => Arc::new(MockGeocoder),
+        "none" => Arc::new(NoopGeocoder),
+        other => {
+            tracing::warn!("unknown geocoder {:?}, geocoding is disabled", other);
+            Arc::new(NoopGeocoder)
+        }
+    }
+}
+
+/// Forgets cached reverse-geocode results that have expired.
+pub async fn prune_cache(pool: &Pool<Sqlite>) -> Result<u64, sqlx::Error> {
+    if *GEOCODE_CACHE_TTL <= 0 {
+        return Ok(0);
+    }
+    let not_before = chrono::Utc::now().timestamp() - *GEOCODE_CACHE_TTL;
+    db::geocode_cache::prune(pool, not_before).await
+}
+
+pub fn name() -> &'static str {
+    GEOCODER.as_str()
+}
@@ -0,0 +1,224 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use axum::async_trait;
+use serde::Deserialize;
+
+use super::{ForwardGeocodeResponse, Geocoder, Point};
+use crate::db::resources::{GeocodeResponse, RadarAddress};
+
+const CONNECT_TIMEOUT_SECONDS: u64 = 5;
+const READ_TIMEOUT_SECONDS: u64 = 10;
+/// Nominatim's usage policy allows one request a second, which self-hosted
+/// instances are usually sized for too
+const MIN_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Client for a Nominatim-compatible API. Nominatim has no batch endpoint, so
+/// points are resolved one request at a time, no faster than one a second.
+#[derive(Clone)]
+pub struct NominatimGeocoder {
+    base_url: String,
+    agent: ureq::Agent,
+    /// When the last request was sent, shared by every clone
+    last_request: Arc<Mutex<Option<Instant>>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct NominatimPlace {
+    lat: String,
+    lon: String,
+    display_name: Option<String>,
+    #[serde(rename = "type")]
+    place_type: Option<String>,
+    importance: Option<f64>,
+    #[serde(default)]
+    address: NominatimAddress,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct NominatimAddress {
+    house_number: Option<String>,
+    road: Option<String>,
+    city: Option<String>,
+    town: Option<String>,
+    village: Option<String>,
+    county: Option<String>,
+    state: Option<String>,
+    postcode: Option<String>,
+    country: Option<String>,
+    country_code: Option<String>,
+}
+
+impl NominatimGeocoder {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(CONNECT_TIMEOUT_SECONDS))
+                .timeout_read(Duration::from_secs(READ_TIMEOUT_SECONDS))
+                .user_agent(&format!(
+                    "integral/{}",
+                    option_env!("CARGO_PKG_VERSION").unwrap_or("unknown")
+                ))
+                .build(),
+            last_request: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Waits until another request can be sent. Called from blocking tasks,
+    /// and holds the lock while waiting so requests go out one at a time.
+    fn throttle(&self) {
+        let mut last_request = self.last_request.lock().unwrap();
+        if let Some(last) = *last_request {
+            let elapsed = last.elapsed();
+            if elapsed < MIN_REQUEST_INTERVAL {
+                thread::sleep(MIN_REQUEST_INTERVAL - elapsed);
+            }
+        }
+        *last_request = Some(Instant::now());
+    }
+
+    fn reverse_one(&self, point: &Point) -> anyhow::Result<Option<GeocodeResponse>> {
+        self.throttle();
+        let resp = self
+            .agent
+            .get(&format!("{}/reverse", self.base_url))
+            .query("format", "jsonv2")
+            .query("addressdetails", "1")
+            .query("lat", &point.lat)
+            .query("lon", &point.lon)
+            .call()?
+            .into_json::<serde_json::Value>()?;
+
+        // Nominatim answers 200 with an error body when nothing is nearby
+        if resp.get("error").is_some() {
+            return Ok(None);
+        }
+        let place: NominatimPlace = serde_json::from_value(resp)?;
+        Ok(Some(place.into_response(point)))
+    }
+
+    fn forward_one(&self, query: &str) -> anyhow::Result<Option<ForwardGeocodeResponse>> {
+        self.throttle();
+        let places = self
+            .agent
+            .get(&format!("{}/search", self.base_url))
+            .query("format", "jsonv2")
+            .query("addressdetails", "1")
+            .query("limit", "1")
+            .query("q", query)
+            .call()?
+            .into_json::<Vec<NominatimPlace>>()?;
+
+        let Some(place) = places.into_iter().next() else {
+            return Ok(None);
+        };
+        let latitude = place.lat.parse::<f64>()?;
+        let longitude = place.lon.parse::<f64>()?;
+        // Nominatim doesn't report a confidence, but importance is a
+        // reasonable stand-in for how likely this is the place that was meant
+        let confidence = place.importance.unwrap_or(0.0).clamp(0.0, 1.0);
+        Ok(Some(ForwardGeocodeResponse {
+            latitude,
+            longitude,
+            confidence,
+            address: place.into_address(),
+        }))
+    }
+}
+
+impl NominatimPlace {
+    fn into_response(self, point: &Point) -> GeocodeResponse {
+        let address = self.into_address();
+        let distance = match (
+            address.latitude,
+            address.longitude,
+            point.lat.parse::<f64>(),
+            point.lon.parse::<f64>(),
+        ) {
+            (Some(lat1), Some(lon1), Ok(lat2), Ok(lon2)) => {
+                crate::geo::distance_m(lat1, lon1, lat2, lon2)
+            }
+            _ => 0.0,
+        };
+
+        GeocodeResponse {
+            lat: point.lat.clone(),
+            lon: point.lon.clone(),
+            distance,
+            address,
+        }
+    }
+
+    fn into_address(self) -> RadarAddress {
+        let address = self.address;
+        let street = address.road;
+        let address_label = match (&address.house_number, &street) {
+            (Some(number), Some(street)) => Some(format!("{} {}", number, street)),
+            (None, Some(street)) => Some(street.clone()),
+            _ => None,
+        };
+
+        RadarAddress {
+            address_label,
+            city: address.city.or(address.town).or(address.village),
+            country: address.country,
+            country_code: address.country_code.map(|c| c.to_uppercase()),
+            county: address.county,
+            formatted_address: self.display_name,
+            latitude: self.lat.parse().ok(),
+            layer: self.place_type,
+            longitude: self.lon.parse().ok(),
+            number: address.house_number,
+            postal_code: address.postcode,
+            state: address.state,
+            state_code: None,
+            street,
+        }
+    }
+}
+
+#[async_trait]
+impl Geocoder for NominatimGeocoder {
+    async fn reverse(&self, points: &[Point]) -> anyhow::Result<Vec<Option<GeocodeResponse>>> {
+        let geocoder = self.clone();
+        let points = points.to_vec();
+        tokio::task::spawn_blocking(move || {
+            Ok(points
+                .iter()
+                .map(|p| match geocoder.reverse_one(p) {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        tracing::warn!("failed to reverse geocode {},{}: {}", p.lat, p.lon, e);
+                        None
+                    }
+                })
+                .collect())
+        })
+        .await?
+    }
+
+    async fn forward(&self, query: &str) -> anyhow::Result<Option<ForwardGeocodeResponse>> {
+        let geocoder = self.clone();
+        let query = query.to_string();
+        tokio::task::spawn_blocking(move || geocoder.forward_one(&query)).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_spaces_requests() {
+        let geocoder = NominatimGeocoder::new("http://127.0.0.1:9");
+        let other = geocoder.clone();
+        let start = Instant::now();
+        geocoder.throttle();
+        other.throttle();
+        assert!(start.elapsed() >= MIN_REQUEST_INTERVAL);
+    }
+}
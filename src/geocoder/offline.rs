@@ -0,0 +1,68 @@
+use axum::async_trait;
+
+use super::{ForwardGeocodeResponse, Geocoder, Point};
+use crate::db::resources::{GeocodeResponse, RadarAddress};
+
+/// Geocoder for deployments without a geocoding service. Nothing is resolved.
+pub struct NoopGeocoder;
+
+#[async_trait]
+impl Geocoder for NoopGeocoder {
+    async fn reverse(&self, points: &[Point]) -> anyhow::Result<Vec<Option<GeocodeResponse>>> {
+        Ok(vec![None; points.len()])
+    }
+
+    async fn forward(&self, _query: &str) -> anyhow::Result<Option<ForwardGeocodeResponse>> {
+        Ok(None)
+    }
+}
+
+/// Geocoder that never touches the network, answering every point with an
+/// address made up from its coordinates. Useful for development and testing.
+pub struct MockGeocoder;
+
+#[async_trait]
+impl Geocoder for MockGeocoder {
+    async fn reverse(&self, points: &[Point]) -> anyhow::Result<Vec<Option<GeocodeResponse>>> {
+        Ok(points
+            .iter()
+            .map(|p| {
+                Some(GeocodeResponse {
+                    lat: p.lat.clone(),
+                    lon: p.lon.clone(),
+                    distance: 0.0,
+                    address: RadarAddress {
+                        address_label: Some(format!("{}, {}", p.lat, p.lon)),
+                        formatted_address: Some(format!("{}, {}", p.lat, p.lon)),
+                        latitude: p.lat.parse().ok(),
+                        longitude: p.lon.parse().ok(),
+                        layer: Some(String::from("mock")),
+                        ..Default::default()
+                    },
+                })
+            })
+            .collect())
+    }
+
+    /// Resolves queries that are already coordinates, like `43.08,-77.67`.
+    async fn forward(&self, query: &str) -> anyhow::Result<Option<ForwardGeocodeResponse>> {
+        let Some((lat, lon)) = query.split_once(',') else {
+            return Ok(None);
+        };
+        let (Ok(latitude), Ok(longitude)) = (lat.trim().parse(), lon.trim().parse()) else {
+            return Ok(None);
+        };
+        Ok(Some(ForwardGeocodeResponse {
+            latitude,
+            longitude,
+            confidence: 1.0,
+            address: RadarAddress {
+                formatted_address: Some(query.to_string()),
+                latitude: Some(latitude),
+                longitude: Some(longitude),
+                layer: Some(String::from("mock")),
+                ..Default::default()
+            },
+        }))
+    }
+}
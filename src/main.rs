@@ -1,4 +1,4 @@
-use std::{env, net::SocketAddr, sync::Arc};
+use std::{env, net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::{
     routing::{self, get, post},
@@ -7,10 +7,27 @@ use axum::{
 use sqlx::{Pool, Sqlite};
 use tokio::sync::broadcast;
 
+mod avl;
 mod db;
 mod extractors;
 mod features;
+mod geo;
+mod geocoder;
+mod metrics;
+mod oidc;
+mod permissions;
+mod presence;
 mod routes;
+mod signing;
+mod totp;
+mod webhooks;
+
+use permissions::{Permission, RequiredPermission};
+
+/// Declares the permission a route requires, checked by `extractors::Authorized`
+fn requires(permission: Permission) -> Extension<RequiredPermission> {
+    Extension(RequiredPermission(permission))
+}
 
 #[tokio::main]
 async fn main() {
@@ -35,6 +52,11 @@ async fn main() {
         option_env!("CARGO_PKG_VERSION").unwrap_or_else(|| "unknown")
     );
     tracing::info!("SIGNUPS_ENABLED = {}", *features::SIGNUPS_ENABLED);
+    tracing::info!("APRS_ENABLED = {}", *features::APRS_ENABLED);
+    tracing::info!(
+        "LOCATION_RETENTION_DAYS = {}",
+        *features::LOCATION_RETENTION_DAYS
+    );
 
     let sqlite_pool: Arc<Pool<Sqlite>> = Arc::new(
         Pool::connect(&env::var("DATABASE_URL").expect("Missing DATABASE_URL"))
@@ -42,23 +64,225 @@ async fn main() {
             .unwrap(),
     );
 
-    let (event_tx, _event_rx) = broadcast::channel::<routes::v0::stream::Event>(16);
+    if *features::LOCATION_RETENTION_DAYS > 0 {
+        let pool = sqlite_pool.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                let before = (chrono::Utc::now()
+                    - chrono::Duration::days(*features::LOCATION_RETENTION_DAYS))
+                .timestamp();
+                match db::resources::prune_locations(&pool, before).await {
+                    Ok(pruned) => tracing::debug!("pruned {} old resource locations", pruned),
+                    Err(e) => tracing::error!("failed to prune resource locations: {}", e),
+                }
+            }
+        });
+    }
+
+    match signing::KEYS.current_kid() {
+        Some(kid) => tracing::info!("JWT_SIGNING_KEY_ID = {}", kid),
+        None => tracing::warn!("signing tokens with JWT_SECRET, set JWT_KEYS_DIR to publish keys"),
+    }
+
+    {
+        let pool = sqlite_pool.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                let before = (chrono::Utc::now()
+                    - chrono::Duration::hours(*features::EVENT_RETENTION_HOURS))
+                .timestamp();
+                match db::events::prune(&pool, before).await {
+                    Ok(pruned) => tracing::debug!("pruned {} old events", pruned),
+                    Err(e) => tracing::error!("failed to prune events: {}", e),
+                }
+                let before = (chrono::Utc::now()
+                    - chrono::Duration::days(*features::WEBHOOK_LOG_RETENTION_DAYS))
+                .timestamp();
+                match db::webhooks::prune_deliveries(&pool, before).await {
+                    Ok(pruned) => tracing::debug!("pruned {} old webhook deliveries", pruned),
+                    Err(e) => tracing::error!("failed to prune webhook deliveries: {}", e),
+                }
+                let before = (chrono::Utc::now()
+                    - chrono::Duration::days(*features::REJECTION_RETENTION_DAYS))
+                .timestamp();
+                match db::devices::prune_rejections(&pool, before).await {
+                    Ok(pruned) => {
+                        tracing::debug!("pruned {} old rejected location updates", pruned)
+                    }
+                    Err(e) => tracing::error!("failed to prune rejected location updates: {}", e),
+                }
+                match geocoder::prune_cache(&pool).await {
+                    Ok(pruned) => {
+                        tracing::debug!("pruned {} expired geocode cache entries", pruned)
+                    }
+                    Err(e) => tracing::error!("failed to prune geocode cache: {}", e),
+                }
+            }
+        });
+    }
+    tokio::spawn(webhooks::run(sqlite_pool.clone()));
+
+    tracing::info!("GEOCODER = {}", geocoder::name());
+    let geocoder = geocoder::from_env(sqlite_pool.clone());
+
+    let oidc = oidc::OidcClient::from_env().map(Arc::new);
+    if let Some(oidc) = &oidc {
+        tracing::info!("OIDC_ISSUER = {}", oidc.issuer());
+    }
+
+    let (event_tx, _event_rx) =
+        broadcast::channel::<routes::v0::stream::Event>(*features::EVENT_BUFFER_SIZE);
     let event_tx = Arc::new(event_tx);
 
+    let presence = Arc::new(presence::Presence::default());
+    {
+        let presence = presence.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                presence.expire_editing();
+            }
+        });
+    }
+
+    if let Ok(bind_address) = env::var("NMEA_BIND_ADDRESS") {
+        let bind_address: SocketAddr = bind_address.parse().unwrap();
+        let (pool, event_tx) = (sqlite_pool.clone(), event_tx.clone());
+        tokio::spawn(async move {
+            if let Err(e) = avl::nmea::listen(bind_address, pool, event_tx).await {
+                tracing::error!("NMEA listener failed: {}", e);
+            }
+        });
+    }
+    if *features::APRS_ENABLED {
+        let server = env::var("APRS_IS_SERVER").expect("Missing APRS_IS_SERVER");
+        let callsign = env::var("APRS_IS_CALLSIGN").expect("Missing APRS_IS_CALLSIGN");
+        // -1 is a receive-only login, which is all we need
+        let passcode = env::var("APRS_IS_PASSCODE").unwrap_or_else(|_| String::from("-1"));
+        tokio::spawn(avl::aprs::run(
+            server,
+            callsign,
+            passcode,
+            sqlite_pool.clone(),
+            event_tx.clone(),
+        ));
+    } else if env::var("APRS_IS_SERVER").is_ok() {
+        tracing::warn!("APRS_IS_SERVER is set but APRS_ENABLED is not, ignoring APRS");
+    }
+
     let app = Router::new()
+        .route("/.well-known/jwks.json", get(routes::v0::login::get_jwks))
         .nest(
             "/api",
             Router::new().nest(
                 "/v0",
                 Router::new()
-                    .route("/stream", get(routes::v0::stream::stream))
+                    .route(
+                        "/stream",
+                        get(routes::v0::stream::stream)
+                            .layer(requires(Permission::SubscribeStream)),
+                    )
+                    .route(
+                        "/ws",
+                        get(routes::v0::ws::websocket).layer(requires(Permission::SubscribeStream)),
+                    )
+                    .route(
+                        "/presence",
+                        get(routes::v0::presence::get_presence)
+                            .put(routes::v0::presence::set_activity)
+                            .layer(requires(Permission::SubscribeStream)),
+                    )
                     .route("/features", get(routes::v0::features::get_features))
+                    .route(
+                        "/metrics",
+                        get(routes::v0::metrics::get_metrics)
+                            .layer(requires(Permission::ViewMetrics)),
+                    )
                     .nest(
                         "/users",
                         Router::new()
                             .route("/whoami", get(routes::v0::login::whoami))
                             .route("/login", post(routes::v0::login::login))
-                            .route("/signup", post(routes::v0::login::create_user)),
+                            .route("/signup", post(routes::v0::login::create_user))
+                            .route("/login/mfa", post(routes::v0::login::login_mfa))
+                            .route("/sso/start", get(routes::v0::sso::start))
+                            .route("/sso/callback", post(routes::v0::sso::callback))
+                            .route("/refresh", post(routes::v0::login::refresh))
+                            .route("/mfa/enroll", post(routes::v0::mfa::enroll))
+                            .route("/mfa/verify", post(routes::v0::mfa::verify))
+                            .route("/mfa/disable", post(routes::v0::mfa::disable))
+                            .route(
+                                "/mfa/recovery-codes",
+                                post(routes::v0::mfa::regenerate_recovery_codes),
+                            )
+                            .route(
+                                "/mfa/reset",
+                                post(routes::v0::mfa::reset)
+                                    .layer(requires(Permission::ManageUsers)),
+                            )
+                            .route(
+                                "/mfa/roles",
+                                get(routes::v0::mfa::get_required_roles)
+                                    .put(routes::v0::mfa::set_required_role)
+                                    .layer(requires(Permission::ManageUsers)),
+                            )
+                            .route("/logout", post(routes::v0::login::logout))
+                            .route("/logout/all", post(routes::v0::login::logout_everywhere))
+                            .route(
+                                "/sessions",
+                                get(routes::v0::login::get_sessions)
+                                    .delete(routes::v0::login::revoke_session),
+                            )
+                            .route(
+                                "/sessions/all",
+                                get(routes::v0::login::get_user_sessions)
+                                    .delete(routes::v0::login::revoke_user_sessions)
+                                    .layer(requires(Permission::ManageUsers)),
+                            )
+                            .route(
+                                "/",
+                                get(routes::v0::users::get_all_users)
+                                    .post(routes::v0::users::create)
+                                    .put(routes::v0::users::update)
+                                    .layer(requires(Permission::ManageUsers)),
+                            )
+                            .route(
+                                "/enabled",
+                                post(routes::v0::users::set_enabled)
+                                    .layer(requires(Permission::ManageUsers)),
+                            )
+                            .route(
+                                "/password",
+                                post(routes::v0::users::reset_password)
+                                    .layer(requires(Permission::ManageUsers)),
+                            )
+                            .route(
+                                "/password/code",
+                                post(routes::v0::users::create_reset_code)
+                                    .layer(requires(Permission::ManageUsers)),
+                            )
+                            .route("/password/change", post(routes::v0::login::change_password))
+                            .route("/password/reset", post(routes::v0::login::reset_password))
+                            .route(
+                                "/lockouts",
+                                get(routes::v0::users::get_login_throttles)
+                                    .layer(requires(Permission::ManageUsers)),
+                            )
+                            .route(
+                                "/unlock",
+                                post(routes::v0::users::unlock)
+                                    .layer(requires(Permission::ManageUsers)),
+                            )
+                            .route(
+                                "/audit",
+                                get(routes::v0::users::get_audit_log)
+                                    .layer(requires(Permission::ManageUsers)),
+                            ),
                     )
                     .nest(
                         "/jobs",
@@ -66,36 +290,171 @@ async fn main() {
                             .route(
                                 "/",
                                 get(routes::v0::jobs::get_all_jobs)
-                                    .post(routes::v0::jobs::create_job),
+                                    .layer(requires(Permission::ViewJobs))
+                                    .merge(
+                                        post(routes::v0::jobs::create_job)
+                                            .layer(requires(Permission::CreateJobs)),
+                                    ),
+                            )
+                            .route(
+                                "/comments",
+                                post(routes::v0::jobs::add_comment)
+                                    .layer(requires(Permission::CommentOnJobs)),
+                            )
+                            .route(
+                                "/close",
+                                post(routes::v0::jobs::close_job)
+                                    .layer(requires(Permission::ManageJobs)),
+                            )
+                            .route(
+                                "/priority",
+                                post(routes::v0::jobs::set_priority)
+                                    .layer(requires(Permission::ManageJobs)),
                             )
-                            .route("/comments", post(routes::v0::jobs::add_comment))
-                            .route("/close", post(routes::v0::jobs::close_job)),
+                            .route(
+                                "/location",
+                                post(routes::v0::jobs::set_location)
+                                    .layer(requires(Permission::ManageJobs)),
+                            )
+                            .route(
+                                "/status",
+                                get(routes::v0::jobs::get_status_history)
+                                    .layer(requires(Permission::ViewJobs))
+                                    .merge(
+                                        post(routes::v0::jobs::set_status)
+                                            .layer(requires(Permission::ManageJobs)),
+                                    ),
+                            ),
+                    )
+                    .route(
+                        "/naturecodes",
+                        get(routes::v0::nature_codes::get_all_nature_codes)
+                            .layer(requires(Permission::ViewJobs))
+                            .merge(
+                                post(routes::v0::nature_codes::create)
+                                    .put(routes::v0::nature_codes::update)
+                                    .delete(routes::v0::nature_codes::disable)
+                                    .layer(requires(Permission::ManageNatureCodes)),
+                            ),
                     )
                     .route(
                         "/resources",
                         get(routes::v0::resources::get_all_resources)
-                            .post(routes::v0::resources::create),
+                            .layer(requires(Permission::ViewResources))
+                            .merge(
+                                post(routes::v0::resources::create)
+                                    .layer(requires(Permission::ManageResources)),
+                            ),
+                    )
+                    .route(
+                        "/resources/crew",
+                        get(routes::v0::resources::get_crew)
+                            .layer(requires(Permission::ViewResources))
+                            .merge(
+                                post(routes::v0::resources::add_crew)
+                                    .delete(routes::v0::resources::remove_crew)
+                                    .layer(requires(Permission::ManageResources)),
+                            ),
                     )
                     .route(
                         "/resources/inservice",
-                        post(routes::v0::resources::set_in_service),
+                        post(routes::v0::resources::set_in_service)
+                            .layer(requires(Permission::UpdateResourceStatus)),
+                    )
+                    .route(
+                        "/resources/status",
+                        get(routes::v0::resources::get_status_history)
+                            .layer(requires(Permission::ViewResources))
+                            .merge(
+                                post(routes::v0::resources::set_status)
+                                    .layer(requires(Permission::UpdateResourceStatus)),
+                            ),
+                    )
+                    .route(
+                        "/assignments/acknowledge",
+                        post(routes::v0::resources::acknowledge)
+                            .layer(requires(Permission::UpdateResourceStatus)),
+                    )
+                    .route(
+                        "/resources/track",
+                        get(routes::v0::resources::get_track)
+                            .layer(requires(Permission::ViewTracks)),
+                    )
+                    .route(
+                        "/resources/recommend",
+                        get(routes::v0::resources::recommend)
+                            .layer(requires(Permission::ViewResources)),
+                    )
+                    .route(
+                        "/resources/location",
+                        post(routes::v0::resources::set_resource_location),
+                    )
+                    .route(
+                        "/avl/osmand",
+                        get(routes::v0::avl::osmand).post(routes::v0::avl::osmand),
+                    )
+                    .route(
+                        "/apikeys",
+                        get(routes::v0::api_keys::get_all_api_keys)
+                            .post(routes::v0::api_keys::create)
+                            .delete(routes::v0::api_keys::revoke)
+                            .layer(requires(Permission::ManageApiKeys)),
+                    )
+                    .nest(
+                        "/webhooks",
+                        Router::new()
+                            .route(
+                                "/",
+                                get(routes::v0::webhooks::get_all_webhooks)
+                                    .post(routes::v0::webhooks::create)
+                                    .put(routes::v0::webhooks::update)
+                                    .delete(routes::v0::webhooks::delete),
+                            )
+                            .route("/deliveries", get(routes::v0::webhooks::get_deliveries))
+                            .route("/deadletters", get(routes::v0::webhooks::get_dead_letters))
+                            .route("/redeliver", post(routes::v0::webhooks::redeliver))
+                            .route_layer(requires(Permission::ManageWebhooks)),
+                    )
+                    .nest(
+                        "/devices",
+                        Router::new()
+                            .route(
+                                "/",
+                                get(routes::v0::devices::get_all_devices)
+                                    .post(routes::v0::devices::create)
+                                    .delete(routes::v0::devices::revoke),
+                            )
+                            .route("/rotate", post(routes::v0::devices::rotate))
+                            .route("/rejections", get(routes::v0::devices::get_rejections))
+                            .route_layer(requires(Permission::ManageDevices)),
                     )
-                    .route("/resources/location", post(routes::v0::resources::set_resource_location))
                     .route(
                         "/assignments",
                         get(routes::v0::resources::get_assignments_for_job)
-                            .post(routes::v0::resources::assign)
-                            .delete(routes::v0::resources::unassign),
+                            .layer(requires(Permission::ViewJobs))
+                            .merge(
+                                post(routes::v0::resources::assign)
+                                    .delete(routes::v0::resources::unassign)
+                                    .layer(requires(Permission::AssignResources)),
+                            ),
                     ),
             ),
         )
         .layer(Extension(sqlite_pool))
-        .layer(Extension(event_tx));
+        .layer(Extension(geocoder))
+        .layer(Extension(oidc))
+        .layer(Extension(event_tx))
+        .layer(Extension(presence));
 
     let bind_address: SocketAddr = env::var("BIND_ADDRESS")
         .unwrap_or_else(|_| String::from("0.0.0.0:8080"))
         .parse()
         .unwrap();
     let listener = tokio::net::TcpListener::bind(bind_address).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
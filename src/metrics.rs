@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_json::json;
+
+use crate::features;
+
+pub static GEOCODE_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+pub static GEOCODE_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+pub static EVENTS_PUBLISHED: AtomicU64 = AtomicU64::new(0);
+pub static EVENT_SUBSCRIBERS: AtomicU64 = AtomicU64::new(0);
+/// Events subscribers missed from the live buffer and had to catch up on
+pub static EVENT_SUBSCRIBER_LAGGED: AtomicU64 = AtomicU64::new(0);
+/// The furthest any subscriber has fallen behind at once
+pub static EVENT_SUBSCRIBER_MAX_LAG: AtomicU64 = AtomicU64::new(0);
+pub static EVENT_SUBSCRIBER_RESYNCS: AtomicU64 = AtomicU64::new(0);
+
+pub static WEBHOOK_DELIVERIES: AtomicU64 = AtomicU64::new(0);
+/// Failed attempts, including ones that will be retried
+pub static WEBHOOK_FAILURES: AtomicU64 = AtomicU64::new(0);
+pub static WEBHOOK_DEAD_LETTERS: AtomicU64 = AtomicU64::new(0);
+
+pub fn snapshot() -> serde_json::Value {
+    let hits = GEOCODE_CACHE_HITS.load(Ordering::Relaxed);
+    let misses = GEOCODE_CACHE_MISSES.load(Ordering::Relaxed);
+    let hit_rate = match hits + misses {
+        0 => 0.0,
+        total => hits as f64 / total as f64,
+    };
+
+    json!({
+        "geocodeCache": {
+            "hits": hits,
+            "misses": misses,
+            "hitRate": hit_rate,
+        },
+        "events": {
+            "published": EVENTS_PUBLISHED.load(Ordering::Relaxed),
+            "bufferSize": *features::EVENT_BUFFER_SIZE,
+            "subscribers": EVENT_SUBSCRIBERS.load(Ordering::Relaxed),
+            "laggedEvents": EVENT_SUBSCRIBER_LAGGED.load(Ordering::Relaxed),
+            "maxLag": EVENT_SUBSCRIBER_MAX_LAG.load(Ordering::Relaxed),
+            "resyncs": EVENT_SUBSCRIBER_RESYNCS.load(Ordering::Relaxed),
+        },
+        "webhooks": {
+            "delivered": WEBHOOK_DELIVERIES.load(Ordering::Relaxed),
+            "failedAttempts": WEBHOOK_FAILURES.load(Ordering::Relaxed),
+            "deadLetters": WEBHOOK_DEAD_LETTERS.load(Ordering::Relaxed),
+        },
+    })
+}
@@ -0,0 +1,633 @@
+//! OpenID Connect single sign-on with the authorization code flow and PKCE.
+//! The provider is found through discovery, and its ID tokens are checked
+//! against the keys it publishes before anyone is signed in.
+
+use std::env;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use lazy_static::lazy_static;
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+
+use crate::permissions::Role;
+
+lazy_static! {
+    static ref OIDC_ISSUER: Option<String> = env::var("OIDC_ISSUER").ok();
+    static ref OIDC_CLIENT_ID: Option<String> = env::var("OIDC_CLIENT_ID").ok();
+    static ref OIDC_CLIENT_SECRET: Option<String> = env::var("OIDC_CLIENT_SECRET").ok();
+    static ref OIDC_REDIRECT_URI: Option<String> = env::var("OIDC_REDIRECT_URI").ok();
+    static ref OIDC_SCOPES: String =
+        env::var("OIDC_SCOPES").unwrap_or_else(|_| String::from("openid email profile"));
+    /// The ID token claim holding the user's groups
+    static ref OIDC_ROLE_CLAIM: String =
+        env::var("OIDC_ROLE_CLAIM").unwrap_or_else(|_| String::from("groups"));
+    /// Comma-separated `group=role` pairs, like `cad-admins=admin,cad-dispatch=dispatcher`
+    static ref OIDC_ROLE_MAPPING: String = env::var("OIDC_ROLE_MAPPING").unwrap_or_default();
+    /// The role given to provisioned users whose groups don't map to one
+    static ref OIDC_DEFAULT_ROLE: String =
+        env::var("OIDC_DEFAULT_ROLE").unwrap_or_else(|_| String::from("read_only"));
+    /// A stalled provider fails the login rather than hanging it
+    static ref AGENT: ureq::Agent = ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(CONNECT_TIMEOUT_SECONDS))
+        .timeout_read(Duration::from_secs(READ_TIMEOUT_SECONDS))
+        .user_agent(concat!("integral/", env!("CARGO_PKG_VERSION")))
+        .build();
+}
+
+/// How long discovery documents and keys are cached for
+const PROVIDER_TTL_SECONDS: i64 = 60 * 60;
+const CONNECT_TIMEOUT_SECONDS: u64 = 5;
+const READ_TIMEOUT_SECONDS: u64 = 10;
+/// Allowed clock skew between us and the provider when checking ID tokens
+const LEEWAY_SECONDS: u64 = 60;
+
+#[derive(Deserialize, Debug, Clone)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Clone)]
+struct Provider {
+    metadata: ProviderMetadata,
+    jwks: JwkSet,
+    fetched_at: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Who the provider says signed in, taken from a validated ID token.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub name: Option<String>,
+    pub groups: Vec<String>,
+}
+
+/// The secrets for one login attempt, kept server-side between sending the
+/// user to the provider and them coming back.
+pub struct LoginAttempt {
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+impl LoginAttempt {
+    pub fn generate() -> Self {
+        Self {
+            state: random_string(),
+            nonce: random_string(),
+            code_verifier: random_string(),
+        }
+    }
+}
+
+fn random_string() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+pub struct OidcClient {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    scopes: String,
+    role_claim: String,
+    role_mapping: Vec<(String, Role)>,
+    default_role: Role,
+    provider: RwLock<Option<Provider>>,
+}
+
+impl OidcClient {
+    /// Builds a client from the environment, if SSO is configured.
+    pub fn from_env() -> Option<Self> {
+        let (Some(issuer), Some(client_id), Some(redirect_uri)) = (
+            OIDC_ISSUER.clone(),
+            OIDC_CLIENT_ID.clone(),
+            OIDC_REDIRECT_URI.clone(),
+        ) else {
+            return None;
+        };
+
+        let role_mapping = OIDC_ROLE_MAPPING
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .filter_map(|(group, role)| {
+                match serde_json::from_value::<Role>(Value::String(role.trim().to_string())) {
+                    Ok(role) => Some((group.trim().to_string(), role)),
+                    Err(_) => {
+                        tracing::warn!("ignoring OIDC role mapping to unknown role {}", role);
+                        None
+                    }
+                }
+            })
+            .collect();
+        let default_role = serde_json::from_value(Value::String(OIDC_DEFAULT_ROLE.clone()))
+            .unwrap_or_else(|_| {
+                tracing::warn!("unknown OIDC_DEFAULT_ROLE {}", *OIDC_DEFAULT_ROLE);
+                Role::default()
+            });
+
+        Some(Self {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret: OIDC_CLIENT_SECRET.clone(),
+            redirect_uri,
+            scopes: OIDC_SCOPES.clone(),
+            role_claim: OIDC_ROLE_CLAIM.clone(),
+            role_mapping,
+            default_role,
+            provider: RwLock::new(None),
+        })
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn default_role(&self) -> Role {
+        self.default_role
+    }
+
+    /// The role a user's groups map to. The first matching entry in the
+    /// mapping wins, so more privileged groups should be listed first.
+    pub fn map_role(&self, groups: &[String]) -> Option<Role> {
+        self.role_mapping
+            .iter()
+            .find(|(group, _)| groups.contains(group))
+            .map(|(_, role)| *role)
+    }
+
+    async fn provider(&self, refresh: bool) -> anyhow::Result<Provider> {
+        let now = chrono::Utc::now().timestamp();
+        if !refresh {
+            if let Some(provider) = self.provider.read().await.as_ref() {
+                if now - provider.fetched_at < PROVIDER_TTL_SECONDS {
+                    return Ok(provider.clone());
+                }
+            }
+        }
+
+        let issuer = self.issuer.clone();
+        let provider = tokio::task::spawn_blocking(move || -> anyhow::Result<Provider> {
+            let metadata: ProviderMetadata = AGENT
+                .get(&format!("{}/.well-known/openid-configuration", issuer))
+                .call()
+                .context("fetching discovery document")?
+                .into_json()?;
+            if metadata.issuer.trim_end_matches('/') != issuer {
+                bail!("discovery document is for issuer {}", metadata.issuer);
+            }
+            let jwks: JwkSet = AGENT
+                .get(&metadata.jwks_uri)
+                .call()
+                .context("fetching signing keys")?
+                .into_json()?;
+            Ok(Provider {
+                metadata,
+                jwks,
+                fetched_at: now,
+            })
+        })
+        .await??;
+
+        *self.provider.write().await = Some(provider.clone());
+        Ok(provider)
+    }
+
+    /// Where to send the user to sign in with the provider.
+    pub async fn authorization_url(&self, attempt: &LoginAttempt) -> anyhow::Result<String> {
+        let provider = self.provider(false).await?;
+        let mut url = url::Url::parse(&provider.metadata.authorization_endpoint)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("scope", &self.scopes)
+            .append_pair("state", &attempt.state)
+            .append_pair("nonce", &attempt.nonce)
+            .append_pair("code_challenge", &pkce_challenge(&attempt.code_verifier))
+            .append_pair("code_challenge_method", "S256");
+        Ok(url.to_string())
+    }
+
+    /// Exchanges an authorization code for an ID token and validates it.
+    pub async fn exchange(&self, code: &str, attempt: &LoginAttempt) -> anyhow::Result<Identity> {
+        let provider = self.provider(false).await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code".to_string()),
+            ("code", code.to_string()),
+            ("redirect_uri", self.redirect_uri.clone()),
+            ("client_id", self.client_id.clone()),
+            ("code_verifier", attempt.code_verifier.clone()),
+        ];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret.clone()));
+        }
+        let token_endpoint = provider.metadata.token_endpoint.clone();
+        let tokens = tokio::task::spawn_blocking(move || -> anyhow::Result<TokenResponse> {
+            let form: Vec<(&str, &str)> = form.iter().map(|(k, v)| (*k, v.as_str())).collect();
+            Ok(AGENT
+                .post(&token_endpoint)
+                .send_form(&form)
+                .context("exchanging authorization code")?
+                .into_json()?)
+        })
+        .await??;
+
+        self.validate(provider, &tokens.id_token, &attempt.nonce)
+            .await
+    }
+
+    async fn validate(
+        &self,
+        mut provider: Provider,
+        id_token: &str,
+        nonce: &str,
+    ) -> anyhow::Result<Identity> {
+        let header = jsonwebtoken::decode_header(id_token)?;
+        // Only asymmetric algorithms, so a token can't be "signed" with the
+        // provider's public key
+        if !matches!(
+            header.alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+                | Algorithm::ES256
+                | Algorithm::ES384
+                | Algorithm::EdDSA
+        ) {
+            bail!("ID token uses unsupported algorithm {:?}", header.alg);
+        }
+
+        // An unknown key id usually means the provider has rotated its keys
+        let kid = header.kid.clone();
+        if let Some(kid) = &kid {
+            if provider.jwks.find(kid).is_none() {
+                provider = self.provider(true).await?;
+            }
+        }
+        let jwk = match &kid {
+            Some(kid) => provider.jwks.find(kid),
+            None if provider.jwks.keys.len() == 1 => provider.jwks.keys.first(),
+            None => None,
+        }
+        .ok_or_else(|| anyhow!("no signing key found for ID token"))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&provider.metadata.issuer]);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.leeway = LEEWAY_SECONDS;
+        let claims = jsonwebtoken::decode::<serde_json::Map<String, Value>>(
+            id_token,
+            &DecodingKey::from_jwk(jwk)?,
+            &validation,
+        )?
+        .claims;
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            bail!("ID token nonce does not match");
+        }
+
+        let string_claim = |name: &str| claims.get(name).and_then(Value::as_str).map(String::from);
+        let groups = match claims.get(&self.role_claim) {
+            Some(Value::Array(groups)) => groups
+                .iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect(),
+            Some(Value::String(group)) => vec![group.clone()],
+            _ => Vec::new(),
+        };
+        Ok(Identity {
+            subject: string_claim("sub").ok_or_else(|| anyhow!("ID token has no subject"))?,
+            email: string_claim("email"),
+            email_verified: claims
+                .get("email_verified")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            name: string_claim("name").or_else(|| string_claim("preferred_username")),
+            groups,
+        })
+    }
+}
+
+/// A local OpenID provider for tests.
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use jsonwebtoken::{
+        jwk::{
+            AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+            EllipticCurveKeyType, Jwk, KeyAlgorithm, PublicKeyUse,
+        },
+        EncodingKey, Header,
+    };
+    use p256::{elliptic_curve::sec1::ToEncodedPoint, pkcs8::EncodePrivateKey};
+    use serde_json::json;
+
+    use super::*;
+
+    pub(crate) const CLIENT_ID: &str = "integral";
+
+    /// A P-256 signing key and the JWK for it.
+    pub(crate) fn signing_key(kid: &str) -> (EncodingKey, Jwk) {
+        let key = p256::SecretKey::random(&mut rand::thread_rng());
+        let der = key.to_pkcs8_der().unwrap();
+        let point = key.public_key().to_encoded_point(false);
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(KeyAlgorithm::ES256),
+                key_id: Some(kid.to_string()),
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: EllipticCurveKeyType::EC,
+                curve: EllipticCurve::P256,
+                x: URL_SAFE_NO_PAD.encode(point.x().unwrap()),
+                y: URL_SAFE_NO_PAD.encode(point.y().unwrap()),
+            }),
+        };
+        (EncodingKey::from_ec_der(der.as_bytes()), jwk)
+    }
+
+    /// An OpenID provider on a local port, serving discovery, its keys, and a
+    /// token endpoint that answers with whatever ID token it's been given.
+    pub(crate) struct MockIssuer {
+        pub(crate) url: String,
+        jwks: Arc<Mutex<JwkSet>>,
+        pub(crate) id_token: Arc<Mutex<String>>,
+        /// Form bodies posted to the token endpoint
+        pub(crate) token_requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockIssuer {
+        pub(crate) fn start(claimed_issuer: Option<&str>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let issuer = Self {
+                url: url.clone(),
+                jwks: Arc::new(Mutex::new(JwkSet { keys: Vec::new() })),
+                id_token: Arc::new(Mutex::new(String::new())),
+                token_requests: Arc::new(Mutex::new(Vec::new())),
+            };
+
+            let discovery = json!({
+                "issuer": claimed_issuer.unwrap_or(&url),
+                "authorization_endpoint": format!("{}/authorize", url),
+                "token_endpoint": format!("{}/token", url),
+                "jwks_uri": format!("{}/jwks", url),
+            });
+            let (jwks, id_token, token_requests) = (
+                issuer.jwks.clone(),
+                issuer.id_token.clone(),
+                issuer.token_requests.clone(),
+            );
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+                    let mut content_length = 0;
+                    loop {
+                        let mut header = String::new();
+                        reader.read_line(&mut header).unwrap();
+                        if header.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = header.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                content_length = value.trim().parse().unwrap();
+                            }
+                        }
+                    }
+                    let mut body = vec![0; content_length];
+                    reader.read_exact(&mut body).unwrap();
+
+                    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+                    let response = match path {
+                        "/.well-known/openid-configuration" => discovery.clone(),
+                        "/jwks" => json!(*jwks.lock().unwrap()),
+                        "/token" => {
+                            token_requests
+                                .lock()
+                                .unwrap()
+                                .push(String::from_utf8(body).unwrap());
+                            json!({"id_token": *id_token.lock().unwrap(), "token_type": "Bearer"})
+                        }
+                        _ => json!({"error": "not found"}),
+                    }
+                    .to_string();
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        response.len(),
+                        response
+                    )
+                    .unwrap();
+                }
+            });
+            issuer
+        }
+
+        pub(crate) fn client(&self) -> OidcClient {
+            OidcClient {
+                issuer: self.url.clone(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: Some(String::from("client secret")),
+                redirect_uri: String::from("https://cad.example.com/sso/callback"),
+                scopes: String::from("openid email profile"),
+                role_claim: String::from("groups"),
+                role_mapping: vec![
+                    (String::from("cad-admins"), Role::Admin),
+                    (String::from("cad-dispatch"), Role::Dispatcher),
+                ],
+                default_role: Role::ReadOnly,
+                provider: RwLock::new(None),
+            }
+        }
+
+        pub(crate) fn publish(&self, jwk: &Jwk) {
+            self.jwks.lock().unwrap().keys.push(jwk.clone());
+        }
+
+        /// Signs an ID token for the token endpoint to hand out next.
+        pub(crate) fn issue(&self, key: &EncodingKey, kid: &str, claims: Value) {
+            let mut header = Header::new(Algorithm::ES256);
+            header.kid = Some(kid.to_string());
+            *self.id_token.lock().unwrap() = jsonwebtoken::encode(&header, &claims, key).unwrap();
+        }
+
+        pub(crate) fn claims(&self, nonce: &str) -> Value {
+            let now = chrono::Utc::now().timestamp();
+            json!({
+                "iss": self.url,
+                "aud": CLIENT_ID,
+                "sub": "248289761001",
+                "iat": now,
+                "exp": now + 300,
+                "nonce": nonce,
+                "email": "jane@example.com",
+                "email_verified": true,
+                "name": "Jane Doe",
+                "groups": ["staff", "cad-dispatch"],
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    use super::mock::*;
+    use super::*;
+
+    #[tokio::test]
+    async fn signs_in_through_the_provider() {
+        let issuer = MockIssuer::start(None);
+        let (key, jwk) = signing_key("key-1");
+        issuer.publish(&jwk);
+        let client = issuer.client();
+        let attempt = LoginAttempt::generate();
+
+        let url = url::Url::parse(&client.authorization_url(&attempt).await.unwrap()).unwrap();
+        assert_eq!(url.path(), "/authorize");
+        let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["client_id"], CLIENT_ID);
+        assert_eq!(params["state"], attempt.state);
+        assert_eq!(params["nonce"], attempt.nonce);
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(
+            params["code_challenge"],
+            pkce_challenge(&attempt.code_verifier)
+        );
+
+        issuer.issue(&key, "key-1", issuer.claims(&attempt.nonce));
+        let identity = client.exchange("auth-code", &attempt).await.unwrap();
+        assert_eq!(identity.subject, "248289761001");
+        assert_eq!(identity.email.as_deref(), Some("jane@example.com"));
+        assert!(identity.email_verified);
+        assert_eq!(identity.name.as_deref(), Some("Jane Doe"));
+        assert_eq!(client.map_role(&identity.groups), Some(Role::Dispatcher));
+
+        let requests = issuer.token_requests.lock().unwrap();
+        let form: HashMap<_, _> = url::form_urlencoded::parse(requests[0].as_bytes())
+            .into_owned()
+            .collect();
+        assert_eq!(form["grant_type"], "authorization_code");
+        assert_eq!(form["code"], "auth-code");
+        assert_eq!(form["code_verifier"], attempt.code_verifier);
+        assert_eq!(form["client_secret"], "client secret");
+    }
+
+    #[tokio::test]
+    async fn picks_up_rotated_keys() {
+        let issuer = MockIssuer::start(None);
+        let (old_key, old_jwk) = signing_key("key-1");
+        issuer.publish(&old_jwk);
+        let client = issuer.client();
+        let attempt = LoginAttempt::generate();
+        issuer.issue(&old_key, "key-1", issuer.claims(&attempt.nonce));
+        client.exchange("auth-code", &attempt).await.unwrap();
+
+        // The cached keys don't have the new one, so they're fetched again
+        let (new_key, new_jwk) = signing_key("key-2");
+        issuer.publish(&new_jwk);
+        let attempt = LoginAttempt::generate();
+        issuer.issue(&new_key, "key-2", issuer.claims(&attempt.nonce));
+        client.exchange("auth-code", &attempt).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_id_tokens() {
+        let issuer = MockIssuer::start(None);
+        let (key, jwk) = signing_key("key-1");
+        issuer.publish(&jwk);
+        let client = issuer.client();
+        let attempt = LoginAttempt::generate();
+
+        let mut claims = issuer.claims("someone else's nonce");
+        issuer.issue(&key, "key-1", claims);
+        assert!(client.exchange("auth-code", &attempt).await.is_err());
+
+        claims = issuer.claims(&attempt.nonce);
+        claims["aud"] = json!("another-client");
+        issuer.issue(&key, "key-1", claims);
+        assert!(client.exchange("auth-code", &attempt).await.is_err());
+
+        claims = issuer.claims(&attempt.nonce);
+        claims["iss"] = json!("https://evil.example.com");
+        issuer.issue(&key, "key-1", claims);
+        assert!(client.exchange("auth-code", &attempt).await.is_err());
+
+        claims = issuer.claims(&attempt.nonce);
+        claims["exp"] = json!(chrono::Utc::now().timestamp() - 600);
+        issuer.issue(&key, "key-1", claims);
+        assert!(client.exchange("auth-code", &attempt).await.is_err());
+
+        // Signed by a key the provider never published
+        let (unknown_key, _) = signing_key("key-1");
+        issuer.issue(&unknown_key, "key-1", issuer.claims(&attempt.nonce));
+        assert!(client.exchange("auth-code", &attempt).await.is_err());
+
+        // A symmetric algorithm is refused before any key is tried
+        *issuer.id_token.lock().unwrap() = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &issuer.claims(&attempt.nonce),
+            &EncodingKey::from_secret(b"client secret"),
+        )
+        .unwrap();
+        assert!(client.exchange("auth-code", &attempt).await.is_err());
+
+        issuer.issue(&key, "key-1", issuer.claims(&attempt.nonce));
+        assert!(client.exchange("auth-code", &attempt).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_discovery_for_another_issuer() {
+        let issuer = MockIssuer::start(Some("https://evil.example.com"));
+        let client = issuer.client();
+        let err = client
+            .authorization_url(&LoginAttempt::generate())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("evil.example.com"));
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Supervisor,
+    Dispatcher,
+    FieldUnit,
+    #[default]
+    ReadOnly,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    ViewJobs,
+    CommentOnJobs,
+    CreateJobs,
+    ManageJobs,
+    ViewResources,
+    UpdateResourceStatus,
+    AssignResources,
+    ManageResources,
+    ViewTracks,
+    ManageNatureCodes,
+    ManageDevices,
+    ManageUsers,
+    SubscribeStream,
+    ViewMetrics,
+    ManageApiKeys,
+    ManageWebhooks,
+    /// Only granted to API keys; people report locations through devices
+    PostLocations,
+}
+
+impl Role {
+    pub fn permissions(self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Admin => &[
+                ViewJobs,
+                CommentOnJobs,
+                CreateJobs,
+                ManageJobs,
+                ViewResources,
+                UpdateResourceStatus,
+                AssignResources,
+                ManageResources,
+                ViewTracks,
+                ManageNatureCodes,
+                ManageDevices,
+                ManageUsers,
+                SubscribeStream,
+                ViewMetrics,
+                ManageApiKeys,
+                ManageWebhooks,
+            ],
+            Role::Supervisor => &[
+                ViewJobs,
+                CommentOnJobs,
+                CreateJobs,
+                ManageJobs,
+                ViewResources,
+                UpdateResourceStatus,
+                AssignResources,
+                ManageResources,
+                ViewTracks,
+                ManageNatureCodes,
+                SubscribeStream,
+                ViewMetrics,
+            ],
+            Role::Dispatcher => &[
+                ViewJobs,
+                CommentOnJobs,
+                CreateJobs,
+                ManageJobs,
+                ViewResources,
+                UpdateResourceStatus,
+                AssignResources,
+                SubscribeStream,
+            ],
+            Role::FieldUnit => &[
+                ViewJobs,
+                CommentOnJobs,
+                ViewResources,
+                UpdateResourceStatus,
+                SubscribeStream,
+            ],
+            Role::ReadOnly => &[ViewJobs, ViewResources, SubscribeStream],
+        }
+    }
+
+    pub fn has(self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Role::Admin => "admin",
+            Role::Supervisor => "supervisor",
+            Role::Dispatcher => "dispatcher",
+            Role::FieldUnit => "field_unit",
+            Role::ReadOnly => "read_only",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// What an API key is allowed to do. Each scope grants a single permission,
+/// regardless of the role of the user who made the key.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    ReadJobs,
+    CreateJobs,
+    PostLocations,
+    SubscribeStream,
+}
+
+impl ApiKeyScope {
+    pub fn permission(self) -> Permission {
+        match self {
+            ApiKeyScope::ReadJobs => Permission::ViewJobs,
+            ApiKeyScope::CreateJobs => Permission::CreateJobs,
+            ApiKeyScope::PostLocations => Permission::PostLocations,
+            ApiKeyScope::SubscribeStream => Permission::SubscribeStream,
+        }
+    }
+}
+
+/// The permission a route requires, attached to it in `main.rs` and checked
+/// by the [`Authorized`](crate::extractors::Authorized) extractor.
+#[derive(Debug, Clone, Copy)]
+pub struct RequiredPermission(pub Permission);
@@ -0,0 +1,176 @@
+//! Who's connected to the event stream and which job each of them has open,
+//! so two dispatchers working the same call can see each other. Presence is
+//! only kept in memory, and rebuilds itself as consoles reconnect after a
+//! restart.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::db::users::User;
+
+/// How long someone shows as editing after they last typed. Consoles repeat
+/// the update while typing continues.
+pub const EDITING_TTL_SECONDS: i64 = 15;
+
+/// Presence changes that can be buffered for a slow subscriber before it's
+/// sent the whole list instead
+const BUFFER_SIZE: usize = 256;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserPresence {
+    pub user_id: String,
+    pub display_name: String,
+    pub online: bool,
+    /// When the user's earliest open connection was made
+    pub since: i64,
+    /// The job open on the user's console
+    pub job_id: Option<String>,
+    /// Whether they're typing in that job
+    pub editing: bool,
+}
+
+struct Entry {
+    display_name: String,
+    connections: usize,
+    since: i64,
+    job_id: Option<String>,
+    editing_until: Option<i64>,
+}
+
+impl Entry {
+    fn presence(&self, user_id: &str, now: i64) -> UserPresence {
+        UserPresence {
+            user_id: user_id.to_string(),
+            display_name: self.display_name.clone(),
+            online: true,
+            since: self.since,
+            job_id: self.job_id.clone(),
+            editing: self.editing_until.is_some_and(|until| until > now),
+        }
+    }
+}
+
+pub struct Presence {
+    users: Mutex<HashMap<String, Entry>>,
+    tx: broadcast::Sender<UserPresence>,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        let (tx, _rx) = broadcast::channel(BUFFER_SIZE);
+        Self {
+            users: Mutex::new(HashMap::new()),
+            tx,
+        }
+    }
+}
+
+impl Presence {
+    /// Changes to anyone's presence, as they happen.
+    pub fn subscribe(&self) -> broadcast::Receiver<UserPresence> {
+        self.tx.subscribe()
+    }
+
+    /// Everyone who's online, by name.
+    pub fn list(&self) -> Vec<UserPresence> {
+        let now = chrono::Utc::now().timestamp();
+        let mut list = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(user_id, entry)| entry.presence(user_id, now))
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        list
+    }
+
+    /// Shows a user as online until the returned handle is dropped. A user
+    /// with several consoles stays online until the last one closes. API keys
+    /// aren't people, so they don't show up.
+    pub fn connect(self: &Arc<Self>, user: &User) -> Option<Connected> {
+        if user.scopes.is_some() {
+            return None;
+        }
+        let mut users = self.users.lock().unwrap();
+        let entry = users.entry(user.id.clone()).or_insert_with(|| Entry {
+            display_name: user.display_name.clone(),
+            connections: 0,
+            since: chrono::Utc::now().timestamp(),
+            job_id: None,
+            editing_until: None,
+        });
+        entry.connections += 1;
+        if entry.connections == 1 {
+            self.tx
+                .send(entry.presence(&user.id, chrono::Utc::now().timestamp()))
+                .ok();
+        }
+        Some(Connected {
+            presence: self.clone(),
+            user_id: user.id.clone(),
+        })
+    }
+
+    fn disconnect(&self, user_id: &str) {
+        let mut users = self.users.lock().unwrap();
+        let Some(entry) = users.get_mut(user_id) else {
+            return;
+        };
+        entry.connections -= 1;
+        if entry.connections == 0 {
+            let mut presence = entry.presence(user_id, chrono::Utc::now().timestamp());
+            presence.online = false;
+            presence.job_id = None;
+            presence.editing = false;
+            users.remove(user_id);
+            self.tx.send(presence).ok();
+        }
+    }
+
+    /// Sets the job open on a user's console, and whether they're typing in
+    /// it. Returns false if they aren't connected.
+    pub fn set_activity(&self, user_id: &str, job_id: Option<String>, editing: bool) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        let mut users = self.users.lock().unwrap();
+        let Some(entry) = users.get_mut(user_id) else {
+            return false;
+        };
+        let before = entry.presence(user_id, now);
+        entry.editing_until = (editing && job_id.is_some()).then_some(now + EDITING_TTL_SECONDS);
+        entry.job_id = job_id;
+        let after = entry.presence(user_id, now);
+        if after != before {
+            self.tx.send(after).ok();
+        }
+        true
+    }
+
+    /// Stops showing people as editing once they've stopped typing.
+    pub fn expire_editing(&self) {
+        let now = chrono::Utc::now().timestamp();
+        let mut users = self.users.lock().unwrap();
+        for (user_id, entry) in users.iter_mut() {
+            if entry.editing_until.is_some_and(|until| until <= now) {
+                entry.editing_until = None;
+                self.tx.send(entry.presence(user_id, now)).ok();
+            }
+        }
+    }
+}
+
+/// One open connection for a user, counted until it's dropped.
+pub struct Connected {
+    presence: Arc<Presence>,
+    user_id: String,
+}
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        self.presence.disconnect(&self.user_id);
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{db, extractors::Authorized, permissions::ApiKeyScope};
+
+pub async fn get_all_api_keys(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    match db::api_keys::list(&pool).await {
+        Ok(keys) => (StatusCode::OK, Json(json!(keys))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ApiKeyCreationRequest {
+    name: String,
+    scopes: Vec<ApiKeyScope>,
+    expires_at: Option<i64>,
+}
+pub async fn create(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<ApiKeyCreationRequest>,
+) -> impl IntoResponse {
+    if req.scopes.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "an api key needs at least one scope"})),
+        );
+    }
+    if req
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= chrono::Utc::now().timestamp())
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "expiresAt must be in the future"})),
+        );
+    }
+
+    match db::api_keys::create(&pool, &req.name, &req.scopes, req.expires_at, &user.id).await {
+        Ok((key, secret)) => (StatusCode::OK, Json(json!({"apiKey": key, "key": secret}))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ApiKeyRequest {
+    id: String,
+}
+pub async fn revoke(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<ApiKeyRequest>,
+) -> impl IntoResponse {
+    match db::api_keys::revoke(&pool, &req.id, &user.id).await {
+        Ok(()) => (StatusCode::OK, Json(json!({}))),
+        Err(sqlx::Error::RowNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that api key does not exist or has been revoked"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
@@ -0,0 +1,73 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+use tokio::sync::broadcast;
+
+use crate::avl::{self, IngestError};
+
+use super::stream::Event;
+
+/// Location updates in the OsmAnd format, as sent by Traccar Client and
+/// similar apps. Clients may send the parameters with either GET or POST, and
+/// authenticate with their device token in the `token` parameter or the
+/// "Authorization" header.
+pub async fn osmand(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let remote_addr = remote_addr.ip().to_string();
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(|header| header.replace("Bearer ", ""))
+        .or_else(|| params.get("token").cloned());
+    let Some(token) = token else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "missing device token"})),
+        );
+    };
+    let Some(fix) = avl::osmand::parse(&params) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "missing position"})),
+        );
+    };
+
+    let ingested =
+        match avl::device_by_token(&pool, &token, "osmand", Some(remote_addr.clone())).await {
+            Ok(device) => {
+                avl::ingest(&pool, &event_tx, &device, fix, "osmand", Some(remote_addr)).await
+            }
+            Err(e) => Err(e),
+        };
+    match ingested {
+        Ok(res) => (StatusCode::OK, Json(json!(res))),
+        Err(IngestError::InvalidCoordinates) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "invalid coordinates"})),
+        ),
+        Err(IngestError::UnknownDevice) => (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "invalid device token"})),
+        ),
+        Err(IngestError::WrongDevice) => (
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "id does not match the device token"})),
+        ),
+        Err(IngestError::Database(e)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{extract::Query, http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{db, extractors::Authorized};
+
+pub async fn get_all_devices(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Query(params): Query<HashMap<String, String>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    let resource_id = params.get("resourceId").map(String::as_str);
+    match db::devices::list(&pool, resource_id).await {
+        Ok(devices) => (StatusCode::OK, Json(json!(devices))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DeviceCreationRequest {
+    resource_id: String,
+    name: String,
+    external_id: Option<String>,
+}
+pub async fn create(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<DeviceCreationRequest>,
+) -> impl IntoResponse {
+    let device = db::devices::create(
+        &pool,
+        &req.resource_id,
+        &req.name,
+        req.external_id,
+        &user.id,
+    )
+    .await;
+    match device {
+        Ok((device, token)) => (
+            StatusCode::OK,
+            Json(json!({"device": device, "token": token})),
+        ),
+        Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "that resource does not exist"})),
+        ),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "another device already uses that external id"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DeviceRequest {
+    id: String,
+}
+pub async fn rotate(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(_user): Authorized,
+    Json(req): Json<DeviceRequest>,
+) -> impl IntoResponse {
+    match db::devices::rotate(&pool, &req.id).await {
+        Ok((device, token)) => (
+            StatusCode::OK,
+            Json(json!({"device": device, "token": token})),
+        ),
+        Err(sqlx::Error::RowNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that device does not exist or has been revoked"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+pub async fn revoke(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<DeviceRequest>,
+) -> impl IntoResponse {
+    match db::devices::revoke(&pool, &req.id, &user.id).await {
+        Ok(res) => (StatusCode::OK, Json(json!(res))),
+        Err(sqlx::Error::RowNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that device does not exist or has been revoked"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+pub async fn get_rejections(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    match db::devices::list_rejections(&pool).await {
+        Ok(rejections) => (StatusCode::OK, Json(json!(rejections))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
@@ -1,14 +1,20 @@
 use crate::extractors::Json;
 use crate::features;
-use axum::{http::StatusCode, response::IntoResponse};
+use crate::oidc::OidcClient;
+use axum::{http::StatusCode, response::IntoResponse, Extension};
 use serde_json::json;
+use std::sync::Arc;
 
-pub async fn get_features() -> impl IntoResponse {
+pub async fn get_features(
+    Extension(oidc): Extension<Option<Arc<OidcClient>>>,
+) -> impl IntoResponse {
     (
         StatusCode::OK,
         Json(json!({
             "version": option_env!("CARGO_PKG_VERSION").unwrap_or_else(|| "unknown"),
             "signup": *features::SIGNUPS_ENABLED,
+            "sso": oidc.is_some(),
+            "ssoJitProvisioning": *features::SSO_JIT_PROVISIONING,
         })),
     )
 }
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use axum::{extract::Query, http::StatusCode, response::IntoResponse, Extension, Json};
 use serde::Deserialize;
@@ -6,14 +6,28 @@ use serde_json::json;
 use sqlx::{Pool, Sqlite};
 use tokio::sync::broadcast;
 
-use crate::{db, extractors::Jwt};
+use crate::{
+    db::{
+        self,
+        jobs::{Job, JobFilter, JobStatus, JobStatusChange, JobStatusError, NewJob},
+    },
+    extractors::Authorized,
+    geo,
+    geocoder::Geocoder,
+};
 
-use super::stream::Event;
+use super::stream::{self, ChangeKind, Event};
+
+/// How long creating a job waits on the geocoder before answering without
+/// coordinates. The job is saved and published before geocoding starts, and
+/// is updated once the geocoder answers.
+const GEOCODE_WAIT: Duration = Duration::from_secs(2);
 
 pub async fn get_all_jobs(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
     Query(params): Query<HashMap<String, String>>,
-    Jwt(_user): Jwt,
+    Query(filter): Query<JobFilter>,
+    Authorized(_user): Authorized,
 ) -> impl IntoResponse {
     if let Some(id) = params.get("id") {
         let job = db::jobs::get_job_by_id(&pool, &id).await;
@@ -25,7 +39,7 @@ pub async fn get_all_jobs(
             ),
         }
     } else {
-        let jobs = db::jobs::get_all_jobs(&pool).await;
+        let jobs = db::jobs::get_all_jobs(&pool, &filter).await;
         match jobs {
             Ok(jobs) => (StatusCode::OK, Json(json!(jobs))),
             Err(e) => (
@@ -44,23 +58,86 @@ pub(crate) struct CreateJob {
     pub caller_name: Option<String>,
     pub caller_phone: Option<String>,
     pub comments: Option<Vec<String>>,
+    pub nature_code: Option<String>,
+    pub priority: Option<i64>,
+    /// Coordinates set by the dispatcher, which skip geocoding the location
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub cross_street: Option<String>,
+    pub location_notes: Option<String>,
+}
+
+fn valid_priority(priority: i64) -> bool {
+    (1..=5).contains(&priority)
 }
 
 pub async fn create_job(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
     Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
-    Jwt(user): Jwt,
+    Extension(geocoder): Extension<Arc<dyn Geocoder>>,
+    Authorized(user): Authorized,
     Json(data): Json<CreateJob>,
 ) -> impl IntoResponse {
-    let created_job = db::jobs::create_job(
-        &pool,
-        &data.synopsis,
-        data.location,
-        data.caller_name,
-        data.caller_phone,
-        &user.id,
-    )
-    .await;
+    let mut priority = data.priority;
+    if let Some(code) = &data.nature_code {
+        match db::nature_codes::get(&pool, code).await {
+            Ok(Some(nature_code)) if nature_code.enabled => {
+                priority = priority.or(Some(nature_code.default_priority));
+            }
+            Ok(_) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "unknown nature code"})),
+                )
+            }
+            Err(e) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!(e.to_string())),
+                )
+            }
+        }
+    }
+    if priority.is_some_and(|p| !valid_priority(p)) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "priority must be between 1 and 5"})),
+        );
+    }
+
+    let mut new_job = NewJob {
+        synopsis: data.synopsis,
+        location: data.location,
+        caller_name: data.caller_name,
+        caller_phone: data.caller_phone,
+        nature_code: data.nature_code,
+        priority,
+        cross_street: data.cross_street,
+        location_notes: data.location_notes,
+        ..Default::default()
+    };
+    let mut geocode_query = None;
+    match (data.latitude, data.longitude) {
+        (Some(lat), Some(lon)) => {
+            if !geo::valid_coordinates(lat, lon) {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "invalid coordinates"})),
+                );
+            }
+            new_job.latitude = Some(lat);
+            new_job.longitude = Some(lon);
+            new_job.location_overridden = true;
+        }
+        (None, None) => geocode_query = new_job.location.clone(),
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "latitude and longitude must be sent together"})),
+            )
+        }
+    }
+    let created_job = db::jobs::create_job(&pool, new_job, &user.id).await;
     if let Err(e) = created_job {
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -80,10 +157,38 @@ pub async fn create_job(
 
     let job = db::jobs::get_job_by_id(&pool, &created_job.id).await;
 
-    event_tx.send(Event::Job(created_job.id.clone())).ok();
-
     match job {
-        Ok(job) => (StatusCode::OK, Json(json!(job))),
+        Ok(Some(job)) => {
+            stream::publish(
+                &pool,
+                &event_tx,
+                ChangeKind::Created,
+                &job.id,
+                stream::Entity::Job(job.clone()),
+            )
+            .await;
+            let mut body = json!(job);
+            if let Some(location) = geocode_query {
+                let geocoding = tokio::spawn(geocode_job(
+                    pool.clone(),
+                    event_tx.clone(),
+                    geocoder.clone(),
+                    job.id.clone(),
+                    location,
+                ));
+                // The job is created either way, but the dispatcher needs to
+                // know if it wasn't placed on the map
+                match tokio::time::timeout(GEOCODE_WAIT, geocoding).await {
+                    Ok(Ok(Ok(Some(job)))) => body = json!(job),
+                    Ok(Ok(Ok(None))) => {}
+                    Ok(Ok(Err(error))) => body["geocodeError"] = json!(error),
+                    Ok(Err(e)) => body["geocodeError"] = json!(e.to_string()),
+                    Err(_) => body["geocodePending"] = json!(true),
+                }
+            }
+            (StatusCode::OK, Json(body))
+        }
+        Ok(None) => (StatusCode::OK, Json(json!(null))),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!(e.to_string())),
@@ -91,6 +196,50 @@ pub async fn create_job(
     }
 }
 
+/// Geocodes a new job's location and places it on the map, publishing the
+/// change. Returns the updated job, or `None` if there was no match or a
+/// dispatcher placed it by hand first.
+async fn geocode_job(
+    pool: Arc<Pool<Sqlite>>,
+    event_tx: Arc<broadcast::Sender<Event>>,
+    geocoder: Arc<dyn Geocoder>,
+    job_id: String,
+    location: String,
+) -> Result<Option<Job>, String> {
+    let found = match geocoder.forward(&location).await {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            tracing::debug!("no geocoding match for {:?}", location);
+            return Ok(None);
+        }
+        Err(e) => {
+            tracing::warn!("failed to geocode {:?}: {}", location, e);
+            return Err(e.to_string());
+        }
+    };
+    let updated = db::jobs::set_geocoded_location(
+        &pool,
+        &job_id,
+        found.latitude,
+        found.longitude,
+        found.address,
+        found.confidence,
+    )
+    .await;
+    match updated {
+        Ok(Some(_)) => {}
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            tracing::error!("failed to save geocoded location for {}: {}", job_id, e);
+            return Err(e.to_string());
+        }
+    }
+    stream::publish_job(&pool, &event_tx, ChangeKind::Updated, &job_id).await;
+    db::jobs::get_job_by_id(&pool, &job_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct CreateComment {
@@ -101,15 +250,103 @@ pub(crate) struct CreateComment {
 pub async fn add_comment(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
     Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
-    Jwt(user): Jwt,
+    Authorized(user): Authorized,
     Json(data): Json<CreateComment>,
 ) -> impl IntoResponse {
     let created_comment = db::jobs::add_comment(&pool, &data.job_id, &data.comment, &user.id).await;
 
-    event_tx.send(Event::Job(data.job_id.clone())).ok();
-
     match created_comment {
-        Ok(c) => (StatusCode::OK, Json(json!(c))),
+        Ok(c) => {
+            stream::publish_job(&pool, &event_tx, ChangeKind::Updated, &data.job_id).await;
+            (StatusCode::OK, Json(json!(c)))
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SetJobLocation {
+    pub job_id: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub cross_street: Option<String>,
+    pub location_notes: Option<String>,
+}
+
+pub async fn set_location(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
+    Authorized(_user): Authorized,
+    Json(data): Json<SetJobLocation>,
+) -> impl IntoResponse {
+    if !geo::valid_coordinates(data.latitude, data.longitude) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "invalid coordinates"})),
+        );
+    }
+
+    let job = db::jobs::override_location(
+        &pool,
+        &data.job_id,
+        data.latitude,
+        data.longitude,
+        data.cross_street,
+        data.location_notes,
+    )
+    .await;
+
+    match job {
+        Ok(job) => {
+            stream::publish_job(&pool, &event_tx, ChangeKind::Updated, &data.job_id).await;
+            (StatusCode::OK, Json(json!(job)))
+        }
+        Err(sqlx::Error::RowNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that job does not exist"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SetJobPriority {
+    pub job_id: String,
+    pub priority: i64,
+}
+
+pub async fn set_priority(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
+    Authorized(user): Authorized,
+    Json(data): Json<SetJobPriority>,
+) -> impl IntoResponse {
+    if !valid_priority(data.priority) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "priority must be between 1 and 5"})),
+        );
+    }
+
+    let comment = db::jobs::set_priority(&pool, &data.job_id, data.priority, &user.id).await;
+
+    match comment {
+        Ok(c) => {
+            stream::publish_job(&pool, &event_tx, ChangeKind::Updated, &data.job_id).await;
+            (StatusCode::OK, Json(json!(c)))
+        }
+        Err(sqlx::Error::RowNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that job does not exist"})),
+        ),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!(e.to_string())),
@@ -120,17 +357,84 @@ pub async fn add_comment(
 pub async fn close_job(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
     Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
-    Jwt(user): Jwt,
+    Authorized(user): Authorized,
     Query(params): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
     if let Some(id) = params.get("id") {
-        let created_comment = db::jobs::close_job(&pool, &id, &user.id).await;
+        let assigned = assigned_resources(&pool, id).await;
+        let closed = db::jobs::close_job(&pool, id, &user.id).await;
+        if closed.is_ok() {
+            publish_closed(&pool, &event_tx, id, assigned).await;
+        }
+        status_change_response(closed)
+    } else {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "missing job id"})),
+        )
+    }
+}
 
-        event_tx.send(Event::Job(id.clone())).ok();
-        event_tx.send(Event::Resource(id.clone())).ok();
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SetJobStatus {
+    pub job_id: String,
+    pub status: JobStatus,
+}
 
-        match created_comment {
-            Ok(c) => (StatusCode::OK, Json(json!(c))),
+pub async fn set_status(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
+    Authorized(user): Authorized,
+    Json(data): Json<SetJobStatus>,
+) -> impl IntoResponse {
+    let assigned = assigned_resources(&pool, &data.job_id).await;
+    let change = db::jobs::set_status(&pool, &data.job_id, data.status, &user.id).await;
+    if change.is_ok() {
+        if data.status.is_terminal() {
+            publish_closed(&pool, &event_tx, &data.job_id, assigned).await;
+        } else {
+            stream::publish_job(&pool, &event_tx, ChangeKind::Updated, &data.job_id).await;
+        }
+    }
+    status_change_response(change)
+}
+
+/// Resources currently assigned to a job, which closing it will release.
+async fn assigned_resources(pool: &Pool<Sqlite>, job_id: &str) -> Vec<String> {
+    match db::assignments::get_assignments_for_job(pool, job_id).await {
+        Ok(assignments) => assignments
+            .into_iter()
+            .filter(|a| a.removed_at.is_none())
+            .map(|a| a.resource_id)
+            .collect(),
+        Err(e) => {
+            tracing::error!("failed to fetch assignments for job {}: {}", job_id, e);
+            Vec::new()
+        }
+    }
+}
+
+async fn publish_closed(
+    pool: &Pool<Sqlite>,
+    event_tx: &broadcast::Sender<Event>,
+    job_id: &str,
+    released: Vec<String>,
+) {
+    stream::publish_job(pool, event_tx, ChangeKind::Closed, job_id).await;
+    for resource_id in released {
+        stream::publish_resource(pool, event_tx, ChangeKind::Unassigned, &resource_id).await;
+    }
+}
+
+pub async fn get_status_history(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Query(params): Query<HashMap<String, String>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    if let Some(id) = params.get("id") {
+        match db::jobs::get_status_history(&pool, id).await {
+            Ok(history) => (StatusCode::OK, Json(json!(history))),
             Err(e) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!(e.to_string())),
@@ -143,3 +447,180 @@ pub async fn close_job(
         )
     }
 }
+
+fn status_change_response(
+    change: Result<JobStatusChange, JobStatusError>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    match change {
+        Ok(c) => (StatusCode::OK, Json(json!(c))),
+        Err(JobStatusError::NotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": JobStatusError::NotFound.to_string()})),
+        ),
+        Err(e @ JobStatusError::InvalidTransition { .. }) => {
+            (StatusCode::CONFLICT, Json(json!({"error": e.to_string()})))
+        }
+        Err(JobStatusError::Database(e)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::async_trait;
+    use serde_json::Value;
+    use tokio::sync::Notify;
+
+    use super::*;
+    use crate::db::{events::Entity, resources::GeocodeResponse, test_pool, users};
+    use crate::geocoder::{ForwardGeocodeResponse, MockGeocoder, Point};
+    use crate::permissions::Role;
+
+    /// Geocodes like `MockGeocoder`, once it's let go.
+    struct SlowGeocoder(Arc<Notify>);
+
+    #[async_trait]
+    impl Geocoder for SlowGeocoder {
+        async fn reverse(&self, points: &[Point]) -> anyhow::Result<Vec<Option<GeocodeResponse>>> {
+            MockGeocoder.reverse(points).await
+        }
+
+        async fn forward(&self, query: &str) -> anyhow::Result<Option<ForwardGeocodeResponse>> {
+            self.0.notified().await;
+            MockGeocoder.forward(query).await
+        }
+    }
+
+    fn new_job(location: &str, latitude: Option<f64>, longitude: Option<f64>) -> CreateJob {
+        CreateJob {
+            synopsis: String::from("Fall"),
+            location: Some(location.to_string()),
+            caller_name: None,
+            caller_phone: None,
+            comments: None,
+            nature_code: None,
+            priority: None,
+            latitude,
+            longitude,
+            cross_street: None,
+            location_notes: None,
+        }
+    }
+
+    async fn create(
+        pool: &Arc<Pool<Sqlite>>,
+        event_tx: &Arc<broadcast::Sender<Event>>,
+        geocoder: Arc<dyn Geocoder>,
+        job: CreateJob,
+    ) -> (StatusCode, Value) {
+        let user = match users::get_user_by_email(pool, "dispatch@example.com").await {
+            Ok(user) => user,
+            Err(_) => users::create_user(
+                pool,
+                "dispatch@example.com",
+                "password",
+                "Dispatch",
+                None,
+                Role::Dispatcher,
+                None,
+            )
+            .await
+            .unwrap(),
+        };
+        let response = create_job(
+            Extension(pool.clone()),
+            Extension(event_tx.clone()),
+            Extension(geocoder),
+            Authorized(user),
+            Json(job),
+        )
+        .await
+        .into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn places_new_jobs_on_the_map() {
+        let pool = Arc::new(test_pool().await);
+        let (event_tx, _) = broadcast::channel(16);
+        let event_tx = Arc::new(event_tx);
+
+        let (status, job) = create(
+            &pool,
+            &event_tx,
+            Arc::new(MockGeocoder),
+            new_job("43.08,-77.67", None, None),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(job["latitude"], json!(43.08));
+        assert_eq!(job["longitude"], json!(-77.67));
+        assert_eq!(job["locationOverridden"], json!(false));
+        assert!(job.get("geocodePending").is_none());
+    }
+
+    #[tokio::test]
+    async fn does_not_wait_long_for_the_geocoder() {
+        let pool = Arc::new(test_pool().await);
+        let (event_tx, mut events) = broadcast::channel(16);
+        let event_tx = Arc::new(event_tx);
+        let release = Arc::new(Notify::new());
+
+        let (status, job) = create(
+            &pool,
+            &event_tx,
+            Arc::new(SlowGeocoder(release.clone())),
+            new_job("43.08,-77.67", None, None),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(job["geocodePending"], json!(true));
+        assert_eq!(job["latitude"], Value::Null);
+        let created = events.recv().await.unwrap();
+        assert_eq!(created.kind, ChangeKind::Created);
+
+        // The job is placed once the geocoder answers
+        release.notify_one();
+        let updated = events.recv().await.unwrap();
+        assert_eq!(updated.kind, ChangeKind::Updated);
+        let Entity::Job(updated) = updated.entity else {
+            panic!("expected a job");
+        };
+        assert_eq!(updated.id, job["id"].as_str().unwrap());
+        assert_eq!(updated.latitude, Some(43.08));
+        assert_eq!(updated.longitude, Some(-77.67));
+    }
+
+    #[tokio::test]
+    async fn needs_both_coordinates() {
+        let pool = Arc::new(test_pool().await);
+        let (event_tx, _) = broadcast::channel(16);
+        let event_tx = Arc::new(event_tx);
+
+        let (status, _) = create(
+            &pool,
+            &event_tx,
+            Arc::new(MockGeocoder),
+            new_job("1 Lomb Memorial Dr", Some(43.08), None),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, job) = create(
+            &pool,
+            &event_tx,
+            Arc::new(MockGeocoder),
+            new_job("1 Lomb Memorial Dr", Some(43.09), Some(-77.68)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(job["latitude"], json!(43.09));
+        assert_eq!(job["locationOverridden"], json!(true));
+    }
+}
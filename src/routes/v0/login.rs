@@ -1,22 +1,29 @@
 use crate::db;
+use crate::db::login_throttles;
+use crate::db::sessions::Session;
 use crate::db::users::User;
-use crate::extractors::Jwt;
-use axum::http::StatusCode;
+use crate::extractors::{Authorized, Jwt, Token};
+use crate::permissions::Role;
+use crate::routes;
+use crate::signing;
+use axum::extract::{ConnectInfo, Query};
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::IntoResponse;
 use axum::{Extension, Json};
-use hmac::{Hmac, Mac};
-use jwt::SignWithKey;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sha2::Sha256;
 use sqlx::{Error, Pool, Sqlite};
-use std::collections::BTreeMap;
 use std::env;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 lazy_static! {
-    static ref JWT_SECRET: String = env::var("JWT_SECRET").unwrap();
+    /// How long an access token is valid for before it has to be refreshed
+    static ref ACCESS_TOKEN_TTL_MINUTES: i64 = env::var("ACCESS_TOKEN_TTL_MINUTES")
+        .ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .unwrap_or(15);
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -33,8 +40,109 @@ pub struct Login {
     pub password: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MfaLogin {
+    pub challenge: String,
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Refresh {
+    pub refresh_token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionRequest {
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSessionsRequest {
+    pub user_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordResetRequest {
+    pub code: String,
+    pub password: String,
+}
+
+fn user_agent(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn token_response(user: User, session: &Session, refresh_token: String) -> serde_json::Value {
+    let (token, expires_at) = issue_jwt(user, &session.id);
+    json!({ "token": token, "expiresAt": expires_at, "refreshToken": refresh_token })
+}
+
+/// Starts a new session for a user who has just proven who they are.
+pub(crate) async fn start_session(
+    pool: &Pool<Sqlite>,
+    user: User,
+    headers: &HeaderMap,
+    addr: SocketAddr,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let ip_address = addr.ip().to_string();
+    match db::sessions::create(pool, &user.id, user_agent(headers), Some(&ip_address)).await {
+        Ok((session, refresh_token)) => (
+            StatusCode::OK,
+            Json(token_response(user, &session, refresh_token)),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:?}", e) })),
+        ),
+    }
+}
+
+/// Asks a user with MFA enabled for their second factor instead of starting
+/// a session. The client finishes signing in with `login_mfa`.
+pub(crate) async fn mfa_challenge(
+    pool: &Pool<Sqlite>,
+    user: &User,
+    ip_address: &str,
+) -> (StatusCode, Json<serde_json::Value>) {
+    record_login_event(
+        pool,
+        "login.mfa_challenged",
+        Some(&user.id),
+        json!({ "ip": ip_address }),
+    )
+    .await;
+    match db::mfa::create_challenge(pool, &user.id).await {
+        Ok((challenge, token)) => (
+            StatusCode::OK,
+            Json(json!({
+                "mfaRequired": true,
+                "challenge": token,
+                "expiresAt": challenge.expires_at,
+            })),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:?}", e) })),
+        ),
+    }
+}
+
 pub async fn create_user(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(signup): Json<Signup>,
 ) -> impl IntoResponse {
     if !(*crate::features::SIGNUPS_ENABLED) {
@@ -43,17 +151,21 @@ pub async fn create_user(
             Json(json!({ "error": "Signups are not enabled" })),
         );
     }
-    // TODO: Potentially more checks for password strength
-    if signup.password.len() < 12 {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({"error": "Password must be at least 12 characters"})),
-        );
+    if let Err(e) = check_password_strength(&signup.password) {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": e })));
     }
-    let user =
-        db::users::create_user(&pool, &signup.email, &signup.password, &signup.display_name).await;
+    let user = db::users::create_user(
+        &pool,
+        &signup.email,
+        &signup.password,
+        &signup.display_name,
+        None,
+        Role::default(),
+        None,
+    )
+    .await;
     match user {
-        Ok(user) => (StatusCode::OK, Json(json!({ "token": issue_jwt(user) }))),
+        Ok(user) => start_session(&pool, user, &headers, addr).await,
         Err(err) => match err {
             Error::Database(e) if e.code().unwrap_or(std::borrow::Cow::Borrowed("")) == "23505" => {
                 (
@@ -69,6 +181,15 @@ pub async fn create_user(
     }
 }
 
+/// Password rules shared by signup and every route that sets a password.
+pub(crate) fn check_password_strength(password: &str) -> Result<(), &'static str> {
+    // TODO: Potentially more checks for password strength
+    if password.len() < 12 {
+        return Err("Password must be at least 12 characters");
+    }
+    Ok(())
+}
+
 pub async fn whoami(Jwt(user): Jwt) -> impl IntoResponse {
     (StatusCode::OK, Json(json!(user)))
 }
@@ -76,13 +197,31 @@ pub async fn whoami(Jwt(user): Jwt) -> impl IntoResponse {
 #[axum::debug_handler]
 pub async fn login(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(login_req): Json<Login>,
 ) -> impl IntoResponse {
+    let ip_address = addr.ip().to_string();
+    let account_key = login_throttles::account_key(&login_req.email);
+    let ip_key = login_throttles::ip_key(&ip_address);
+    match login_throttles::retry_after(&pool, &[&account_key, &ip_key]).await {
+        Ok(None) => {}
+        Ok(Some(seconds)) => return throttled_response(seconds),
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": format!("{:?}", e) })),
+            )
+        }
+    }
+
     let user = db::users::get_user_by_email(&pool, &login_req.email).await;
     let user = match user {
         Ok(user) => user,
         Err(err) => match err {
             Error::RowNotFound => {
+                record_login_failure(&pool, &login_req.email, &ip_address, None, "unknown_user")
+                    .await;
                 return (
                     StatusCode::UNAUTHORIZED,
                     Json(json!({"error": "Invalid email or password"})),
@@ -98,42 +237,451 @@ pub async fn login(
     };
 
     if !bcrypt::verify(&login_req.password, &user.password).unwrap_or(false) {
+        record_login_failure(
+            &pool,
+            &login_req.email,
+            &ip_address,
+            Some(&user.id),
+            "invalid_password",
+        )
+        .await;
         return (
             StatusCode::UNAUTHORIZED,
             Json(json!({"error": "Invalid email or password"})),
         );
     }
     if !user.enabled {
+        record_login_failure(
+            &pool,
+            &login_req.email,
+            &ip_address,
+            Some(&user.id),
+            "disabled",
+        )
+        .await;
         return (
             StatusCode::UNAUTHORIZED,
             Json(json!({"error": "Invalid email or password"})),
         );
     }
 
-    let token = issue_jwt(user);
-    (StatusCode::OK, Json(json!({ "token": token })))
+    if user.mfa_enabled {
+        return mfa_challenge(&pool, &user, &ip_address).await;
+    }
+
+    record_login_success(&pool, &user, &ip_address, "password").await;
+    start_session(&pool, user, &headers, addr).await
+}
+
+/// The second step of a login for users with MFA enabled, taking the
+/// challenge from `login` or the SSO callback and a TOTP or recovery code.
+pub async fn login_mfa(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<MfaLogin>,
+) -> impl IntoResponse {
+    let ip_address = addr.ip().to_string();
+    let challenge = match db::mfa::attempt_challenge(&pool, &req.challenge).await {
+        Ok(Some(challenge)) => challenge,
+        Ok(None) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "That login has expired, please sign in again"})),
+            )
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": format!("{:?}", e) })),
+            )
+        }
+    };
+    let user = match db::users::get_user(&pool, &challenge.user_id).await {
+        Ok(user) if user.enabled => user,
+        Ok(_) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "Invalid email or password"})),
+            )
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": format!("{:?}", e) })),
+            )
+        }
+    };
+
+    match routes::v0::mfa::verify_second_factor(&pool, &user.id, &req.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            record_login_failure(
+                &pool,
+                &user.email,
+                &ip_address,
+                Some(&user.id),
+                "invalid_mfa_code",
+            )
+            .await;
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "Invalid code"})),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": format!("{:?}", e) })),
+            )
+        }
+    }
+    match db::mfa::complete_challenge(&pool, &challenge.id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "That login has expired, please sign in again"})),
+            )
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": format!("{:?}", e) })),
+            )
+        }
+    }
+
+    record_login_success(&pool, &user, &ip_address, "mfa").await;
+    start_session(&pool, user, &headers, addr).await
+}
+
+fn throttled_response(retry_after: i64) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        Json(json!({
+            "error": "Too many failed login attempts, please try again later",
+            "retryAfter": retry_after,
+        })),
+    )
+}
+
+/// Records a login event in the audit log. Failing to record it shouldn't
+/// change the response, so errors are only logged.
+async fn record_login_event(
+    pool: &Pool<Sqlite>,
+    action: &str,
+    user_id: Option<&str>,
+    details: serde_json::Value,
+) {
+    if let Err(e) = db::audit::record(pool, user_id, action, "user", user_id, Some(details)).await {
+        tracing::error!("failed to record {}: {}", action, e);
+    }
+}
+
+pub(crate) async fn record_login_success(
+    pool: &Pool<Sqlite>,
+    user: &User,
+    ip_address: &str,
+    method: &str,
+) {
+    if let Err(e) = login_throttles::clear(pool, &login_throttles::account_key(&user.email)).await {
+        tracing::error!("failed to clear login throttle: {}", e);
+    }
+    record_login_event(
+        pool,
+        "login.succeeded",
+        Some(&user.id),
+        json!({ "ip": ip_address, "method": method }),
+    )
+    .await;
+}
+
+/// Counts a failed login against both the account and the address it came
+/// from, locking either out once it's had too many.
+async fn record_login_failure(
+    pool: &Pool<Sqlite>,
+    email: &str,
+    ip_address: &str,
+    user_id: Option<&str>,
+    reason: &str,
+) {
+    tracing::warn!(email, ip = ip_address, reason, "failed login");
+    record_login_event(
+        pool,
+        "login.failed",
+        user_id,
+        json!({ "email": email, "ip": ip_address, "reason": reason }),
+    )
+    .await;
+
+    let keys = [
+        (
+            login_throttles::account_key(email),
+            *login_throttles::ACCOUNT_LOCKOUT_THRESHOLD,
+        ),
+        (
+            login_throttles::ip_key(ip_address),
+            *login_throttles::IP_LOCKOUT_THRESHOLD,
+        ),
+    ];
+    for (key, threshold) in keys {
+        match login_throttles::record_failure(pool, &key, threshold).await {
+            Ok((throttle, true)) => {
+                tracing::warn!(key, "locked out after {} failed logins", throttle.failures);
+                record_login_event(
+                    pool,
+                    "login.locked_out",
+                    user_id,
+                    json!({ "key": key, "failures": throttle.failures, "lockedUntil": throttle.locked_until }),
+                )
+                .await;
+            }
+            Ok((_, false)) => {}
+            Err(e) => tracing::error!("failed to record login failure: {}", e),
+        }
+    }
+}
+
+/// Exchanges a refresh token for a new access token and refresh token.
+pub async fn refresh(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<Refresh>,
+) -> impl IntoResponse {
+    let ip_address = addr.ip().to_string();
+    let refreshed = db::sessions::refresh(
+        &pool,
+        &req.refresh_token,
+        user_agent(&headers),
+        Some(&ip_address),
+    )
+    .await;
+    let (session, refresh_token) = match refreshed {
+        Ok(Some(refreshed)) => refreshed,
+        Ok(None) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "Invalid refresh token"})),
+            )
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": format!("{:?}", e) })),
+            )
+        }
+    };
+
+    match db::users::get_user(&pool, &session.user_id).await {
+        Ok(user) if user.enabled => (
+            StatusCode::OK,
+            Json(token_response(user, &session, refresh_token)),
+        ),
+        Ok(_) => (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "user is disabled"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:?}", e) })),
+        ),
+    }
+}
+
+/// Signs out the session the token was issued for. Returns 400 for tokens
+/// that aren't tied to a session, and 404 if the session is already revoked.
+pub async fn logout(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Jwt(user): Jwt,
+) -> impl IntoResponse {
+    let Some(session_id) = user.session_id.as_deref() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "this token is not tied to a session"})),
+        );
+    };
+    match db::sessions::revoke(&pool, session_id, &user.id, &user.id).await {
+        Ok(()) => (StatusCode::OK, Json(json!({}))),
+        Err(Error::RowNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "this session has already been revoked"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:?}", e) })),
+        ),
+    }
+}
+
+pub async fn logout_everywhere(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Jwt(user): Jwt,
+) -> impl IntoResponse {
+    match db::sessions::revoke_all(&*pool, &user.id, &user.id, None).await {
+        Ok(count) => (StatusCode::OK, Json(json!({ "revoked": count }))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:?}", e) })),
+        ),
+    }
+}
+
+/// Lists the signed-in user's own sessions.
+pub async fn get_sessions(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Jwt(user): Jwt,
+) -> impl IntoResponse {
+    match db::sessions::list_for_user(&pool, &user.id).await {
+        Ok(sessions) => {
+            let sessions: Vec<Session> = sessions
+                .into_iter()
+                .map(|session| Session {
+                    current: user.session_id.as_ref() == Some(&session.id),
+                    ..session
+                })
+                .collect();
+            (StatusCode::OK, Json(json!(sessions)))
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:?}", e) })),
+        ),
+    }
+}
+
+/// Signs one of the user's own sessions out, like a lost phone.
+pub async fn revoke_session(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Jwt(user): Jwt,
+    Json(req): Json<SessionRequest>,
+) -> impl IntoResponse {
+    match db::sessions::revoke(&pool, &req.id, &user.id, &user.id).await {
+        Ok(()) => (StatusCode::OK, Json(json!({}))),
+        Err(Error::RowNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that session does not exist or has been revoked"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:?}", e) })),
+        ),
+    }
+}
+
+pub async fn get_user_sessions(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Query(req): Query<UserSessionsRequest>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    match db::sessions::list_for_user(&pool, &req.user_id).await {
+        Ok(sessions) => (StatusCode::OK, Json(json!(sessions))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:?}", e) })),
+        ),
+    }
+}
+
+/// Signs another user out everywhere.
+pub async fn revoke_user_sessions(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<UserSessionsRequest>,
+) -> impl IntoResponse {
+    match db::sessions::revoke_all_audited(&pool, &req.user_id, &user.id).await {
+        Ok(count) => (StatusCode::OK, Json(json!({ "revoked": count }))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:?}", e) })),
+        ),
+    }
+}
+
+/// Changes the signed-in user's password. Every other session is signed out.
+pub async fn change_password(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Jwt(user): Jwt,
+    Json(req): Json<PasswordChange>,
+) -> impl IntoResponse {
+    if !bcrypt::verify(&req.current_password, &user.password).unwrap_or(false) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Current password is incorrect"})),
+        );
+    }
+    if let Err(e) = check_password_strength(&req.new_password) {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": e })));
+    }
+
+    let changed = db::users::change_password(
+        &pool,
+        &user.id,
+        &req.new_password,
+        user.session_id.as_deref(),
+    )
+    .await;
+    match changed {
+        Ok(_) => (StatusCode::OK, Json(json!({}))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:?}", e) })),
+        ),
+    }
 }
 
-fn issue_jwt(user: User) -> String {
-    let key: Hmac<Sha256> = Hmac::new_from_slice((*JWT_SECRET).as_bytes()).unwrap();
-    let mut claims = BTreeMap::new();
+/// Sets a new password with a reset code from an admin. Every session is
+/// signed out, so the user has to log in again with the new password.
+///
+/// Returns 400 if the code is unknown, expired or already used, without
+/// saying which, and 400 if the password is too weak.
+pub async fn reset_password(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Json(req): Json<PasswordResetRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = check_password_strength(&req.password) {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": e })));
+    }
 
-    let iat = chrono::Utc::now().timestamp().to_string();
-    let exp = (chrono::Utc::now() + chrono::Duration::hours(7 * 24))
-        .timestamp()
-        .to_string();
-    let dn = user.display_name.clone();
-    let admin = user.admin.to_string();
-    let sub = user.id.to_string();
+    match db::users::reset_password_with_code(&pool, &req.code, &req.password).await {
+        Ok(Some(_)) => (StatusCode::OK, Json(json!({}))),
+        Ok(None) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "That reset code is invalid, has expired, or has been used"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": format!("{:?}", e) })),
+        ),
+    }
+}
+
+/// Issues an access token for a session, returning it with its expiry.
+fn issue_jwt(user: User, session_id: &str) -> (String, i64) {
+    let now = chrono::Utc::now();
+    let expires_at = (now + chrono::Duration::minutes(*ACCESS_TOKEN_TTL_MINUTES)).timestamp();
 
     // https://www.iana.org/assignments/jwt/jwt.xhtml
-    claims.insert("iss", "integral");
-    claims.insert("sub", &sub);
-    claims.insert("iat", &iat);
-    claims.insert("exp", &exp);
-    claims.insert("dn", &dn);
-    claims.insert("email", &user.email);
-    claims.insert("admin", &admin);
-
-    claims.sign_with_key(&key).unwrap()
+    let claims = Token {
+        iss: signing::ISSUER.to_string(),
+        sub: user.id,
+        iat: now.timestamp(),
+        exp: expires_at,
+        dn: user.display_name,
+        email: user.email,
+        admin: user.admin,
+        sid: session_id.to_string(),
+    };
+
+    (signing::KEYS.sign(&claims).unwrap(), expires_at)
+}
+
+/// The public keys access tokens are signed with, so other services can
+/// verify them.
+pub async fn get_jwks() -> impl IntoResponse {
+    (
+        [(header::CACHE_CONTROL, "public, max-age=300")],
+        Json(signing::KEYS.jwks()),
+    )
 }
@@ -0,0 +1,8 @@
+use axum::{http::StatusCode, response::IntoResponse};
+
+use crate::extractors::{Authorized, Json};
+use crate::metrics;
+
+pub async fn get_metrics(Authorized(_user): Authorized) -> impl IntoResponse {
+    (StatusCode::OK, Json(metrics::snapshot()))
+}
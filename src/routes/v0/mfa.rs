@@ -0,0 +1,210 @@
+use std::sync::Arc;
+
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    db,
+    extractors::{Authorized, Jwt},
+    permissions::Role,
+    totp,
+};
+
+fn error_response(e: sqlx::Error) -> (StatusCode, Json<serde_json::Value>) {
+    match e {
+        sqlx::Error::RowNotFound => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that user does not exist"})),
+        ),
+        e => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+/// Checks a code from the user's authenticator app. A code is only accepted
+/// once, so one read over a dispatcher's shoulder can't be reused.
+async fn verify_totp(pool: &Pool<Sqlite>, user_id: &str, code: &str) -> Result<bool, sqlx::Error> {
+    let Some(secret) = db::mfa::get_secret(pool, user_id).await? else {
+        return Ok(false);
+    };
+    match totp::verify(&secret, code, chrono::Utc::now().timestamp()) {
+        Some(step) => db::mfa::record_step(pool, user_id, step).await,
+        None => Ok(false),
+    }
+}
+
+/// Checks a second factor at login, which can be a TOTP code or one of the
+/// user's recovery codes.
+pub(crate) async fn verify_second_factor(
+    pool: &Pool<Sqlite>,
+    user_id: &str,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    if verify_totp(pool, user_id, code).await? {
+        return Ok(true);
+    }
+    db::mfa::use_recovery_code(pool, user_id, code).await
+}
+
+/// Starts enrolling the signed-in user, returning a new secret and the URI
+/// for their authenticator app. MFA isn't enforced until they verify a code.
+pub async fn enroll(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Jwt(user): Jwt,
+) -> impl IntoResponse {
+    let secret = totp::generate_secret();
+    match db::mfa::begin_enrollment(&pool, &user.id, &secret).await {
+        Ok(true) => (
+            StatusCode::OK,
+            Json(json!({"secret": secret, "uri": totp::otpauth_uri(&secret, &user.email)})),
+        ),
+        Ok(false) => (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "two-factor authentication is already enabled"})),
+        ),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CodeRequest {
+    code: String,
+}
+/// Finishes enrolling with a code from the authenticator app, returning the
+/// user's recovery codes. They're only shown this once.
+pub async fn verify(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Jwt(user): Jwt,
+    Json(req): Json<CodeRequest>,
+) -> impl IntoResponse {
+    let secret = match db::mfa::get_secret(&pool, &user.id).await {
+        Ok(Some(secret)) if !user.mfa_enabled => secret,
+        Ok(_) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({"error": "there is no two-factor enrollment in progress"})),
+            )
+        }
+        Err(e) => return error_response(e),
+    };
+    let Some(step) = totp::verify(&secret, &req.code, chrono::Utc::now().timestamp()) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "That code is not valid"})),
+        );
+    };
+
+    match db::mfa::confirm_enrollment(&pool, &user.id, step).await {
+        Ok(Some(codes)) => (StatusCode::OK, Json(json!({ "recoveryCodes": codes }))),
+        Ok(None) => (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "there is no two-factor enrollment in progress"})),
+        ),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Replaces the signed-in user's recovery codes. Needs a current TOTP code so
+/// a stolen session can't be used to mint new ones.
+pub async fn regenerate_recovery_codes(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Jwt(user): Jwt,
+    Json(req): Json<CodeRequest>,
+) -> impl IntoResponse {
+    match verify_totp(&pool, &user.id, &req.code).await {
+        Ok(true) if user.mfa_enabled => {}
+        Ok(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "That code is not valid"})),
+            )
+        }
+        Err(e) => return error_response(e),
+    }
+    match db::mfa::regenerate_recovery_codes(&pool, &user.id).await {
+        Ok(codes) => (StatusCode::OK, Json(json!({ "recoveryCodes": codes }))),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DisableRequest {
+    password: String,
+}
+pub async fn disable(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Jwt(user): Jwt,
+    Json(req): Json<DisableRequest>,
+) -> impl IntoResponse {
+    if !bcrypt::verify(&req.password, &user.password).unwrap_or(false) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Current password is incorrect"})),
+        );
+    }
+    match db::mfa::is_required(&pool, user.role).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(json!({"error": "two-factor authentication is required for your role"})),
+            )
+        }
+        Err(e) => return error_response(e),
+    }
+    match db::mfa::disable(&pool, &user.id, &user.id).await {
+        Ok(()) => (StatusCode::OK, Json(json!({}))),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ResetRequest {
+    id: String,
+}
+/// Turns MFA off for a user who has lost their authenticator and recovery
+/// codes, so they can enroll again.
+pub async fn reset(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<ResetRequest>,
+) -> impl IntoResponse {
+    match db::mfa::disable(&pool, &req.id, &user.id).await {
+        Ok(()) => (StatusCode::OK, Json(json!({}))),
+        Err(e) => error_response(e),
+    }
+}
+
+pub async fn get_required_roles(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    match db::mfa::required_roles(&pool).await {
+        Ok(roles) => (StatusCode::OK, Json(json!(roles))),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RoleRequirementRequest {
+    role: Role,
+    required: bool,
+}
+pub async fn set_required_role(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<RoleRequirementRequest>,
+) -> impl IntoResponse {
+    match db::mfa::set_required(&pool, req.role, req.required, &user.id).await {
+        Ok(()) => (StatusCode::OK, Json(json!({}))),
+        Err(e) => error_response(e),
+    }
+}
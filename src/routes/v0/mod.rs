@@ -1,5 +1,16 @@
+pub mod api_keys;
+pub mod avl;
+pub mod devices;
 pub mod features;
 pub mod jobs;
 pub mod login;
+pub mod metrics;
+pub mod mfa;
+pub mod nature_codes;
+pub mod presence;
 pub mod resources;
+pub mod sso;
 pub mod stream;
+pub mod users;
+pub mod webhooks;
+pub mod ws;
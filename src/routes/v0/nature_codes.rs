@@ -0,0 +1,137 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{extract::Query, http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{db, extractors::Authorized};
+
+pub async fn get_all_nature_codes(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    match db::nature_codes::list(&pool).await {
+        Ok(codes) => (StatusCode::OK, Json(json!(codes))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NatureCodeRequest {
+    code: String,
+    description: String,
+    default_priority: i64,
+    #[serde(default)]
+    recommended_resource_types: Vec<String>,
+    response_plan: Option<String>,
+    enabled: Option<bool>,
+}
+
+fn validate(req: &NatureCodeRequest) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if req.code.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "nature code must not be empty"})),
+        ));
+    }
+    if !(1..=5).contains(&req.default_priority) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "priority must be between 1 and 5"})),
+        ));
+    }
+    Ok(())
+}
+
+pub async fn create(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(_user): Authorized,
+    Json(req): Json<NatureCodeRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = validate(&req) {
+        return e;
+    }
+
+    let code = db::nature_codes::create(
+        &pool,
+        req.code.trim(),
+        &req.description,
+        req.default_priority,
+        req.recommended_resource_types,
+        req.response_plan,
+    )
+    .await;
+    match code {
+        Ok(code) => (StatusCode::OK, Json(json!(code))),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "that nature code already exists"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+pub async fn update(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(_user): Authorized,
+    Json(req): Json<NatureCodeRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = validate(&req) {
+        return e;
+    }
+
+    let code = db::nature_codes::update(
+        &pool,
+        req.code.trim(),
+        &req.description,
+        req.default_priority,
+        req.recommended_resource_types,
+        req.response_plan,
+        req.enabled.unwrap_or(true),
+    )
+    .await;
+    match code {
+        Ok(code) => (StatusCode::OK, Json(json!(code))),
+        Err(sqlx::Error::RowNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that nature code does not exist"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+pub async fn disable(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Query(params): Query<HashMap<String, String>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    if let Some(code) = params.get("code") {
+        match db::nature_codes::disable(&pool, code.trim()).await {
+            Ok(code) => (StatusCode::OK, Json(json!(code))),
+            Err(sqlx::Error::RowNotFound) => (
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "that nature code does not exist"})),
+            ),
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!(e.to_string())),
+            ),
+        }
+    } else {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "missing code"})),
+        )
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::extractors::Authorized;
+use crate::presence::Presence;
+
+pub async fn get_presence(
+    Extension(presence): Extension<Arc<Presence>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    (StatusCode::OK, Json(json!(presence.list())))
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ActivityRequest {
+    job_id: Option<String>,
+    #[serde(default)]
+    editing: bool,
+}
+/// Sets the job open on the caller's console. Consoles on the SSE stream use
+/// this; WebSocket clients send an `activity` message instead.
+pub async fn set_activity(
+    Extension(presence): Extension<Arc<Presence>>,
+    Authorized(user): Authorized,
+    Json(req): Json<ActivityRequest>,
+) -> impl IntoResponse {
+    if presence.set_activity(&user.id, req.job_id, req.editing) {
+        (StatusCode::OK, Json(json!({})))
+    } else {
+        (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "you are not connected to the stream"})),
+        )
+    }
+}
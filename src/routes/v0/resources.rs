@@ -1,20 +1,38 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
-use axum::{extract::Query, http::StatusCode, response::IntoResponse, Extension, Json};
-use serde::Deserialize;
-use serde_json::json;
+use axum::{
+    extract::{ConnectInfo, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use sqlx::{Pool, Sqlite};
 use tokio::sync::broadcast;
 
-use crate::{db, extractors::Jwt};
+use crate::{
+    db::{
+        self,
+        assignments::Assignment,
+        jobs::JobStatus,
+        resources::{AvailableResource, ResourceLocation, ResourceStatus, ResourceStatusChange},
+        users::User,
+    },
+    extractors::{reject_device, Authorized, LocationReporter},
+    geo,
+    geocoder::Geocoder,
+    permissions::{Permission, Role},
+};
 
-use super::stream::Event;
+use super::stream::{self, ChangeKind, Event};
 
 pub async fn get_all_resources(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
-    Jwt(_user): Jwt,
+    Extension(geocoder): Extension<Arc<dyn Geocoder>>,
+    Authorized(_user): Authorized,
 ) -> impl IntoResponse {
-    let resources = db::resources::list(&pool).await;
+    let resources = db::resources::list(&pool, geocoder.as_ref()).await;
     match resources {
         Ok(job) => (StatusCode::OK, Json(json!(job))),
         Err(e) => (
@@ -29,17 +47,78 @@ pub async fn get_all_resources(
 pub(crate) struct ResourceCreationRequest {
     display_name: String,
     comment: Option<String>,
+    resource_type: Option<String>,
+    #[serde(default)]
+    capabilities: Vec<String>,
 }
 pub async fn create(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
     Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
-    Jwt(_user): Jwt,
+    Authorized(_user): Authorized,
     Json(req): Json<ResourceCreationRequest>,
 ) -> impl IntoResponse {
-    let resource = db::resources::create_resource(&pool, &req.display_name, req.comment).await;
-    event_tx.send(Event::Resource(req.display_name)).ok();
+    let resource = db::resources::create_resource(
+        &pool,
+        &req.display_name,
+        req.comment,
+        req.resource_type,
+        req.capabilities,
+    )
+    .await;
     match resource {
-        Ok(res) => (StatusCode::OK, Json(json!(res))),
+        Ok(res) => {
+            stream::publish_resource(&pool, &event_tx, ChangeKind::Created, &res.id).await;
+            (StatusCode::OK, Json(json!(res)))
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+/// Who is crewing which units. Can be narrowed to one unit with `resourceId`
+/// or one user with `userId`.
+pub async fn get_crew(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Query(params): Query<HashMap<String, String>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    let resource_id = params.get("resourceId").map(String::as_str);
+    let user_id = params.get("userId").map(String::as_str);
+    match db::resources::list_bindings(&pool, resource_id, user_id).await {
+        Ok(bindings) => (StatusCode::OK, Json(json!(bindings))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CrewRequest {
+    resource_id: String,
+    user_id: String,
+}
+/// Puts a user on a unit's crew, letting them update it as a field unit.
+/// Field units that subscribed to `my_unit` before they were bound have to
+/// subscribe again to hear about it.
+pub async fn add_crew(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<CrewRequest>,
+) -> impl IntoResponse {
+    match db::resources::bind_user(&pool, &req.resource_id, &req.user_id, &user.id).await {
+        Ok(binding) => (StatusCode::OK, Json(json!(binding))),
+        Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "that resource or user does not exist"})),
+        ),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "that user is already crewing that unit"})),
+        ),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!(e.to_string())),
@@ -47,6 +126,46 @@ pub async fn create(
     }
 }
 
+pub async fn remove_crew(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<CrewRequest>,
+) -> impl IntoResponse {
+    match db::resources::unbind_user(&pool, &req.resource_id, &req.user_id, &user.id).await {
+        Ok(binding) => (StatusCode::OK, Json(json!(binding))),
+        Err(sqlx::Error::RowNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that user is not crewing that unit"})),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+/// Field units can only change the status of the unit they're crewing.
+async fn check_own_unit(
+    pool: &Pool<Sqlite>,
+    user: &User,
+    resource_id: &str,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    if user.role != Role::FieldUnit {
+        return Ok(());
+    }
+    match db::resources::is_bound_to_user(pool, resource_id, &user.id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "forbidden", "permission": Permission::UpdateResourceStatus})),
+        )),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        )),
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct SetResourceInServiceRequest {
@@ -56,13 +175,18 @@ pub(crate) struct SetResourceInServiceRequest {
 pub async fn set_in_service(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
     Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
-    Jwt(user): Jwt,
+    Authorized(user): Authorized,
     Json(req): Json<SetResourceInServiceRequest>,
 ) -> impl IntoResponse {
+    if let Err(e) = check_own_unit(&pool, &user, &req.id).await {
+        return e;
+    }
     let resource = db::resources::set_in_service(&pool, &req.id, req.in_service, &user.id).await;
-    event_tx.send(Event::Resource(req.id)).ok();
     match resource {
-        Ok(res) => (StatusCode::OK, Json(json!(res))),
+        Ok(res) => {
+            stream::publish_resource(&pool, &event_tx, ChangeKind::Updated, &req.id).await;
+            (StatusCode::OK, Json(json!(res)))
+        }
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!(e.to_string())),
@@ -70,6 +194,323 @@ pub async fn set_in_service(
     }
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SetResourceStatusRequest {
+    id: String,
+    status: ResourceStatus,
+    note: Option<String>,
+}
+pub async fn set_status(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<SetResourceStatusRequest>,
+) -> impl IntoResponse {
+    match update_status(&pool, &event_tx, &user, &req.id, req.status, req.note).await {
+        Ok(change) => (StatusCode::OK, Json(json!(change))),
+        Err(e) => e,
+    }
+}
+
+/// Changes a resource's status and keeps its job in step. Shared with the
+/// status command on the WebSocket.
+pub(crate) async fn update_status(
+    pool: &Pool<Sqlite>,
+    event_tx: &broadcast::Sender<Event>,
+    user: &User,
+    id: &str,
+    status: ResourceStatus,
+    note: Option<String>,
+) -> Result<ResourceStatusChange, (StatusCode, Json<serde_json::Value>)> {
+    check_own_unit(pool, user, id).await?;
+    let assignment = db::assignments::get_active_assignment_for_resource(pool, id)
+        .await
+        .ok()
+        .flatten();
+
+    let change = db::resources::set_status(pool, id, status, note, &user.id).await;
+    let change = match change {
+        Ok(change) => change,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "that resource does not exist"})),
+            ))
+        }
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!(e.to_string())),
+            ))
+        }
+    };
+    stream::publish_resource(pool, event_tx, ChangeKind::Updated, id).await;
+
+    if let Some(assignment) = assignment {
+        // Keep the job's status in step with the first unit to reach each stage
+        let job_status = match status {
+            ResourceStatus::EnRoute => Some(JobStatus::EnRoute),
+            ResourceStatus::OnScene => Some(JobStatus::OnScene),
+            _ => None,
+        };
+        if let Some(job_status) = job_status {
+            if let Ok(Some(job)) = db::jobs::get_job_by_id(pool, &assignment.job_id).await {
+                if job.status.can_transition_to(job_status) {
+                    if let Err(e) = db::jobs::set_status(pool, &job.id, job_status, &user.id).await
+                    {
+                        tracing::error!("{}", e);
+                    }
+                }
+            }
+        }
+        stream::publish_job(pool, event_tx, ChangeKind::Updated, &assignment.job_id).await;
+    }
+
+    Ok(change)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AcknowledgeRequest {
+    assignment_id: String,
+}
+pub async fn acknowledge(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<AcknowledgeRequest>,
+) -> impl IntoResponse {
+    match acknowledge_assignment(&pool, &event_tx, &user, &req.assignment_id).await {
+        Ok(assignment) => (StatusCode::OK, Json(json!(assignment))),
+        Err(e) => e,
+    }
+}
+
+/// Records a unit confirming it received an assignment. Field units can only
+/// acknowledge for the unit they're crewing. Shared with the WebSocket.
+pub(crate) async fn acknowledge_assignment(
+    pool: &Pool<Sqlite>,
+    event_tx: &broadcast::Sender<Event>,
+    user: &User,
+    assignment_id: &str,
+) -> Result<Assignment, (StatusCode, Json<serde_json::Value>)> {
+    let assignment = match db::assignments::get_by_id(pool, assignment_id).await {
+        Ok(Some(assignment)) => assignment,
+        Ok(None) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "that assignment does not exist"})),
+            ))
+        }
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!(e.to_string())),
+            ))
+        }
+    };
+    check_own_unit(pool, user, &assignment.resource_id).await?;
+
+    match db::assignments::acknowledge(pool, assignment_id, &user.id).await {
+        Ok(Some(assignment)) => {
+            stream::publish_job(pool, event_tx, ChangeKind::Updated, &assignment.job_id).await;
+            Ok(assignment)
+        }
+        Ok(None) => Err((
+            StatusCode::CONFLICT,
+            Json(json!({"error": "that assignment was removed or already acknowledged"})),
+        )),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        )),
+    }
+}
+
+pub async fn get_status_history(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Query(params): Query<HashMap<String, String>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    if let Some(id) = params.get("id") {
+        match db::resources::get_status_history(&pool, id).await {
+            Ok(history) => (StatusCode::OK, Json(json!(history))),
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!(e.to_string())),
+            ),
+        }
+    } else {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "missing id"})),
+        )
+    }
+}
+
+/// Locations older than this are considered stale unless the caller says otherwise
+const DEFAULT_MAX_LOCATION_AGE: i64 = 15 * 60;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RecommendationQuery {
+    job_id: Option<String>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    /// Comma-separated list of acceptable resource types
+    #[serde(rename = "type")]
+    resource_type: Option<String>,
+    /// Comma-separated list of capabilities a resource must all have
+    capability: Option<String>,
+    /// Maximum age of a location fix in seconds before it's considered stale
+    max_age: Option<i64>,
+    #[serde(default)]
+    include_stale: bool,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Recommendation {
+    #[serde(flatten)]
+    resource: AvailableResource,
+    distance_meters: f64,
+    location_age: i64,
+    stale: bool,
+    /// Whether the resource type is one the job's nature code recommends
+    recommended: bool,
+}
+
+fn split_list(list: &Option<String>) -> Vec<String> {
+    list.as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+pub async fn recommend(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Query(query): Query<RecommendationQuery>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    let mut recommended_types = Vec::new();
+    let (lat, lon) = match (&query.job_id, query.lat, query.lon) {
+        (Some(job_id), _, _) => {
+            let job = match db::jobs::get_job_by_id(&pool, job_id).await {
+                Ok(Some(job)) => job,
+                Ok(None) => {
+                    return (
+                        StatusCode::NOT_FOUND,
+                        Json(json!({"error": "that job does not exist"})),
+                    )
+                }
+                Err(e) => {
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!(e.to_string())),
+                    )
+                }
+            };
+            if let Some(code) = &job.nature_code {
+                if let Ok(Some(code)) = db::nature_codes::get(&pool, code).await {
+                    recommended_types = code
+                        .recommended_resource_types
+                        .iter()
+                        .map(|t| t.to_lowercase())
+                        .collect();
+                }
+            }
+            match (job.latitude, job.longitude) {
+                (Some(lat), Some(lon)) => (lat, lon),
+                _ => {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({"error": "that job does not have a geocoded location"})),
+                    )
+                }
+            }
+        }
+        (None, Some(lat), Some(lon)) => (lat, lon),
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "missing job id or lat/lon"})),
+            )
+        }
+    };
+    if !geo::valid_coordinates(lat, lon) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "invalid coordinates"})),
+        );
+    }
+
+    let resources = match db::resources::list_available_with_location(&pool).await {
+        Ok(resources) => resources,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!(e.to_string())),
+            )
+        }
+    };
+
+    let types = split_list(&query.resource_type);
+    let capabilities = split_list(&query.capability);
+    let max_age = query.max_age.unwrap_or(DEFAULT_MAX_LOCATION_AGE);
+    let now = chrono::Utc::now().timestamp();
+
+    let mut recommendations = resources
+        .into_iter()
+        .filter_map(|resource| {
+            let resource_type = resource.resource_type.as_deref().map(str::to_lowercase);
+            if !types.is_empty() && !resource_type.as_ref().is_some_and(|t| types.contains(t)) {
+                return None;
+            }
+            let has_capabilities = capabilities.iter().all(|c| {
+                resource
+                    .capabilities
+                    .iter()
+                    .any(|rc| rc.eq_ignore_ascii_case(c))
+            });
+            if !has_capabilities {
+                return None;
+            }
+
+            let r_lat = resource.latitude.parse::<f64>().ok()?;
+            let r_lon = resource.longitude.parse::<f64>().ok()?;
+            let location_age = now - resource.at_time;
+            let stale = location_age > max_age;
+            if stale && !query.include_stale {
+                return None;
+            }
+
+            Some(Recommendation {
+                distance_meters: geo::distance_m(lat, lon, r_lat, r_lon),
+                location_age,
+                stale,
+                recommended: resource_type.is_some_and(|t| recommended_types.contains(&t)),
+                resource,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Units with a fresh fix always rank ahead of ones we're unsure about
+    recommendations.sort_by(|a, b| {
+        a.stale
+            .cmp(&b.stale)
+            .then(a.distance_meters.total_cmp(&b.distance_meters))
+    });
+    if let Some(limit) = query.limit {
+        recommendations.truncate(limit);
+    }
+
+    (StatusCode::OK, Json(json!(recommendations)))
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct AssignmentRequest {
@@ -79,10 +520,11 @@ pub(crate) struct AssignmentRequest {
 pub async fn assign(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
     Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
-    Jwt(user): Jwt,
+    Extension(geocoder): Extension<Arc<dyn Geocoder>>,
+    Authorized(user): Authorized,
     Json(req): Json<AssignmentRequest>,
 ) -> impl IntoResponse {
-    let resources = db::resources::list(&pool).await;
+    let resources = db::resources::list(&pool, geocoder.as_ref()).await;
     match resources {
         Ok(resources) => {
             let resource = resources.iter().find(|r| r.id == req.resource_id);
@@ -120,11 +562,25 @@ pub async fn assign(
     let assignment =
         crate::db::assignments::assign(&pool, &req.job_id, &req.resource_id, &user.id).await;
 
-    event_tx.send(Event::Resource(req.resource_id)).ok();
-    event_tx.send(Event::Job(req.job_id)).ok();
+    if assignment.is_ok() {
+        if let Ok(Some(job)) = db::jobs::get_job_by_id(&pool, &req.job_id).await {
+            if job.status == JobStatus::Pending {
+                if let Err(e) =
+                    db::jobs::set_status(&pool, &job.id, JobStatus::Dispatched, &user.id).await
+                {
+                    tracing::error!("{}", e);
+                }
+            }
+        }
+    }
 
     match assignment {
-        Ok(job) => (StatusCode::OK, Json(json!(job))),
+        Ok(assignment) => {
+            stream::publish_resource(&pool, &event_tx, ChangeKind::Assigned, &req.resource_id)
+                .await;
+            stream::publish_job(&pool, &event_tx, ChangeKind::Assigned, &req.job_id).await;
+            (StatusCode::OK, Json(json!(assignment)))
+        }
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!(e.to_string())),
@@ -140,18 +596,27 @@ pub(crate) struct UnAssignmentRequest {
 pub async fn unassign(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
     Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
-    Jwt(user): Jwt,
+    Authorized(user): Authorized,
     Json(req): Json<UnAssignmentRequest>,
 ) -> impl IntoResponse {
     let assignment = crate::db::assignments::unassign(&pool, &req.assignment_id, &user.id).await;
 
-    event_tx
-        .send(Event::Resource(req.assignment_id.clone()))
-        .ok();
-    event_tx.send(Event::Job(req.assignment_id)).ok();
-
     match assignment {
-        Ok(job) => (StatusCode::OK, Json(json!(job))),
+        Ok(assignment) => {
+            stream::publish_resource(
+                &pool,
+                &event_tx,
+                ChangeKind::Unassigned,
+                &assignment.resource_id,
+            )
+            .await;
+            stream::publish_job(&pool, &event_tx, ChangeKind::Unassigned, &assignment.job_id).await;
+            (StatusCode::OK, Json(json!(assignment)))
+        }
+        Err(sqlx::Error::RowNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that assignment does not exist"})),
+        ),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!(e.to_string())),
@@ -162,7 +627,7 @@ pub async fn unassign(
 pub async fn get_assignments_for_job(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
     Query(params): Query<HashMap<String, String>>,
-    Jwt(_user): Jwt,
+    Authorized(_user): Authorized,
 ) -> impl IntoResponse {
     if let Some(id) = params.get("id") {
         let assignments = db::assignments::get_assignments_for_job(&pool, &id).await;
@@ -181,28 +646,200 @@ pub async fn get_assignments_for_job(
     }
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TrackQuery {
+    id: String,
+    /// Start of the track as a unix timestamp, defaulting to an hour before `to`
+    from: Option<i64>,
+    /// End of the track as a unix timestamp, defaulting to now
+    to: Option<i64>,
+    /// Minimum number of seconds between returned fixes
+    interval: Option<i64>,
+    /// Minimum number of meters between returned fixes
+    distance: Option<f64>,
+    /// `json` (the default) or `geojson`
+    format: Option<String>,
+}
+pub async fn get_track(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Query(query): Query<TrackQuery>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    let to = query.to.unwrap_or_else(|| chrono::Utc::now().timestamp());
+    let from = query.from.unwrap_or(to - 60 * 60);
+    if from > to {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "from must be before to"})),
+        );
+    }
+
+    let track = match db::resources::get_track(&pool, &query.id, from, to).await {
+        Ok(track) => db::resources::downsample(track, query.interval, query.distance),
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!(e.to_string())),
+            )
+        }
+    };
+
+    match query.format.as_deref() {
+        None | Some("json") => (StatusCode::OK, Json(json!(track))),
+        Some("geojson") => (
+            StatusCode::OK,
+            Json(track_geojson(&query.id, from, to, &track)),
+        ),
+        Some(_) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "format must be json or geojson"})),
+        ),
+    }
+}
+
+/// A track as a GeoJSON feature, with the time of each fix in `times`. A
+/// single fix is a Point, and an empty track has no geometry.
+fn track_geojson(resource_id: &str, from: i64, to: i64, track: &[ResourceLocation]) -> Value {
+    // Fixes that don't parse are skipped for both, so times line up with coordinates
+    let (coordinates, times): (Vec<_>, Vec<_>) = track
+        .iter()
+        .filter_map(|fix| {
+            let lon = fix.longitude.parse::<f64>().ok()?;
+            let lat = fix.latitude.parse::<f64>().ok()?;
+            Some(([lon, lat], fix.at_time))
+        })
+        .unzip();
+    let geometry = match coordinates.as_slice() {
+        [] => Value::Null,
+        [point] => json!({"type": "Point", "coordinates": point}),
+        _ => json!({"type": "LineString", "coordinates": coordinates}),
+    };
+    json!({
+        "type": "Feature",
+        "geometry": geometry,
+        "properties": {
+            "resourceId": resource_id,
+            "from": from,
+            "to": to,
+            "times": times,
+        },
+    })
+}
 
-// TODO: Auth
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct SetResourceLocationRequest {
-    id: String,
+    /// Optional since the device token already identifies the resource, but
+    /// rejected if it's for any other resource
+    id: Option<String>,
     lat: String,
     lon: String,
 }
 pub async fn set_resource_location(
     Extension(pool): Extension<Arc<Pool<Sqlite>>>,
     Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    reporter: LocationReporter,
     Json(req): Json<SetResourceLocationRequest>,
 ) -> impl IntoResponse {
-    tracing::info!("received new location for resource {}", req.id);
-    let resource = db::resources::set_location(&pool, &req.id, &req.lat, &req.lon).await;
-    event_tx.send(Event::Resource(req.id)).ok();
-    match resource {
-        Ok(res) => (StatusCode::OK, Json(json!(res))),
+    let resource_id = match reporter {
+        LocationReporter::Device(device) => {
+            if req.id.as_ref().is_some_and(|id| *id != device.resource_id) {
+                reject_device(
+                    &pool,
+                    Some(&device),
+                    Some(remote_addr.ip().to_string()),
+                    &format!(
+                        "device is not bound to resource {}",
+                        req.id.unwrap_or_default()
+                    ),
+                )
+                .await;
+                return (
+                    StatusCode::FORBIDDEN,
+                    Json(json!({"error": "this device can't update that resource"})),
+                );
+            }
+            tracing::info!(
+                "received new location for resource {} from device {}",
+                device.resource_id,
+                device.id
+            );
+            device.resource_id
+        }
+        LocationReporter::ApiKey(user) => {
+            let Some(id) = req.id else {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "a resource id is required"})),
+                );
+            };
+            tracing::info!(
+                "received new location for resource {} from api key of {}",
+                id,
+                user.id
+            );
+            id
+        }
+    };
+
+    let location = db::resources::set_location(&pool, &resource_id, &req.lat, &req.lon).await;
+    match location {
+        Ok(location) => {
+            stream::publish_location(&pool, &event_tx, location.clone()).await;
+            (StatusCode::OK, Json(json!(location)))
+        }
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!(e.to_string())),
         ),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fix(at_time: i64, latitude: &str, longitude: &str) -> ResourceLocation {
+        ResourceLocation {
+            resource_id: String::from("1"),
+            at_time,
+            latitude: latitude.to_string(),
+            longitude: longitude.to_string(),
+        }
+    }
+
+    #[test]
+    fn track_times_match_coordinates() {
+        let track = [
+            fix(100, "43.08", "-77.67"),
+            fix(110, "garbage", "-77.68"),
+            fix(120, "43.10", "-77.69"),
+        ];
+        let geojson = track_geojson("1", 0, 200, &track);
+        assert_eq!(geojson["geometry"]["type"], "LineString");
+        assert_eq!(
+            geojson["geometry"]["coordinates"],
+            json!([[-77.67, 43.08], [-77.69, 43.10]])
+        );
+        assert_eq!(geojson["properties"]["times"], json!([100, 120]));
+    }
+
+    #[test]
+    fn single_fix_track_is_a_point() {
+        let geojson = track_geojson("1", 0, 200, &[fix(100, "43.08", "-77.67")]);
+        assert_eq!(
+            geojson["geometry"],
+            json!({"type": "Point", "coordinates": [-77.67, 43.08]})
+        );
+        assert_eq!(geojson["properties"]["times"], json!([100]));
+    }
+
+    #[test]
+    fn empty_track_has_no_geometry() {
+        let geojson = track_geojson("1", 0, 200, &[]);
+        assert!(geojson["geometry"].is_null());
+        assert_eq!(geojson["properties"]["times"], json!([]));
+    }
+}
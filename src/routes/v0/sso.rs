@@ -0,0 +1,321 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    db::{self, users::UserUpdate},
+    features,
+    oidc::{LoginAttempt, OidcClient},
+    routes::v0::login::{mfa_challenge, record_login_success, start_session},
+};
+
+fn not_configured() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({"error": "Single sign-on is not configured"})),
+    )
+}
+
+fn internal_error(e: impl std::fmt::Debug) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": format!("{:?}", e) })),
+    )
+}
+
+/// Starts an SSO login, returning the provider URL to send the user to.
+pub async fn start(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(oidc): Extension<Option<Arc<OidcClient>>>,
+) -> impl IntoResponse {
+    let Some(oidc) = oidc else {
+        return not_configured();
+    };
+
+    let attempt = LoginAttempt::generate();
+    let url = match oidc.authorization_url(&attempt).await {
+        Ok(url) => url,
+        Err(e) => {
+            tracing::error!("failed to build SSO login for {}: {:?}", oidc.issuer(), e);
+            return (
+                StatusCode::BAD_GATEWAY,
+                Json(json!({"error": "The single sign-on provider is unavailable"})),
+            );
+        }
+    };
+    match db::oidc::create_login(
+        &pool,
+        &attempt.state,
+        &attempt.nonce,
+        &attempt.code_verifier,
+    )
+    .await
+    {
+        Ok(()) => (StatusCode::OK, Json(json!({ "url": url }))),
+        Err(e) => internal_error(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct CallbackRequest {
+    code: String,
+    state: String,
+}
+/// Finishes an SSO login with the code and state the provider redirected
+/// back with, signing the user in like a password login would. The provider
+/// only stands in for the password, so users with MFA enabled are still asked
+/// for their second factor.
+pub async fn callback(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(oidc): Extension<Option<Arc<OidcClient>>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<CallbackRequest>,
+) -> impl IntoResponse {
+    let Some(oidc) = oidc else {
+        return not_configured();
+    };
+
+    let login = match db::oidc::take_login(&pool, &req.state).await {
+        Ok(Some(login)) => login,
+        Ok(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "That login has expired, please sign in again"})),
+            )
+        }
+        Err(e) => return internal_error(e),
+    };
+    let attempt = LoginAttempt {
+        state: login.state,
+        nonce: login.nonce,
+        code_verifier: login.code_verifier,
+    };
+    let identity = match oidc.exchange(&req.code, &attempt).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            tracing::warn!("SSO login failed: {:?}", e);
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "Single sign-on failed"})),
+            );
+        }
+    };
+    let mapped_role = oidc.map_role(&identity.groups);
+
+    let user = match db::oidc::get_user_by_subject(&pool, &identity.subject).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            // Only trust the provider's email to link an existing account if
+            // the provider has verified it
+            let existing = match &identity.email {
+                Some(email) if identity.email_verified => {
+                    match db::users::get_user_by_email(&pool, email).await {
+                        Ok(user) => Some(user),
+                        Err(sqlx::Error::RowNotFound) => None,
+                        Err(e) => return internal_error(e),
+                    }
+                }
+                _ => None,
+            };
+
+            match (existing, &identity.email) {
+                (Some(existing), _) => {
+                    match db::oidc::link_subject(&pool, &existing.id, &identity.subject).await {
+                        Ok(Some(user)) => user,
+                        Ok(None) => {
+                            return (
+                                StatusCode::CONFLICT,
+                                Json(
+                                    json!({"error": "Your account is linked to a different single sign-on identity"}),
+                                ),
+                            )
+                        }
+                        Err(e) => return internal_error(e),
+                    }
+                }
+                (None, Some(email)) if *features::SSO_JIT_PROVISIONING => {
+                    let display_name = identity.name.as_deref().unwrap_or(email);
+                    let role = mapped_role.unwrap_or(oidc.default_role());
+                    match db::users::create_sso_user(
+                        &pool,
+                        email,
+                        display_name,
+                        role,
+                        &identity.subject,
+                    )
+                    .await
+                    {
+                        Ok(user) => user,
+                        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                            return (
+                                StatusCode::CONFLICT,
+                                Json(json!({"error": "A user with that email already exists"})),
+                            )
+                        }
+                        Err(e) => return internal_error(e),
+                    }
+                }
+                _ => {
+                    return (
+                        StatusCode::FORBIDDEN,
+                        Json(
+                            json!({"error": "There is no account for you, ask an administrator to create one"}),
+                        ),
+                    )
+                }
+            }
+        }
+        Err(e) => return internal_error(e),
+    };
+
+    if !user.enabled {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "user is disabled"})),
+        );
+    }
+
+    // The provider's groups are the source of truth for roles, so someone
+    // taken out of every mapped group drops to the default role
+    let role = mapped_role.unwrap_or(oidc.default_role());
+    let user = if role != user.role {
+        let update = UserUpdate {
+            role: Some(role),
+            ..Default::default()
+        };
+        match db::users::update_user(&pool, &user.id, &update, &user.id).await {
+            Ok(user) => user,
+            Err(e) => return internal_error(e),
+        }
+    } else {
+        user
+    };
+
+    let ip_address = addr.ip().to_string();
+    if user.mfa_enabled {
+        return mfa_challenge(&pool, &user, &ip_address).await;
+    }
+    record_login_success(&pool, &user, &ip_address, "oidc").await;
+    start_session(&pool, user, &headers, addr).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::Response;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::db::{test_pool, users::User};
+    use crate::oidc::mock::{signing_key, MockIssuer};
+    use crate::permissions::Role;
+
+    async fn body(response: Response) -> Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    /// Goes through the provider and back as `jane@example.com`, in the
+    /// given groups.
+    async fn sign_in(pool: &Arc<Pool<Sqlite>>, groups: &[&str]) -> (StatusCode, Value) {
+        let issuer = MockIssuer::start(None);
+        let (key, jwk) = signing_key("key-1");
+        issuer.publish(&jwk);
+        let attempt = LoginAttempt::generate();
+        db::oidc::create_login(pool, &attempt.state, &attempt.nonce, &attempt.code_verifier)
+            .await
+            .unwrap();
+        let mut claims = issuer.claims(&attempt.nonce);
+        claims["groups"] = json!(groups);
+        issuer.issue(&key, "key-1", claims);
+
+        let response = callback(
+            Extension(pool.clone()),
+            Extension(Some(Arc::new(issuer.client()))),
+            ConnectInfo("127.0.0.1:4000".parse().unwrap()),
+            HeaderMap::new(),
+            Json(CallbackRequest {
+                code: String::from("auth-code"),
+                state: attempt.state,
+            }),
+        )
+        .await
+        .into_response();
+        (response.status(), body(response).await)
+    }
+
+    async fn create_jane(pool: &Pool<Sqlite>, role: Role) -> User {
+        db::users::create_user(
+            pool,
+            "jane@example.com",
+            "correct horse battery",
+            "Jane Doe",
+            None,
+            role,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn signs_in_linked_users() {
+        let pool = Arc::new(test_pool().await);
+        let jane = create_jane(&pool, Role::Dispatcher).await;
+
+        let (status, body) = sign_in(&pool, &["cad-dispatch"]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["token"].is_string());
+        assert!(body["refreshToken"].is_string());
+        let linked = db::oidc::get_user_by_subject(&pool, "248289761001")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(linked.id, jane.id);
+    }
+
+    #[tokio::test]
+    async fn asks_for_the_second_factor() {
+        let pool = Arc::new(test_pool().await);
+        let jane = create_jane(&pool, Role::Dispatcher).await;
+        db::mfa::begin_enrollment(&pool, &jane.id, "JBSWY3DPEHPK3PXP")
+            .await
+            .unwrap();
+        db::mfa::confirm_enrollment(&pool, &jane.id, 0)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let (status, body) = sign_in(&pool, &["cad-dispatch"]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["mfaRequired"], json!(true));
+        assert!(body["challenge"].is_string());
+        assert!(body.get("token").is_none());
+        assert!(body.get("refreshToken").is_none());
+    }
+
+    #[tokio::test]
+    async fn syncs_roles_from_groups() {
+        let pool = Arc::new(test_pool().await);
+        let jane = create_jane(&pool, Role::Dispatcher).await;
+
+        sign_in(&pool, &["cad-admins"]).await;
+        let user = db::users::get_user(&pool, &jane.id).await.unwrap();
+        assert_eq!(user.role, Role::Admin);
+
+        // Taken out of every mapped group in the provider
+        sign_in(&pool, &["staff"]).await;
+        let user = db::users::get_user(&pool, &jane.id).await.unwrap();
+        assert_eq!(user.role, Role::ReadOnly);
+    }
+}
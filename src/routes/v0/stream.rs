@@ -1,26 +1,249 @@
-use std::{collections::HashMap, sync::Arc};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
 
-use axum::http::StatusCode;
-use axum::response::sse::{Event as SseEvent, Sse};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
 use axum::response::Response;
 use axum::{extract::Query, response::IntoResponse, Extension};
-use serde::Serialize;
+use lazy_static::lazy_static;
 use serde_json::json;
-use tokio::sync::broadcast;
+use sqlx::{Pool, Sqlite};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    Mutex,
+};
 
-use crate::extractors::{get_user_from_token, Json};
+pub use crate::db::events::{ChangeKind, Entity, Event};
+use crate::db::{self, resources::ResourceLocation, users::User};
+use crate::extractors::{authorize, check_mfa_enrolled, get_user_from_token, Json};
+use crate::permissions::RequiredPermission;
+use crate::presence::{Presence, UserPresence};
+use crate::{features, metrics, webhooks};
 
-#[derive(Debug, Clone, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub enum Event {
-    Job(String),
-    Resource(String),
+/// The most events a subscriber will be caught up on from the log before
+/// it's told to resync instead
+const REPLAY_LIMIT: i64 = 1000;
+
+lazy_static! {
+    static ref PUBLISH_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Records a change in the event log, sends it to everyone streaming and
+/// queues it for webhooks. Events go out in the order they're logged, so the
+/// last id a client saw is enough to replay what it missed. The change itself
+/// has already been made by the time this is called, so failures are only
+/// logged.
+pub async fn publish(
+    pool: &Pool<Sqlite>,
+    event_tx: &broadcast::Sender<Event>,
+    kind: ChangeKind,
+    entity_id: &str,
+    entity: Entity,
+) {
+    let _lock = PUBLISH_LOCK.lock().await;
+    match db::events::record(pool, kind, entity_id, entity).await {
+        Ok(event) => {
+            metrics::EVENTS_PUBLISHED.fetch_add(1, Ordering::Relaxed);
+            webhooks::enqueue(pool, &event).await;
+            event_tx.send(event).ok();
+        }
+        Err(e) => tracing::error!("failed to record event for {}: {}", entity_id, e),
+    }
+}
+
+pub async fn publish_job(
+    pool: &Pool<Sqlite>,
+    event_tx: &broadcast::Sender<Event>,
+    kind: ChangeKind,
+    job_id: &str,
+) {
+    match db::jobs::get_job_by_id(pool, job_id).await {
+        Ok(Some(job)) => publish(pool, event_tx, kind, job_id, Entity::Job(job)).await,
+        Ok(None) => tracing::warn!("not publishing event for missing job {}", job_id),
+        Err(e) => tracing::error!("failed to fetch job {} for event: {}", job_id, e),
+    }
+}
+
+pub async fn publish_resource(
+    pool: &Pool<Sqlite>,
+    event_tx: &broadcast::Sender<Event>,
+    kind: ChangeKind,
+    resource_id: &str,
+) {
+    match db::resources::get(pool, resource_id).await {
+        Ok(Some(resource)) => {
+            publish(
+                pool,
+                event_tx,
+                kind,
+                resource_id,
+                Entity::Resource(resource),
+            )
+            .await
+        }
+        Ok(None) => tracing::warn!("not publishing event for missing resource {}", resource_id),
+        Err(e) => tracing::error!("failed to fetch resource {} for event: {}", resource_id, e),
+    }
+}
+
+pub async fn publish_location(
+    pool: &Pool<Sqlite>,
+    event_tx: &broadcast::Sender<Event>,
+    location: ResourceLocation,
+) {
+    let resource_id = location.resource_id.clone();
+    publish(
+        pool,
+        event_tx,
+        ChangeKind::Updated,
+        &resource_id,
+        Entity::ResourceLocation(location),
+    )
+    .await
+}
+
+pub enum Delivery {
+    Event(Box<Event>),
+    /// Events were missed that can't be replayed, so the client has to fetch
+    /// the current state again
+    Resync,
+}
+
+/// One client's feed of events. Each subscriber keeps its own place in the
+/// live buffer, so a slow one never holds up the rest. One that falls out of
+/// the buffer is caught up from the event log, and told to resync if the log
+/// can't cover the gap.
+pub struct Subscription {
+    pool: Arc<Pool<Sqlite>>,
+    rx: broadcast::Receiver<Event>,
+    last_sent: i64,
+    /// Where to catch up from before reading the live buffer again
+    catch_up_from: Option<i64>,
+    backlog: VecDeque<Event>,
+}
+
+impl Subscription {
+    /// Subscribes to new events, replaying any after `last_event_id` first.
+    pub fn new(
+        pool: Arc<Pool<Sqlite>>,
+        event_tx: &broadcast::Sender<Event>,
+        last_event_id: Option<i64>,
+    ) -> Self {
+        metrics::EVENT_SUBSCRIBERS.fetch_add(1, Ordering::Relaxed);
+        // Subscribing before reading the log means nothing published in
+        // between is missed, and anything sent twice is skipped by id
+        Self {
+            pool,
+            rx: event_tx.subscribe(),
+            last_sent: last_event_id.unwrap_or(0),
+            catch_up_from: last_event_id,
+            backlog: VecDeque::new(),
+        }
+    }
+
+    /// The next thing to send, or `None` once the server is shutting down.
+    /// Safe to cancel, so it can be raced against other work in `select!`.
+    pub async fn next(&mut self) -> Option<Delivery> {
+        loop {
+            if let Some(event) = self.backlog.pop_front() {
+                self.last_sent = event.id;
+                return Some(Delivery::Event(Box::new(event)));
+            }
+            if let Some(after) = self.catch_up_from {
+                let replay = db::events::list_since(&self.pool, after, REPLAY_LIMIT).await;
+                self.catch_up_from = None;
+                match replay {
+                    Ok(Some(events)) => {
+                        self.backlog = events.into();
+                        continue;
+                    }
+                    Ok(None) => {}
+                    Err(e) => tracing::error!("failed to replay events: {}", e),
+                }
+                metrics::EVENT_SUBSCRIBER_RESYNCS.fetch_add(1, Ordering::Relaxed);
+                return Some(Delivery::Resync);
+            }
+
+            match self.rx.recv().await {
+                Ok(event) if event.id <= self.last_sent => continue,
+                Ok(event) => {
+                    self.last_sent = event.id;
+                    return Some(Delivery::Event(Box::new(event)));
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("event subscriber fell {} events behind", missed);
+                    metrics::EVENT_SUBSCRIBER_LAGGED.fetch_add(missed, Ordering::Relaxed);
+                    metrics::EVENT_SUBSCRIBER_MAX_LAG.fetch_max(missed, Ordering::Relaxed);
+                    // Without anything sent yet there's no place to replay
+                    // from, so go straight to a resync
+                    self.catch_up_from = Some(self.last_sent).filter(|id| *id > 0);
+                    if self.catch_up_from.is_none() {
+                        metrics::EVENT_SUBSCRIBER_RESYNCS.fetch_add(1, Ordering::Relaxed);
+                        return Some(Delivery::Resync);
+                    }
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        metrics::EVENT_SUBSCRIBERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Presence goes out as named events, so consoles that only listen for
+/// messages don't see it. A subscriber that falls behind is sent everyone.
+fn presence_event(
+    update: Result<UserPresence, RecvError>,
+    presence: &Presence,
+) -> Option<Result<SseEvent, axum::Error>> {
+    match update {
+        Ok(update) => Some(SseEvent::default().event("presence").json_data(update)),
+        Err(RecvError::Lagged(_)) => Some(
+            SseEvent::default()
+                .event("presence_list")
+                .json_data(presence.list()),
+        ),
+        Err(RecvError::Closed) => None,
+    }
+}
+
+fn sse_event(delivery: &Delivery) -> Result<SseEvent, axum::Error> {
+    match delivery {
+        Delivery::Event(event) => SseEvent::default()
+            .id(event.id.to_string())
+            .json_data(event.as_ref()),
+        Delivery::Resync => SseEvent::default().json_data(json!({"type": "resync"})),
+    }
+}
+
+/// Whether the session a stream was opened with is still signed in. Streams
+/// outlive the token they were opened with, so logging out, being disabled or
+/// resetting a password has to close them too. API keys don't have sessions.
+pub(crate) async fn signed_in(pool: &Pool<Sqlite>, user: &User) -> bool {
+    let Some(session_id) = &user.session_id else {
+        return true;
+    };
+    match db::sessions::is_active(pool, session_id, &user.id).await {
+        Ok(active) => active,
+        Err(e) => {
+            tracing::error!("failed to check stream session: {}", e);
+            true
+        }
+    }
 }
 
 pub async fn stream(
     Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
     Extension(pool): Extension<Arc<sqlx::Pool<sqlx::Sqlite>>>,
     Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
+    Extension(presence): Extension<Arc<Presence>>,
+    required: Option<Extension<RequiredPermission>>,
 ) -> Response {
     let user = match params.get("token") {
         Some(token) => {
@@ -32,7 +255,36 @@ pub async fn stream(
         }
         None => return (StatusCode::UNAUTHORIZED, Json(json!("missing token"))).into_response(),
     };
+    if let Err(e) = authorize(&user, required.map(|Extension(r)| r)) {
+        return e.into_response();
+    }
+    if let Err(e) = check_mfa_enrolled(&pool, &user).await {
+        return e.into_response();
+    }
+
+    // Browsers send the last id they saw when they reconnect. Clients that
+    // manage their own reconnects can pass it in the query string instead.
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .or(params.get("lastEventId").map(String::as_str))
+        .and_then(|id| id.parse::<i64>().ok());
 
+    let heartbeat = Duration::from_secs(*features::STREAM_HEARTBEAT_SECONDS);
+    events(pool, event_tx, presence, user, last_event_id, heartbeat)
+}
+
+/// Streams events to a user until they disconnect or the session the stream
+/// was opened with is signed out. Sessions are checked as often as idle
+/// streams are sent a keep-alive.
+fn events(
+    pool: Arc<Pool<Sqlite>>,
+    event_tx: Arc<broadcast::Sender<Event>>,
+    presence: Arc<Presence>,
+    user: User,
+    last_event_id: Option<i64>,
+    heartbeat: Duration,
+) -> Response {
     struct Guard {
         user_id: String,
     }
@@ -48,12 +300,99 @@ pub async fn stream(
             user_id: user.id.clone()
         };
         tracing::debug!(user=guard.user_id, "opened stream");
-        let mut rx = event_tx.subscribe();
-        while let Ok(event) = rx.recv().await {
+        let mut subscription = Subscription::new(pool.clone(), &event_tx, last_event_id);
+        let mut presence_rx = presence.subscribe();
+        let _connected = presence.connect(&user);
+        let mut session_check = tokio::time::interval(heartbeat);
+        session_check.tick().await;
+        loop {
+            let event = tokio::select! {
+                delivery = subscription.next() => delivery.map(|delivery| sse_event(&delivery)),
+                update = presence_rx.recv() => presence_event(update, &presence),
+                _ = session_check.tick() => {
+                    if signed_in(&pool, &user).await {
+                        continue;
+                    }
+                    yield SseEvent::default()
+                        .event("revoked")
+                        .json_data(json!({"error": "session has been revoked"}));
+                    break;
+                }
+            };
+            let Some(event) = event else {
+                break;
+            };
             tracing::debug!(user=guard.user_id, "sent event");
-            yield SseEvent::default().json_data(event);
+            yield event;
         }
     };
 
-    Sse::new(stream).into_response()
+    Sse::new(stream)
+        .keep_alive(KeepAlive::new().interval(heartbeat))
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{sessions, test_pool, users};
+    use crate::permissions::Role;
+
+    async fn signed_in_user(pool: &Pool<Sqlite>) -> User {
+        let mut user = users::create_user(
+            pool,
+            "dispatch@example.com",
+            "password",
+            "Dispatch",
+            None,
+            Role::Dispatcher,
+            None,
+        )
+        .await
+        .unwrap();
+        let (session, _) = sessions::create(pool, &user.id, None, None).await.unwrap();
+        user.session_id = Some(session.id);
+        user
+    }
+
+    fn open(pool: &Arc<Pool<Sqlite>>, user: User) -> Response {
+        let (event_tx, _) = broadcast::channel(16);
+        events(
+            pool.clone(),
+            Arc::new(event_tx),
+            Arc::new(Presence::default()),
+            user,
+            None,
+            Duration::from_millis(100),
+        )
+    }
+
+    #[tokio::test]
+    async fn stays_open_while_signed_in() {
+        let pool = Arc::new(test_pool().await);
+        let user = signed_in_user(&pool).await;
+
+        let body = axum::body::to_bytes(open(&pool, user).into_body(), usize::MAX);
+        assert!(tokio::time::timeout(Duration::from_millis(500), body)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn closes_when_the_session_is_revoked() {
+        let pool = Arc::new(test_pool().await);
+        let user = signed_in_user(&pool).await;
+        let (user_id, session_id) = (user.id.clone(), user.session_id.clone().unwrap());
+        let response = open(&pool, user);
+
+        sessions::revoke(&pool, &session_id, &user_id, &user_id)
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX);
+        let body = tokio::time::timeout(Duration::from_secs(5), body)
+            .await
+            .expect("stream should close")
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("event: revoked"));
+    }
 }
@@ -0,0 +1,244 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{extract::Query, http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    db::{self, users::UserUpdate},
+    extractors::Authorized,
+    permissions::Role,
+    routes::v0::login::check_password_strength,
+};
+
+fn user_error_response(e: sqlx::Error) -> (StatusCode, Json<serde_json::Value>) {
+    match e {
+        sqlx::Error::RowNotFound => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that user does not exist"})),
+        ),
+        sqlx::Error::Database(e) if e.is_unique_violation() => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "A user with that email already exists"})),
+        ),
+        e => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+pub async fn get_all_users(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    match db::users::list_users(&pool).await {
+        Ok(users) => (StatusCode::OK, Json(json!(users))),
+        Err(e) => user_error_response(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UserCreationRequest {
+    email: String,
+    password: String,
+    display_name: String,
+    phone: Option<String>,
+    #[serde(default)]
+    role: Role,
+}
+pub async fn create(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<UserCreationRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = check_password_strength(&req.password) {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": e })));
+    }
+    let created = db::users::create_user(
+        &pool,
+        &req.email,
+        &req.password,
+        &req.display_name,
+        req.phone.as_deref(),
+        req.role,
+        Some(&user.id),
+    )
+    .await;
+    match created {
+        Ok(created) => (StatusCode::OK, Json(json!(created))),
+        Err(e) => user_error_response(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UserUpdateRequest {
+    id: String,
+    #[serde(flatten)]
+    update: UserUpdate,
+}
+pub async fn update(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<UserUpdateRequest>,
+) -> impl IntoResponse {
+    // Admins can't demote themselves, so someone is always left to undo a change
+    if req.id == user.id && req.update.role.is_some_and(|role| role != user.role) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "you cannot change your own role"})),
+        );
+    }
+    match db::users::update_user(&pool, &req.id, &req.update, &user.id).await {
+        Ok(updated) => (StatusCode::OK, Json(json!(updated))),
+        Err(e) => user_error_response(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UserEnabledRequest {
+    id: String,
+    enabled: bool,
+}
+pub async fn set_enabled(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<UserEnabledRequest>,
+) -> impl IntoResponse {
+    if req.id == user.id && !req.enabled {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "you cannot disable your own account"})),
+        );
+    }
+    match db::users::set_enabled(&pool, &req.id, req.enabled, &user.id).await {
+        Ok(updated) => (StatusCode::OK, Json(json!(updated))),
+        Err(e) => user_error_response(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UserRequest {
+    id: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PasswordResetRequest {
+    id: String,
+    password: String,
+}
+pub async fn reset_password(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<PasswordResetRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = check_password_strength(&req.password) {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": e })));
+    }
+    match db::users::set_password(&pool, &req.id, &req.password, &user.id).await {
+        Ok(updated) => (StatusCode::OK, Json(json!(updated))),
+        Err(e) => user_error_response(e),
+    }
+}
+
+/// Issues a one-time code the user can set a new password with, for when an
+/// admin shouldn't know the new password themselves. Only a hash of the code
+/// is stored, so the plaintext code is in this response and nowhere else:
+/// it can't be fetched again, and a lost code means issuing a new one.
+pub async fn create_reset_code(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<UserRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = db::users::get_user(&pool, &req.id).await {
+        return user_error_response(e);
+    }
+    match db::password_resets::create(&pool, &req.id, &user.id).await {
+        Ok((reset, code)) => (
+            StatusCode::OK,
+            Json(json!({"code": code, "expiresAt": reset.expires_at})),
+        ),
+        Err(e) => user_error_response(e),
+    }
+}
+
+pub async fn get_login_throttles(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    match db::login_throttles::list(&pool).await {
+        Ok(throttles) => (StatusCode::OK, Json(json!(throttles))),
+        Err(e) => user_error_response(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UnlockRequest {
+    id: Option<String>,
+    ip: Option<String>,
+}
+/// Clears failed logins for a user, an address, or both, lifting any lockout.
+pub async fn unlock(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<UnlockRequest>,
+) -> impl IntoResponse {
+    let mut keys = Vec::new();
+    if let Some(id) = &req.id {
+        match db::users::get_user(&pool, id).await {
+            Ok(target) => keys.push(db::login_throttles::account_key(&target.email)),
+            Err(e) => return user_error_response(e),
+        }
+    }
+    if let Some(ip) = &req.ip {
+        keys.push(db::login_throttles::ip_key(ip));
+    }
+    if keys.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "a user id or ip is required"})),
+        );
+    }
+
+    for key in &keys {
+        if let Err(e) = db::login_throttles::clear(&pool, key).await {
+            return user_error_response(e);
+        }
+    }
+    let audited = db::audit::record(
+        &*pool,
+        Some(&user.id),
+        "login.unlocked",
+        "user",
+        req.id.as_deref(),
+        Some(json!({ "keys": keys })),
+    )
+    .await;
+    match audited {
+        Ok(()) => (StatusCode::OK, Json(json!({}))),
+        Err(e) => user_error_response(e),
+    }
+}
+
+pub async fn get_audit_log(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Query(params): Query<HashMap<String, String>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    let target_type = params.get("targetType").map(String::as_str);
+    let target_id = params.get("targetId").map(String::as_str);
+    match db::audit::list(&pool, target_type, target_id).await {
+        Ok(entries) => (StatusCode::OK, Json(json!(entries))),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
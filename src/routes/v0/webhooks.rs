@@ -0,0 +1,189 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{extract::Query, http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    db::{self, webhooks::DeliveryState},
+    extractors::Authorized,
+};
+
+fn error_response(e: sqlx::Error) -> (StatusCode, Json<Value>) {
+    match e {
+        sqlx::Error::RowNotFound => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that webhook does not exist"})),
+        ),
+        e => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!(e.to_string())),
+        ),
+    }
+}
+
+/// Receivers can be on the local network, but they have to speak HTTP.
+fn check_url(url: &str) -> Result<(), (StatusCode, Json<Value>)> {
+    match url::Url::parse(url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        _ => Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "url must be an http or https URL"})),
+        )),
+    }
+}
+
+fn check_events(events: &[String]) -> Result<(), (StatusCode, Json<Value>)> {
+    if events.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "a webhook needs at least one event, or \"*\" for all of them"})),
+        ));
+    }
+    Ok(())
+}
+
+pub async fn get_all_webhooks(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    match db::webhooks::list(&pool).await {
+        Ok(webhooks) => (StatusCode::OK, Json(json!(webhooks))),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct WebhookCreationRequest {
+    url: String,
+    events: Vec<String>,
+    secret: Option<String>,
+}
+pub async fn create(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<WebhookCreationRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = check_url(&req.url).and(check_events(&req.events)) {
+        return e;
+    }
+
+    match db::webhooks::create(&pool, &req.url, &req.events, req.secret, &user.id).await {
+        Ok((webhook, secret)) => (
+            StatusCode::OK,
+            Json(json!({"webhook": webhook, "secret": secret})),
+        ),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct WebhookUpdateRequest {
+    id: String,
+    url: Option<String>,
+    events: Option<Vec<String>>,
+    enabled: Option<bool>,
+}
+pub async fn update(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<WebhookUpdateRequest>,
+) -> impl IntoResponse {
+    if let Some(url) = &req.url {
+        if let Err(e) = check_url(url) {
+            return e;
+        }
+    }
+    if let Some(events) = &req.events {
+        if let Err(e) = check_events(events) {
+            return e;
+        }
+    }
+
+    match db::webhooks::update(
+        &pool,
+        &req.id,
+        req.url.as_deref(),
+        req.events.as_deref(),
+        req.enabled,
+        &user.id,
+    )
+    .await
+    {
+        Ok(webhook) => (StatusCode::OK, Json(json!(webhook))),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct WebhookRequest {
+    id: String,
+}
+pub async fn delete(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<WebhookRequest>,
+) -> impl IntoResponse {
+    match db::webhooks::delete(&pool, &req.id, &user.id).await {
+        Ok(()) => (StatusCode::OK, Json(json!({}))),
+        Err(e) => error_response(e),
+    }
+}
+
+/// The delivery log, newest first. Can be narrowed to one webhook with
+/// `webhookId` and to `pending`, `delivered` or `dead` deliveries with
+/// `state`.
+pub async fn get_deliveries(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Query(params): Query<HashMap<String, String>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    let webhook_id = params.get("webhookId").map(String::as_str);
+    let state = match params.get("state") {
+        Some(state) => match serde_json::from_value::<DeliveryState>(json!(state)) {
+            Ok(state) => Some(state),
+            Err(_) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "state must be pending, delivered or dead"})),
+                )
+            }
+        },
+        None => None,
+    };
+    match db::webhooks::list_deliveries(&pool, webhook_id, state).await {
+        Ok(deliveries) => (StatusCode::OK, Json(json!(deliveries))),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Deliveries that were given up on, across every webhook.
+pub async fn get_dead_letters(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(_user): Authorized,
+) -> impl IntoResponse {
+    match db::webhooks::list_deliveries(&pool, None, Some(DeliveryState::Dead)).await {
+        Ok(deliveries) => (StatusCode::OK, Json(json!(deliveries))),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Queues a dead letter to be sent again, once the receiver is fixed.
+pub async fn redeliver(
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Authorized(user): Authorized,
+    Json(req): Json<WebhookRequest>,
+) -> impl IntoResponse {
+    match db::webhooks::redeliver(&pool, &req.id, &user.id).await {
+        Ok(delivery) => (StatusCode::OK, Json(json!(delivery))),
+        Err(sqlx::Error::RowNotFound) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "that delivery does not exist or is not a dead letter"})),
+        ),
+        Err(e) => error_response(e),
+    }
+}
@@ -0,0 +1,390 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{Pool, Sqlite};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::resources::{acknowledge_assignment, update_status};
+use super::stream::{self, ChangeKind, Delivery, Entity, Event, Subscription};
+use crate::db::{self, resources::ResourceStatus, users::User};
+use crate::extractors::{authorize, check_mfa_enrolled, get_user_from_token};
+use crate::permissions::{Permission, RequiredPermission};
+use crate::presence::Presence;
+use crate::{features, geo};
+
+/// How long a client has to send its token after connecting
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a client can ask to be sent events for.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "topic", rename_all = "snake_case")]
+pub(crate) enum Topic {
+    Jobs,
+    Job {
+        id: String,
+    },
+    /// Jobs with coordinates inside a circle
+    #[serde(rename_all = "camelCase")]
+    JobsInArea {
+        latitude: f64,
+        longitude: f64,
+        radius_meters: f64,
+    },
+    /// Every resource, including location updates
+    Resources,
+    /// The units the user is crewing and the jobs they're assigned to
+    MyUnit,
+    /// Who's online and which job each of them has open
+    Presence,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ClientMessage {
+    #[serde(rename_all = "camelCase")]
+    Auth {
+        token: String,
+        last_event_id: Option<i64>,
+    },
+    Subscribe(Topic),
+    Unsubscribe(Topic),
+    #[serde(rename_all = "camelCase")]
+    ResourceStatus {
+        request_id: Option<String>,
+        resource_id: String,
+        status: ResourceStatus,
+        note: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Acknowledge {
+        request_id: Option<String>,
+        assignment_id: String,
+    },
+    /// The job open on the user's console. Sent often, so it isn't answered.
+    #[serde(rename_all = "camelCase")]
+    Activity {
+        job_id: Option<String>,
+        #[serde(default)]
+        editing: bool,
+    },
+}
+
+async fn authenticate(
+    pool: &Pool<Sqlite>,
+    token: &str,
+    required: Option<RequiredPermission>,
+) -> Result<User, Value> {
+    let user = get_user_from_token(pool, token).await.map_err(|e| e.1 .0)?;
+    authorize(&user, required).map_err(|e| e.1 .0)?;
+    check_mfa_enrolled(pool, &user).await.map_err(|e| e.1 .0)?;
+    Ok(user)
+}
+
+/// An error reply. Bodies from the HTTP handlers are merged in so clients see
+/// the same fields either way.
+fn error_message(request_id: Option<&str>, body: Value) -> Value {
+    let mut message = match body {
+        Value::Object(fields) => Value::Object(fields),
+        other => json!({ "error": other }),
+    };
+    message["type"] = json!("error");
+    if let Some(request_id) = request_id {
+        message["requestId"] = json!(request_id);
+    }
+    message
+}
+
+async fn send(socket: &mut WebSocket, message: Value) -> bool {
+    socket
+        .send(Message::Text(message.to_string()))
+        .await
+        .is_ok()
+}
+
+/// Waits for the client's `auth` message. Browsers can't set headers on a
+/// WebSocket, and tokens in the query string end up in access logs, so this
+/// is how they sign in.
+async fn wait_for_auth(
+    socket: &mut WebSocket,
+    pool: &Pool<Sqlite>,
+    required: Option<RequiredPermission>,
+) -> Result<(User, Option<i64>), Value> {
+    let message = tokio::time::timeout(AUTH_TIMEOUT, socket.recv())
+        .await
+        .map_err(|_| json!({"error": "timed out waiting for auth"}))?;
+    let Some(Ok(Message::Text(text))) = message else {
+        return Err(json!({"error": "expected an auth message"}));
+    };
+    match serde_json::from_str::<ClientMessage>(&text) {
+        Ok(ClientMessage::Auth {
+            token,
+            last_event_id,
+        }) => Ok((authenticate(pool, &token, required).await?, last_event_id)),
+        _ => Err(json!({"error": "expected an auth message"})),
+    }
+}
+
+pub async fn websocket(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Extension(pool): Extension<Arc<Pool<Sqlite>>>,
+    Extension(event_tx): Extension<Arc<broadcast::Sender<Event>>>,
+    Extension(presence): Extension<Arc<Presence>>,
+    required: Option<Extension<RequiredPermission>>,
+) -> Response {
+    let required = required.map(|Extension(r)| r);
+
+    // Clients that can set headers authenticate before the upgrade, and the
+    // rest send an auth message once connected
+    let user = match headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(header) => {
+            let token = header.replace("Bearer ", "");
+            match authenticate(&pool, &token, required).await {
+                Ok(user) => Some(user),
+                Err(e) => return (StatusCode::UNAUTHORIZED, axum::Json(e)).into_response(),
+            }
+        }
+        None => None,
+    };
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|id| id.parse::<i64>().ok());
+
+    ws.on_upgrade(move |mut socket| async move {
+        let (user, last_event_id) = match user {
+            Some(user) => (user, last_event_id),
+            None => match wait_for_auth(&mut socket, &pool, required).await {
+                Ok(authenticated) => authenticated,
+                Err(e) => {
+                    send(&mut socket, error_message(None, e)).await;
+                    socket.send(Message::Close(None)).await.ok();
+                    return;
+                }
+            },
+        };
+        tracing::debug!(user = user.id, "opened websocket");
+        let user_id = user.id.clone();
+        let connection = Connection {
+            pool,
+            event_tx,
+            presence,
+            user,
+            topics: Vec::new(),
+            my_resources: Vec::new(),
+        };
+        connection.run(socket, last_event_id).await;
+        tracing::debug!(user = user_id, "closed websocket");
+    })
+}
+
+struct Connection {
+    pool: Arc<Pool<Sqlite>>,
+    event_tx: Arc<broadcast::Sender<Event>>,
+    presence: Arc<Presence>,
+    user: User,
+    topics: Vec<Topic>,
+    /// The units the user was crewing when they subscribed to `MyUnit`
+    my_resources: Vec<String>,
+}
+
+impl Connection {
+    async fn run(mut self, mut socket: WebSocket, last_event_id: Option<i64>) {
+        if !send(
+            &mut socket,
+            json!({"type": "authenticated", "user": self.user}),
+        )
+        .await
+        {
+            return;
+        }
+
+        let mut subscription = Subscription::new(self.pool.clone(), &self.event_tx, last_event_id);
+        let mut presence_rx = self.presence.subscribe();
+        let _connected = self.presence.connect(&self.user);
+        let mut heartbeat =
+            tokio::time::interval(Duration::from_secs(*features::STREAM_HEARTBEAT_SECONDS));
+        heartbeat.tick().await;
+
+        loop {
+            let reply = tokio::select! {
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Text(text))) => self.handle(&text).await,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // Pings are answered for us, and nothing is sent as binary
+                    Some(Ok(_)) => None,
+                },
+                delivery = subscription.next() => match delivery {
+                    Some(Delivery::Event(event)) if self.wants(&event) => {
+                        Some(json!({"type": "event", "event": event}))
+                    }
+                    Some(Delivery::Event(_)) => None,
+                    Some(Delivery::Resync) => Some(json!({"type": "resync"})),
+                    None => break,
+                },
+                update = presence_rx.recv() => match update {
+                    Err(RecvError::Closed) => break,
+                    _ if !self.topics.contains(&Topic::Presence) => None,
+                    Ok(update) => Some(json!({"type": "presence", "presence": update})),
+                    Err(RecvError::Lagged(_)) => {
+                        Some(json!({"type": "presence_list", "presence": self.presence.list()}))
+                    }
+                },
+                _ = heartbeat.tick() => {
+                    // The socket outlives the token it was opened with, so
+                    // signing out elsewhere has to close it too
+                    if !stream::signed_in(&self.pool, &self.user).await {
+                        send(&mut socket, error_message(None, json!({"error": "session has been revoked"}))).await;
+                        socket.send(Message::Close(None)).await.ok();
+                        break;
+                    }
+                    if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                        break;
+                    }
+                    None
+                }
+            };
+            if let Some(reply) = reply {
+                if !send(&mut socket, reply).await {
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, text: &str) -> Option<Value> {
+        let message = match serde_json::from_str::<ClientMessage>(text) {
+            Ok(message) => message,
+            Err(e) => return Some(error_message(None, json!({"error": e.to_string()}))),
+        };
+        match message {
+            ClientMessage::Activity { job_id, editing } => {
+                self.presence.set_activity(&self.user.id, job_id, editing);
+                None
+            }
+            ClientMessage::Auth { .. } => Some(error_message(
+                None,
+                json!({"error": "already authenticated"}),
+            )),
+            ClientMessage::Subscribe(topic) => {
+                if topic == Topic::MyUnit {
+                    match db::resources::bound_to_user(&self.pool, &self.user.id).await {
+                        Ok(resources) => self.my_resources = resources,
+                        Err(e) => return Some(error_message(None, json!(e.to_string()))),
+                    }
+                }
+                if !self.topics.contains(&topic) {
+                    self.topics.push(topic.clone());
+                }
+                // Presence changes only say who changed, so start from
+                // everyone who's already online
+                if topic == Topic::Presence {
+                    return Some(json!({
+                        "type": "subscribed",
+                        "subscription": topic,
+                        "presence": self.presence.list(),
+                    }));
+                }
+                Some(json!({"type": "subscribed", "subscription": topic}))
+            }
+            ClientMessage::Unsubscribe(topic) => {
+                self.topics.retain(|t| *t != topic);
+                Some(json!({"type": "unsubscribed", "subscription": topic}))
+            }
+            ClientMessage::ResourceStatus {
+                request_id,
+                resource_id,
+                status,
+                note,
+            } => {
+                let result = match self.check_permission(Permission::UpdateResourceStatus) {
+                    Ok(()) => update_status(
+                        &self.pool,
+                        &self.event_tx,
+                        &self.user,
+                        &resource_id,
+                        status,
+                        note,
+                    )
+                    .await
+                    .map(|change| json!(change))
+                    .map_err(|e| e.1 .0),
+                    Err(e) => Err(e),
+                };
+                Some(result_message(request_id.as_deref(), result))
+            }
+            ClientMessage::Acknowledge {
+                request_id,
+                assignment_id,
+            } => {
+                let result = match self.check_permission(Permission::UpdateResourceStatus) {
+                    Ok(()) => acknowledge_assignment(
+                        &self.pool,
+                        &self.event_tx,
+                        &self.user,
+                        &assignment_id,
+                    )
+                    .await
+                    .map(|assignment| json!(assignment))
+                    .map_err(|e| e.1 .0),
+                    Err(e) => Err(e),
+                };
+                Some(result_message(request_id.as_deref(), result))
+            }
+        }
+    }
+
+    fn check_permission(&self, permission: Permission) -> Result<(), Value> {
+        authorize(&self.user, Some(RequiredPermission(permission))).map_err(|e| e.1 .0)
+    }
+
+    fn wants(&self, event: &Event) -> bool {
+        self.topics
+            .iter()
+            .any(|topic| match (topic, &event.entity) {
+                (Topic::Jobs, Entity::Job(_)) => true,
+                (Topic::Job { id }, Entity::Job(_)) => *id == event.entity_id,
+                (
+                    Topic::JobsInArea {
+                        latitude,
+                        longitude,
+                        radius_meters,
+                    },
+                    Entity::Job(job),
+                ) => match (job.latitude, job.longitude) {
+                    (Some(lat), Some(lon)) => {
+                        geo::distance_m(*latitude, *longitude, lat, lon) <= *radius_meters
+                    }
+                    _ => false,
+                },
+                (Topic::Resources, Entity::Resource(_) | Entity::ResourceLocation(_)) => true,
+                (Topic::MyUnit, Entity::Resource(_) | Entity::ResourceLocation(_)) => {
+                    self.my_resources.contains(&event.entity_id)
+                }
+                // A unit hears about the jobs it's on, including the one it was
+                // just taken off or that was closed under it
+                (Topic::MyUnit, Entity::Job(job)) => job.assignments.iter().any(|assignment| {
+                    self.my_resources.contains(&assignment.resource_id)
+                        && (assignment.removed_at.is_none()
+                            || matches!(event.kind, ChangeKind::Unassigned | ChangeKind::Closed))
+                }),
+                _ => false,
+            })
+    }
+}
+
+fn result_message(request_id: Option<&str>, result: Result<Value, Value>) -> Value {
+    match result {
+        Ok(data) => json!({"type": "result", "requestId": request_id, "data": data}),
+        Err(e) => error_message(request_id, e),
+    }
+}
@@ -0,0 +1,267 @@
+//! Keys for signing and verifying our access tokens. Tokens are signed with
+//! RS256 or ES256 keys identified by `kid`, and the public halves are
+//! published as a JWKS so other services can verify them without a shared
+//! secret.
+//!
+//! Keys are PEM files in `JWT_KEYS_DIR`, named `<kid>.pem`. New tokens are
+//! signed with `JWT_SIGNING_KEY_ID`, or the last key in name order if that's
+//! unset, so dated names like `2026-10-18.pem` rotate by adding a file. Old
+//! keys should stay in the directory until tokens signed with them expire.
+//!
+//! Without `JWT_KEYS_DIR`, tokens are signed with HS256 and `JWT_SECRET` as
+//! before. When both are set the secret is only used to verify, so switching
+//! over doesn't sign everyone out.
+
+use std::{env, fs, path::Path};
+
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+        EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, PublicKeyUse, RSAKeyParameters,
+        RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation,
+};
+use lazy_static::lazy_static;
+use serde::{de::DeserializeOwned, Serialize};
+
+lazy_static! {
+    pub static ref KEYS: Keys = Keys::load().expect("failed to load token signing keys");
+}
+
+/// The `iss` of every token we sign
+pub const ISSUER: &str = "integral";
+
+/// RSA keys shorter than this are refused
+const MIN_RSA_BITS: usize = 2048;
+
+struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    jwk: Jwk,
+}
+
+pub struct Keys {
+    keys: Vec<SigningKey>,
+    /// Index into `keys` of the key new tokens are signed with
+    current: Option<usize>,
+    legacy: Option<(EncodingKey, DecodingKey)>,
+}
+
+impl Keys {
+    fn load() -> anyhow::Result<Self> {
+        // Tests sign with a fixed secret rather than whatever's in the
+        // environment
+        if cfg!(test) {
+            return Ok(Self {
+                keys: Vec::new(),
+                current: None,
+                legacy: Some(legacy_key("integral test secret")),
+            });
+        }
+        Self::from_env()
+    }
+
+    fn from_env() -> anyhow::Result<Self> {
+        let legacy = env::var("JWT_SECRET")
+            .ok()
+            .map(|secret| legacy_key(&secret));
+
+        let Ok(dir) = env::var("JWT_KEYS_DIR") else {
+            if legacy.is_none() {
+                bail!("one of JWT_KEYS_DIR or JWT_SECRET must be set");
+            }
+            return Ok(Self {
+                keys: Vec::new(),
+                current: None,
+                legacy,
+            });
+        };
+
+        let mut paths = fs::read_dir(&dir)
+            .with_context(|| format!("reading {}", dir))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "pem"))
+            .collect::<Vec<_>>();
+        paths.sort();
+        let keys = paths
+            .iter()
+            .map(|path| load_key(path).with_context(|| format!("loading {}", path.display())))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let current = match env::var("JWT_SIGNING_KEY_ID") {
+            Ok(kid) => keys
+                .iter()
+                .position(|key| key.kid == kid)
+                .ok_or_else(|| anyhow!("JWT_SIGNING_KEY_ID {} is not in {}", kid, dir))?,
+            Err(_) => keys
+                .len()
+                .checked_sub(1)
+                .ok_or_else(|| anyhow!("no .pem keys found in {}", dir))?,
+        };
+
+        Ok(Self {
+            keys,
+            current: Some(current),
+            legacy,
+        })
+    }
+
+    /// The key id new tokens are signed with, or `None` for the legacy secret.
+    pub fn current_kid(&self) -> Option<&str> {
+        self.current.map(|i| self.keys[i].kid.as_str())
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> jsonwebtoken::errors::Result<String> {
+        match self.current.map(|i| &self.keys[i]) {
+            Some(key) => {
+                let mut header = Header::new(key.algorithm);
+                header.kid = Some(key.kid.clone());
+                jsonwebtoken::encode(&header, claims, &key.encoding)
+            }
+            None => {
+                let (encoding, _) = self.legacy.as_ref().expect("no signing key configured");
+                jsonwebtoken::encode(&Header::new(Algorithm::HS256), claims, encoding)
+            }
+        }
+    }
+
+    /// Checks a token's signature against the key its header names. The
+    /// algorithm comes from our key rather than the token, so a token can't
+    /// pick a weaker one. Claims are left to `validation`.
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        mut validation: Validation,
+    ) -> jsonwebtoken::errors::Result<TokenData<T>> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let (algorithm, decoding) = match &header.kid {
+            Some(kid) => self
+                .keys
+                .iter()
+                .find(|key| &key.kid == kid)
+                .map(|key| (key.algorithm, &key.decoding)),
+            None => self
+                .legacy
+                .as_ref()
+                .map(|(_, decoding)| (Algorithm::HS256, decoding)),
+        }
+        .ok_or(jsonwebtoken::errors::ErrorKind::InvalidSignature)?;
+
+        validation.algorithms = vec![algorithm];
+        jsonwebtoken::decode(token, decoding, &validation)
+    }
+
+    /// The public keys tokens may be signed with, for `/.well-known/jwks.json`.
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.keys.iter().map(|key| key.jwk.clone()).collect(),
+        }
+    }
+}
+
+fn legacy_key(secret: &str) -> (EncodingKey, DecodingKey) {
+    (
+        EncodingKey::from_secret(secret.as_bytes()),
+        DecodingKey::from_secret(secret.as_bytes()),
+    )
+}
+
+fn load_key(path: &Path) -> anyhow::Result<SigningKey> {
+    let kid = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| anyhow!("key file names must be valid UTF-8"))?
+        .to_string();
+    let pem = fs::read_to_string(path)?;
+
+    let (algorithm, encoding, algorithm_parameters) = match read_rsa_key(&pem) {
+        Some(key) => load_rsa_key(key?)?,
+        None => load_ec_key(&pem)?,
+    };
+
+    let jwk = Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(match algorithm {
+                Algorithm::ES256 => KeyAlgorithm::ES256,
+                _ => KeyAlgorithm::RS256,
+            }),
+            key_id: Some(kid.clone()),
+            ..Default::default()
+        },
+        algorithm: algorithm_parameters,
+    };
+    Ok(SigningKey {
+        kid,
+        algorithm,
+        encoding,
+        decoding: DecodingKey::from_jwk(&jwk)?,
+        jwk,
+    })
+}
+
+/// Parses an RSA key in either PKCS#1 or PKCS#8 form. Returns `None` if the
+/// PEM isn't an RSA key at all.
+fn read_rsa_key(pem: &str) -> Option<anyhow::Result<rsa::RsaPrivateKey>> {
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+    use rsa::pkcs8::DecodePrivateKey;
+
+    if pem.contains("RSA PRIVATE KEY") {
+        return Some(rsa::RsaPrivateKey::from_pkcs1_pem(pem).map_err(Into::into));
+    }
+    rsa::RsaPrivateKey::from_pkcs8_pem(pem).ok().map(Ok)
+}
+
+fn load_rsa_key(
+    key: rsa::RsaPrivateKey,
+) -> anyhow::Result<(Algorithm, EncodingKey, AlgorithmParameters)> {
+    use rsa::pkcs1::EncodeRsaPrivateKey;
+    use rsa::traits::PublicKeyParts;
+
+    if key.size() * 8 < MIN_RSA_BITS {
+        bail!("RSA keys must be at least {} bits", MIN_RSA_BITS);
+    }
+    let der = key.to_pkcs1_der()?;
+    Ok((
+        Algorithm::RS256,
+        EncodingKey::from_rsa_der(der.as_bytes()),
+        AlgorithmParameters::RSA(RSAKeyParameters {
+            key_type: RSAKeyType::RSA,
+            n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+            e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+        }),
+    ))
+}
+
+/// Loads a P-256 key in either SEC1 or PKCS#8 form.
+fn load_ec_key(pem: &str) -> anyhow::Result<(Algorithm, EncodingKey, AlgorithmParameters)> {
+    use p256::elliptic_curve::sec1::ToEncodedPoint;
+    use p256::pkcs8::{DecodePrivateKey, EncodePrivateKey};
+
+    let key = if pem.contains("EC PRIVATE KEY") {
+        p256::SecretKey::from_sec1_pem(pem)?
+    } else {
+        p256::SecretKey::from_pkcs8_pem(pem)
+            .map_err(|_| anyhow!("keys must be RSA or P-256 private keys"))?
+    };
+    let der = key.to_pkcs8_der()?;
+    let point = key.public_key().to_encoded_point(false);
+    let (Some(x), Some(y)) = (point.x(), point.y()) else {
+        bail!("invalid P-256 public key");
+    };
+    Ok((
+        Algorithm::ES256,
+        EncodingKey::from_ec_der(der.as_bytes()),
+        AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+            key_type: EllipticCurveKeyType::EC,
+            curve: EllipticCurve::P256,
+            x: URL_SAFE_NO_PAD.encode(x),
+            y: URL_SAFE_NO_PAD.encode(y),
+        }),
+    ))
+}
@@ -0,0 +1,485 @@
+//! Sends events to admin-configured webhooks. Deliveries are queued in the
+//! database as events are published, so nothing waiting to go out is lost to
+//! a restart, and failed ones are retried with backoff until they're given up
+//! on as dead letters.
+//!
+//! Each delivery is a POST of the event as JSON with these headers:
+//!
+//! - `X-Integral-Event`: the event name, like `job.created`
+//! - `X-Integral-Delivery`: the delivery id, the same across retries
+//! - `X-Integral-Timestamp`: when this attempt was signed, in Unix seconds
+//! - `X-Integral-Signature`: `sha256=` and the hex HMAC-SHA256 of
+//!   `<timestamp>.<body>` keyed with the webhook's secret
+//!
+//! Receivers should check the signature and reject old timestamps so
+//! requests can't be replayed. Retries can arrive after later events, so
+//! order by the event's `id` rather than arrival.
+//!
+//! Each webhook is sent its deliveries one at a time, alongside every other
+//! webhook, so a slow or broken receiver only holds up its own queue. When a
+//! delivery fails the rest of that webhook's queue waits for the retry too.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{atomic::Ordering, Arc, Mutex};
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use sha2::Sha256;
+use sqlx::{Pool, Sqlite};
+use tokio::{sync::Notify, task::JoinHandle};
+
+use crate::db::{self, events::Event, webhooks::DueDelivery};
+use crate::{features, metrics};
+
+/// Deliveries sent to one webhook per pass over the queue
+const BATCH_SIZE: i64 = 20;
+/// How often the queue is checked for retries that have come due
+const POLL_SECONDS: u64 = 5;
+const TIMEOUT_SECONDS: u64 = 10;
+const BASE_BACKOFF_SECONDS: i64 = 30;
+const MAX_BACKOFF_SECONDS: i64 = 60 * 60;
+
+lazy_static! {
+    /// Wakes the sender when new deliveries are queued
+    static ref QUEUED: Notify = Notify::new();
+    /// Redirects aren't followed, so a receiver can't bounce signed events
+    /// somewhere else
+    static ref AGENT: ureq::Agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(TIMEOUT_SECONDS))
+        .redirects(0)
+        .user_agent(concat!("integral/", env!("CARGO_PKG_VERSION")))
+        .build();
+}
+
+/// Queues an event for the webhooks that want it. Called as each event is
+/// published, so failures are only logged.
+pub async fn enqueue(pool: &Pool<Sqlite>, event: &Event) {
+    match db::webhooks::enqueue(pool, event).await {
+        Ok(0) => {}
+        Ok(_) => QUEUED.notify_one(),
+        Err(e) => tracing::error!("failed to queue event {} for webhooks: {}", event.id, e),
+    }
+}
+
+/// Webhooks that have a sender working through their deliveries
+type Sending = Arc<Mutex<HashSet<String>>>;
+
+/// Sends queued deliveries until the server shuts down. Only one of these
+/// should run against a database, or deliveries may be sent twice.
+pub async fn run(pool: Arc<Pool<Sqlite>>) {
+    let sending = Sending::default();
+    loop {
+        if let Err(e) = send_due(&pool, &sending).await {
+            tracing::error!("failed to send webhook deliveries: {}", e);
+        }
+        tokio::select! {
+            _ = QUEUED.notified() => {}
+            _ = tokio::time::sleep(Duration::from_secs(POLL_SECONDS)) => {}
+        }
+    }
+}
+
+/// Starts a sender for each webhook with deliveries due, unless it already
+/// has one, returning the senders started.
+async fn send_due(
+    pool: &Arc<Pool<Sqlite>>,
+    sending: &Sending,
+) -> Result<Vec<JoinHandle<()>>, sqlx::Error> {
+    let mut batches = HashMap::<String, Vec<DueDelivery>>::new();
+    for delivery in db::webhooks::due(pool, BATCH_SIZE).await? {
+        batches
+            .entry(delivery.webhook_id.clone())
+            .or_default()
+            .push(delivery);
+    }
+
+    let mut senders = Vec::new();
+    for (webhook_id, batch) in batches {
+        if !sending.lock().unwrap().insert(webhook_id.clone()) {
+            continue;
+        }
+        let pool = pool.clone();
+        let sending = sending.clone();
+        senders.push(tokio::spawn(async move {
+            match send_batch(&pool, &batch).await {
+                // A full batch means there may be more waiting
+                Ok(true) if batch.len() as i64 == BATCH_SIZE => QUEUED.notify_one(),
+                Ok(_) => {}
+                Err(e) => tracing::error!("failed to send deliveries to {}: {}", webhook_id, e),
+            }
+            sending.lock().unwrap().remove(&webhook_id);
+        }));
+    }
+    Ok(senders)
+}
+
+/// Sends one webhook's deliveries in order, stopping at the first failure.
+/// Returns whether they were all delivered.
+async fn send_batch(pool: &Pool<Sqlite>, batch: &[DueDelivery]) -> Result<bool, sqlx::Error> {
+    for delivery in batch {
+        if !send(pool, delivery).await? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// How long to wait before the next attempt, doubling with each failure.
+fn backoff(attempts: i64) -> i64 {
+    let exponent = (attempts - 1).clamp(0, 16) as u32;
+    (BASE_BACKOFF_SECONDS * 2i64.pow(exponent)).min(MAX_BACKOFF_SECONDS)
+}
+
+/// Sends one delivery and records how it went, returning whether it was
+/// delivered.
+async fn send(pool: &Pool<Sqlite>, delivery: &DueDelivery) -> Result<bool, sqlx::Error> {
+    let timestamp = chrono::Utc::now().timestamp();
+    let signature = sign(&delivery.secret, timestamp, &delivery.payload);
+    let request = delivery.clone();
+    let response = tokio::task::spawn_blocking(move || {
+        let response = AGENT
+            .post(&request.url)
+            .set("Content-Type", "application/json")
+            .set("X-Integral-Event", &request.event)
+            .set("X-Integral-Delivery", &request.id)
+            .set("X-Integral-Timestamp", &timestamp.to_string())
+            .set("X-Integral-Signature", &signature)
+            .send_string(&request.payload);
+        match response {
+            Ok(response) => Ok(response.status()),
+            Err(ureq::Error::Status(status, _)) => Err((Some(status), format!("HTTP {}", status))),
+            Err(e) => Err((None, e.to_string())),
+        }
+    })
+    .await;
+
+    let (status, error) = match response {
+        Ok(Ok(status)) if (200..300).contains(&status) => {
+            metrics::WEBHOOK_DELIVERIES.fetch_add(1, Ordering::Relaxed);
+            db::webhooks::record_success(pool, &delivery.id, status).await?;
+            return Ok(true);
+        }
+        // Redirects aren't followed, so they land here
+        Ok(Ok(status)) => (Some(status), format!("unexpected status {}", status)),
+        Ok(Err(failure)) => failure,
+        Err(e) => (None, e.to_string()),
+    };
+
+    metrics::WEBHOOK_FAILURES.fetch_add(1, Ordering::Relaxed);
+    let attempts = delivery.attempts + 1;
+    let retry_at =
+        (attempts < *features::WEBHOOK_MAX_ATTEMPTS).then(|| timestamp + backoff(attempts));
+    if retry_at.is_none() {
+        metrics::WEBHOOK_DEAD_LETTERS.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(
+            "giving up on webhook delivery {} to {} after {} attempts: {}",
+            delivery.id,
+            delivery.webhook_id,
+            attempts,
+            error
+        );
+    }
+    db::webhooks::record_failure(pool, &delivery.id, status, &error, retry_at).await?;
+    db::webhooks::postpone(pool, &delivery.webhook_id, timestamp + backoff(attempts)).await?;
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    use super::*;
+    use crate::db::{
+        events::{self, ChangeKind, Entity},
+        resources::ResourceLocation,
+        test_pool, users,
+        webhooks::DeliveryState,
+    };
+    use crate::permissions::Role;
+
+    /// A request as the receiver saw it, with lowercased header names.
+    struct Received {
+        headers: HashMap<String, String>,
+        body: String,
+    }
+
+    /// Answers each request on a local port with the given status after
+    /// `delay`, passing along what was sent.
+    fn receiver(status: &'static str, delay: Duration) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut headers = HashMap::new();
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(": ") {
+                        Some((name, value)) => {
+                            headers.insert(name.to_ascii_lowercase(), value.to_string())
+                        }
+                        None => break,
+                    };
+                }
+                let length = headers["content-length"].parse().unwrap();
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                thread::sleep(delay);
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nLocation: http://127.0.0.1:9/elsewhere\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                let body = String::from_utf8(body).unwrap();
+                if tx.send(Received { headers, body }).is_err() {
+                    break;
+                }
+            }
+        });
+        (url, rx)
+    }
+
+    /// Adds a webhook for `url` that wants every resource location.
+    async fn create_webhook(pool: &Pool<Sqlite>, url: &str) -> (String, String) {
+        let admin = match users::get_user_by_email(pool, "admin@example.com").await {
+            Ok(admin) => admin,
+            Err(_) => users::create_user(
+                pool,
+                "admin@example.com",
+                "password",
+                "Admin",
+                None,
+                Role::Admin,
+                None,
+            )
+            .await
+            .unwrap(),
+        };
+        let (webhook, secret) = db::webhooks::create(
+            pool,
+            url,
+            &[String::from("resource_location.*")],
+            None,
+            &admin.id,
+        )
+        .await
+        .unwrap();
+        (webhook.id, secret)
+    }
+
+    /// Records an event and queues it for the webhooks.
+    async fn publish(pool: &Pool<Sqlite>) -> Event {
+        let event = events::record(
+            pool,
+            ChangeKind::Created,
+            "1",
+            Entity::ResourceLocation(ResourceLocation {
+                resource_id: String::from("1"),
+                at_time: 1718000000,
+                latitude: String::from("43.08"),
+                longitude: String::from("-77.67"),
+            }),
+        )
+        .await
+        .unwrap();
+        db::webhooks::enqueue(pool, &event).await.unwrap();
+        event
+    }
+
+    /// Sets up a webhook for `url` and queues one event for it.
+    async fn queue_event(pool: &Pool<Sqlite>, url: &str) -> (String, Event) {
+        let (_, secret) = create_webhook(pool, url).await;
+        (secret, publish(pool).await)
+    }
+
+    /// Sends everything that's due and waits for it to finish, returning how
+    /// many webhooks were sent to.
+    async fn send_all(pool: &Arc<Pool<Sqlite>>) -> usize {
+        let senders = send_due(pool, &Sending::default()).await.unwrap();
+        let count = senders.len();
+        for sender in senders {
+            sender.await.unwrap();
+        }
+        count
+    }
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        assert_eq!(
+            sign("whsec_test", 1718000000, r#"{"id":1}"#),
+            "sha256=9909e4481a6c5d27d2ad4b309b9ad69c4a053a2b735e83929cfa6a4d72fa38da"
+        );
+        assert_ne!(
+            sign("whsec_test", 1718000001, r#"{"id":1}"#),
+            sign("whsec_test", 1718000000, r#"{"id":1}"#)
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_an_hour() {
+        assert_eq!(backoff(1), 30);
+        assert_eq!(backoff(2), 60);
+        assert_eq!(backoff(5), 480);
+        assert_eq!(backoff(8), 3600);
+        assert_eq!(backoff(100), 3600);
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_events() {
+        let pool = Arc::new(test_pool().await);
+        let (url, received) = receiver("204 No Content", Duration::ZERO);
+        let (secret, event) = queue_event(&pool, &url).await;
+
+        assert_eq!(send_all(&pool).await, 1);
+        let request = received.recv().unwrap();
+        assert_eq!(request.body, serde_json::to_string(&event).unwrap());
+        assert_eq!(request.headers["content-type"], "application/json");
+        assert_eq!(
+            request.headers["x-integral-event"],
+            "resource_location.created"
+        );
+        let timestamp = request.headers["x-integral-timestamp"].parse().unwrap();
+        assert_eq!(
+            request.headers["x-integral-signature"],
+            sign(&secret, timestamp, &request.body)
+        );
+
+        let deliveries = db::webhooks::list_deliveries(&pool, None, None)
+            .await
+            .unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(request.headers["x-integral-delivery"], deliveries[0].id);
+        assert_eq!(deliveries[0].state, DeliveryState::Delivered);
+        assert_eq!(deliveries[0].attempts, 1);
+        assert_eq!(deliveries[0].last_status, Some(204));
+        assert!(deliveries[0].delivered_at.is_some());
+
+        // Nothing's left to send
+        assert_eq!(send_all(&pool).await, 0);
+    }
+
+    #[tokio::test]
+    async fn retries_failures_then_gives_up() {
+        let pool = Arc::new(test_pool().await);
+        let (url, received) = receiver("500 Internal Server Error", Duration::ZERO);
+        queue_event(&pool, &url).await;
+
+        assert_eq!(send_all(&pool).await, 1);
+        let first = received.recv().unwrap();
+        let delivery = &db::webhooks::list_deliveries(&pool, None, None)
+            .await
+            .unwrap()[0];
+        assert_eq!(delivery.state, DeliveryState::Pending);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.last_status, Some(500));
+        assert!(
+            delivery.next_attempt_at
+                >= delivery.last_attempt_at.unwrap() + BASE_BACKOFF_SECONDS - 1
+        );
+
+        // The retry isn't due yet
+        assert_eq!(send_all(&pool).await, 0);
+
+        sqlx::query("UPDATE webhook_deliveries SET attempts = ?, next_attempt_at = 0")
+            .bind(*features::WEBHOOK_MAX_ATTEMPTS - 1)
+            .execute(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(send_all(&pool).await, 1);
+        let last = received.recv().unwrap();
+        // Retries are the same delivery with the same body
+        assert_eq!(
+            last.headers["x-integral-delivery"],
+            first.headers["x-integral-delivery"]
+        );
+        assert_eq!(last.body, first.body);
+
+        let dead = db::webhooks::list_deliveries(&pool, None, Some(DeliveryState::Dead))
+            .await
+            .unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].attempts, *features::WEBHOOK_MAX_ATTEMPTS);
+        assert_eq!(send_all(&pool).await, 0);
+    }
+
+    #[tokio::test]
+    async fn does_not_follow_redirects() {
+        let pool = Arc::new(test_pool().await);
+        let (url, received) = receiver("302 Found", Duration::ZERO);
+        queue_event(&pool, &url).await;
+
+        assert_eq!(send_all(&pool).await, 1);
+        received.recv().unwrap();
+        let delivery = &db::webhooks::list_deliveries(&pool, None, None)
+            .await
+            .unwrap()[0];
+        assert_eq!(delivery.state, DeliveryState::Pending);
+        assert_eq!(delivery.last_status, Some(302));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failing_receivers_do_not_hold_up_others() {
+        let pool = Arc::new(test_pool().await);
+        let (slow_url, _slow) = receiver("500 Internal Server Error", Duration::from_secs(1));
+        let (url, received) = receiver("204 No Content", Duration::ZERO);
+        let (slow_id, _) = create_webhook(&pool, &slow_url).await;
+        let (id, _) = create_webhook(&pool, &url).await;
+        publish(&pool).await;
+        publish(&pool).await;
+
+        let sending = Sending::default();
+        let senders = send_due(&pool, &sending).await.unwrap();
+        assert_eq!(senders.len(), 2);
+
+        // Both go out while the other receiver is still thinking
+        received.recv_timeout(Duration::from_millis(500)).unwrap();
+        received.recv_timeout(Duration::from_millis(500)).unwrap();
+        // and it isn't sent to twice at once
+        assert!(send_due(&pool, &sending).await.unwrap().is_empty());
+        for sender in senders {
+            sender.await.unwrap();
+        }
+
+        let delivered = db::webhooks::list_deliveries(&pool, Some(&id), None)
+            .await
+            .unwrap();
+        assert!(delivered
+            .iter()
+            .all(|delivery| delivery.state == DeliveryState::Delivered));
+
+        // The failure holds off the rest of that webhook's queue, without
+        // using up its attempts
+        let now = chrono::Utc::now().timestamp();
+        let mut waiting = db::webhooks::list_deliveries(&pool, Some(&slow_id), None)
+            .await
+            .unwrap();
+        waiting.sort_by_key(|delivery| delivery.event_id);
+        assert_eq!(waiting[0].attempts, 1);
+        assert_eq!(waiting[1].attempts, 0);
+        assert!(waiting
+            .iter()
+            .all(|delivery| delivery.state == DeliveryState::Pending
+                && delivery.next_attempt_at >= now + BASE_BACKOFF_SECONDS - 2));
+        assert_eq!(send_all(&pool).await, 0);
+    }
+}